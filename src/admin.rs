@@ -0,0 +1,1078 @@
+use crate::bandwidth::BandwidthConfig;
+use crate::changelog::Changelog;
+use crate::config::TenantConfig;
+use crate::deployment::{self, Validation};
+use crate::experiment::{ExperimentConfig, VariantCounts};
+use crate::latency::Latency;
+use crate::metrics::{self, RecentClient};
+#[cfg(feature = "profiling")]
+use crate::profiling;
+use crate::promotion::PatchState;
+use crate::replication::{self, AUTHORIZATION_PREFIX};
+use crate::rollout::Rollout;
+use crate::safe_path::Root;
+use crate::schedule::Activation;
+use crate::server::{DynamicListenerConfig, Server};
+use crate::tenant::{Tenant, DEFAULT_TENANT};
+use crate::{format_size, Channel, Quarantined};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Extension, Json, Router};
+use log::{error, info};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// Identifies a channel. Routes outside of `/tenants/{tenant}` refer to the
+/// channels of the default tenant.
+#[derive(Deserialize)]
+struct ChannelPath {
+    #[serde(default = "default_tenant")]
+    tenant: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ActivationPath {
+    #[serde(default = "default_tenant")]
+    tenant: String,
+    name: String,
+    version: u16,
+}
+
+#[derive(Deserialize)]
+struct ReplicatedFilePath {
+    #[serde(default = "default_tenant")]
+    tenant: String,
+    name: String,
+    version: u16,
+    file: String,
+}
+
+#[derive(Deserialize)]
+struct PinPath {
+    #[serde(default = "default_tenant")]
+    tenant: String,
+    name: String,
+    address: IpAddr,
+}
+
+#[derive(Deserialize)]
+struct VersionBody {
+    version: u16,
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+#[derive(Serialize)]
+struct TenantStatus {
+    name: String,
+    ports: Vec<u16>,
+    /// Ports whose listener failed repeatedly and is being restarted.
+    degraded_ports: Vec<u16>,
+    latency: Option<Latency>,
+    channels: Vec<ChannelStatus>,
+}
+
+#[derive(Serialize)]
+struct ChannelStatus {
+    name: String,
+    /// The version clients get patched to by default.
+    target: Option<u16>,
+    /// The name of the target for humans, if it has one.
+    target_name: Option<String>,
+    /// Whether the target was set explicitly rather than being the latest version.
+    target_override: bool,
+    /// The size in bytes of the update to the target from the version before it.
+    update_size: Option<u64>,
+    versions: Vec<u16>,
+    version_names: BTreeMap<u16, String>,
+    skipped_versions: Vec<u16>,
+    /// Versions only served to canaries until they're promoted.
+    staged_versions: Vec<u16>,
+    retired_versions: Vec<u16>,
+    quarantined: Vec<Quarantined>,
+    pins: BTreeMap<IpAddr, u16>,
+    rollout: Option<Rollout>,
+    schedule: Vec<Activation>,
+    experiment: Option<ExperimentStatus>,
+    mirrors: Vec<MirrorStatus>,
+    patch_dir: PathBuf,
+    /// The patch tree waiting to be activated.
+    staged: Option<StagedStatus>,
+    /// The patch tree served before the last switch.
+    previous_patch_dir: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct MirrorStatus {
+    name: String,
+    /// The versions the mirror received and is advertised for.
+    synced: BTreeSet<u16>,
+}
+
+#[derive(Serialize)]
+struct StagedStatus {
+    patch_dir: PathBuf,
+    versions: Vec<u16>,
+}
+
+#[derive(Deserialize)]
+struct StagingBody {
+    patch_dir: PathBuf,
+}
+
+#[derive(Serialize)]
+struct ExperimentStatus {
+    #[serde(flatten)]
+    config: ExperimentConfig,
+    variants: [VariantCounts; 2],
+}
+
+pub async fn serve(
+    bind: SocketAddr,
+    token: Option<String>,
+    replication_token: Option<String>,
+    patch_root: Option<PathBuf>,
+    server: Arc<Server>,
+    cancel_token: CancellationToken,
+) {
+    if token.is_none() && !bind.ip().is_loopback() {
+        error!(
+            "Admin API on {} is reachable from other hosts and requires a token",
+            bind
+        );
+        return;
+    }
+
+    let patch_root = match patch_root.as_deref().map(Root::new).transpose() {
+        Ok(patch_root) => patch_root.map(Arc::new),
+        Err(err) => {
+            error!("Could not open the patch root of the admin API: {}", err);
+            return;
+        }
+    };
+
+    let admin_token =
+        middleware::from_fn_with_state(token.map(Arc::<str>::from), require_admin_token);
+    // Patch files easily exceed the default body limit.
+    let replication_routes = Router::new()
+        .route(
+            "/channels/{name}/replication/{version}",
+            post(activate_replicated_version),
+        )
+        .route(
+            "/channels/{name}/replication/{version}/files/{*file}",
+            put(stage_replicated_file),
+        )
+        .layer(DefaultBodyLimit::disable())
+        .route_layer(middleware::from_fn_with_state(
+            replication_token.map(Arc::<str>::from),
+            require_token,
+        ));
+    let channel_routes = Router::new()
+        .route(
+            "/channels/{name}/target",
+            put(set_target).delete(clear_target),
+        )
+        .route(
+            "/channels/{name}/rollout",
+            put(set_rollout).delete(clear_rollout),
+        )
+        .route(
+            "/channels/{name}/experiment",
+            put(start_experiment).delete(stop_experiment),
+        )
+        .route(
+            "/channels/{name}/pins/{address}",
+            put(pin_client).delete(unpin_client),
+        )
+        .route("/channels/{name}/schedule", put(schedule_activation))
+        .route(
+            "/channels/{name}/staging",
+            put(stage_patch_tree).delete(discard_patch_tree),
+        )
+        .route(
+            "/channels/{name}/staging/activate",
+            post(activate_patch_tree),
+        )
+        .route("/channels/{name}/versions/{version}", post(load_version))
+        .route(
+            "/channels/{name}/versions/{version}/changelog",
+            get(get_changelog).put(set_changelog),
+        )
+        .route(
+            "/channels/{name}/versions/{version}/promote",
+            post(promote_version),
+        )
+        .route(
+            "/channels/{name}/versions/{version}/retire",
+            post(retire_version),
+        )
+        .route("/channels/{name}/rollback", post(rollback))
+        .route("/channels/{name}/switch-back", post(switch_back))
+        .route(
+            "/channels/{name}/schedule/{version}",
+            delete(remove_activation),
+        )
+        .route_layer(admin_token.clone())
+        .merge(replication_routes);
+    let router = Router::new()
+        .route("/metrics", get(render_metrics))
+        .route("/bandwidth", get(get_bandwidth).put(set_bandwidth))
+        .route("/tenants", get(list_tenants).post(add_tenant))
+        .route("/tenants/{tenant}", delete(remove_tenant))
+        .route("/tenants/{tenant}/listeners", post(add_listener))
+        .route("/tenants/{tenant}/clients", get(list_recent_clients))
+        .route(
+            "/tenants/{tenant}/latency",
+            put(set_latency).delete(clear_latency),
+        )
+        .route(
+            "/tenants/{tenant}/listeners/{port}",
+            delete(remove_listener),
+        )
+        .route("/channels", get(list_default_channels))
+        .route("/tenants/{tenant}/channels", get(list_channels));
+    #[cfg(feature = "profiling")]
+    let router = router.route("/debug/pprof/profile", get(profiling::cpu_profile));
+    let router = router
+        .route_layer(admin_token)
+        .merge(channel_routes.clone())
+        .nest("/tenants/{tenant}", channel_routes)
+        .layer(Extension(patch_root))
+        .with_state(server);
+
+    let listener = TcpListener::bind(bind).await.unwrap();
+    info!("Admin API listening on {}", bind);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(cancel_token.cancelled_owned())
+        .await
+        .unwrap();
+}
+
+fn find_channel(server: &Server, path: &ChannelPath) -> Option<Arc<Channel>> {
+    server
+        .find_tenant(&path.tenant)?
+        .find_channel(&path.name)
+        .cloned()
+}
+
+fn channel_status(channel: &Channel) -> ChannelStatus {
+    ChannelStatus {
+        name: channel.name().to_string(),
+        target: channel.default_target(),
+        target_name: channel
+            .default_target()
+            .and_then(|target| channel.version_name(target))
+            .map(str::to_string),
+        target_override: channel.target_override().is_some(),
+        update_size: channel.update_size(),
+        versions: channel.provider().versions(),
+        version_names: channel.version_names().clone(),
+        skipped_versions: channel.provider().skipped(),
+        staged_versions: channel.provider().versions_in(PatchState::Staged),
+        retired_versions: channel.provider().versions_in(PatchState::Retired),
+        quarantined: channel.provider().quarantined(),
+        pins: channel.pins(),
+        rollout: channel.rollout(),
+        schedule: channel.activations(),
+        experiment: channel.experiment().map(|experiment| ExperimentStatus {
+            config: experiment.config(),
+            variants: experiment.counts(),
+        }),
+        mirrors: channel
+            .mirrors()
+            .iter()
+            .map(|mirror| MirrorStatus {
+                name: mirror.name().to_string(),
+                synced: mirror.synced(),
+            })
+            .collect(),
+        patch_dir: channel.provider().patch_dir().to_path_buf(),
+        staged: channel.staged().map(|staged| StagedStatus {
+            patch_dir: staged.patch_dir().to_path_buf(),
+            versions: staged.versions(),
+        }),
+        previous_patch_dir: channel
+            .previous()
+            .map(|previous| previous.patch_dir().to_path_buf()),
+    }
+}
+
+fn tenant_status(tenant: &Tenant) -> TenantStatus {
+    TenantStatus {
+        name: tenant.name().to_string(),
+        ports: tenant.ports(),
+        degraded_ports: tenant
+            .metrics()
+            .into_iter()
+            .filter(|(_, metrics)| metrics.is_degraded())
+            .map(|(port, _)| port)
+            .collect(),
+        latency: tenant.latency(),
+        channels: tenant
+            .channels()
+            .iter()
+            .map(|channel| channel_status(channel))
+            .collect(),
+    }
+}
+
+async fn render_metrics(State(server): State<Arc<Server>>) -> String {
+    let tenants = server.tenants();
+    let listeners = tenants
+        .iter()
+        .flat_map(|tenant| {
+            tenant
+                .metrics()
+                .into_iter()
+                .map(move |(port, metrics)| (tenant.name(), port, metrics))
+        })
+        .collect::<Vec<_>>();
+    metrics::render(
+        listeners
+            .iter()
+            .map(|(tenant, port, metrics)| (*tenant, *port, metrics.as_ref())),
+    )
+}
+
+async fn list_tenants(State(server): State<Arc<Server>>) -> Json<Vec<TenantStatus>> {
+    Json(
+        server
+            .tenants()
+            .iter()
+            .map(|tenant| tenant_status(tenant))
+            .collect(),
+    )
+}
+
+#[derive(Serialize)]
+struct RecentClientStatus {
+    port: u16,
+    #[serde(flatten)]
+    client: RecentClient,
+}
+
+/// The clients answered most recently on any listener of the tenant, newest first.
+async fn list_recent_clients(
+    State(server): State<Arc<Server>>,
+    Path(tenant): Path<String>,
+) -> Result<Json<Vec<RecentClientStatus>>, StatusCode> {
+    let tenant = server.find_tenant(&tenant).ok_or(StatusCode::NOT_FOUND)?;
+    let mut clients = tenant
+        .metrics()
+        .into_iter()
+        .flat_map(|(port, metrics)| {
+            metrics
+                .recent_clients()
+                .into_iter()
+                .map(move |client| RecentClientStatus { port, client })
+        })
+        .collect::<Vec<_>>();
+    clients.sort_by_key(|status| Reverse(status.client.time));
+    Ok(Json(clients))
+}
+
+/// Resolves a patch directory given through the API inside the patch root.
+fn confine(
+    patch_root: Option<&Root>,
+    dir: &std::path::Path,
+) -> Result<PathBuf, (StatusCode, String)> {
+    let Some(patch_root) = patch_root else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "no patch root is configured".to_string(),
+        ));
+    };
+    patch_root.resolve(dir).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => (
+            StatusCode::BAD_REQUEST,
+            format!("'{}' does not exist", dir.display()),
+        ),
+        _ => (StatusCode::FORBIDDEN, err.to_string()),
+    })
+}
+
+async fn add_tenant(
+    State(server): State<Arc<Server>>,
+    Extension(patch_root): Extension<Option<Arc<Root>>>,
+    Json(mut config): Json<TenantConfig>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let patch_root = patch_root.as_deref();
+    if let Some(patch_dir) = &mut config.patch_dir {
+        *patch_dir = confine(patch_root, patch_dir)?;
+    }
+    for channel in &mut config.channels {
+        channel.patch_dir = confine(patch_root, &channel.patch_dir)?;
+        if let Some(blob_dir) = &mut channel.blob_dir {
+            *blob_dir = confine(patch_root, blob_dir)?;
+        }
+        if let Some(deltas) = &mut channel.deltas {
+            deltas.dir = confine(patch_root, &deltas.dir)?;
+        }
+    }
+
+    let tenant = server
+        .add_tenant(config)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    info!("Added tenant '{}'", tenant.name());
+    Ok(StatusCode::CREATED)
+}
+
+async fn remove_tenant(
+    State(server): State<Arc<Server>>,
+    Path(tenant): Path<String>,
+) -> StatusCode {
+    if server.remove_tenant(&tenant) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn add_listener(
+    State(server): State<Arc<Server>>,
+    Path(tenant): Path<String>,
+    Json(config): Json<DynamicListenerConfig>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    server
+        .add_listener(&tenant, config)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn remove_listener(
+    State(server): State<Arc<Server>>,
+    Path((tenant, port)): Path<(String, u16)>,
+) -> StatusCode {
+    if server.remove_listener(&tenant, port) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn get_bandwidth(State(server): State<Arc<Server>>) -> Json<BandwidthConfig> {
+    Json(server.bandwidth().config())
+}
+
+async fn set_bandwidth(
+    State(server): State<Arc<Server>>,
+    Json(config): Json<BandwidthConfig>,
+) -> StatusCode {
+    info!(
+        "Limiting downloads to {} each and {} in total",
+        describe_limit(config.per_download),
+        describe_limit(config.total)
+    );
+    server.bandwidth().set_config(config);
+    StatusCode::NO_CONTENT
+}
+
+fn describe_limit(limit: Option<u64>) -> String {
+    limit.map_or("unlimited".to_string(), |limit| {
+        format!("{}/s", format_size(limit))
+    })
+}
+
+async fn set_latency(
+    State(server): State<Arc<Server>>,
+    Path(tenant): Path<String>,
+    Json(latency): Json<Latency>,
+) -> StatusCode {
+    let Some(tenant) = server.find_tenant(&tenant) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    info!(
+        "Delaying patch responses of tenant '{}' by {:?} (jitter {:?})",
+        tenant.name(),
+        latency.delay,
+        latency.jitter
+    );
+    tenant.set_latency(Some(latency));
+    StatusCode::NO_CONTENT
+}
+
+async fn clear_latency(
+    State(server): State<Arc<Server>>,
+    Path(tenant): Path<String>,
+) -> StatusCode {
+    let Some(tenant) = server.find_tenant(&tenant) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    info!(
+        "No longer delaying patch responses of tenant '{}'",
+        tenant.name()
+    );
+    tenant.set_latency(None);
+    StatusCode::NO_CONTENT
+}
+
+async fn list_default_channels(
+    State(server): State<Arc<Server>>,
+) -> Result<Json<Vec<ChannelStatus>>, StatusCode> {
+    list_channels(State(server), Path(default_tenant())).await
+}
+
+async fn list_channels(
+    State(server): State<Arc<Server>>,
+    Path(tenant): Path<String>,
+) -> Result<Json<Vec<ChannelStatus>>, StatusCode> {
+    let tenant = server.find_tenant(&tenant).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(tenant_status(&tenant).channels))
+}
+
+async fn set_target(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+    Json(target): Json<VersionBody>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if !channel.provider().versions().contains(&target.version) {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    info!(
+        "Setting target of channel '{}' to version {}",
+        path.name, target.version
+    );
+    channel.set_target(Some(target.version));
+    StatusCode::NO_CONTENT
+}
+
+async fn clear_target(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    info!(
+        "Channel '{}' patches to the latest version again",
+        path.name
+    );
+    channel.set_target(None);
+    StatusCode::NO_CONTENT
+}
+
+/// Patches clients back to the version in the body, or to the one before
+/// the version they currently get without a body.
+async fn rollback(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+    target: Option<Json<VersionBody>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+
+    let (from, to) = channel
+        .rollback(target.map(|Json(target)| target.version))
+        .map_err(|err| (StatusCode::CONFLICT, err))?;
+    info!(
+        "Rolled back channel '{}' from version {} to {}",
+        path.name, from, to
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_rollout(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+    Json(rollout): Json<Rollout>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if rollout.percentage > 100 || !channel.provider().versions().contains(&rollout.version) {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    info!(
+        "Rolling out version {} to {}% of channel '{}'",
+        rollout.version, rollout.percentage, path.name
+    );
+    channel.set_rollout(Some(rollout));
+    StatusCode::NO_CONTENT
+}
+
+async fn clear_rollout(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    info!("Stopping rollout in channel '{}'", path.name);
+    channel.set_rollout(None);
+    StatusCode::NO_CONTENT
+}
+
+async fn pin_client(
+    State(server): State<Arc<Server>>,
+    Path(PinPath {
+        tenant,
+        name,
+        address,
+    }): Path<PinPath>,
+    Json(pin): Json<VersionBody>,
+) -> StatusCode {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if !channel.provider().versions().contains(&pin.version) {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    info!(
+        "Pinning {} to version {} in channel '{}'",
+        address, pin.version, path.name
+    );
+    channel.pin(address, pin.version);
+    StatusCode::NO_CONTENT
+}
+
+async fn unpin_client(
+    State(server): State<Arc<Server>>,
+    Path(PinPath {
+        tenant,
+        name,
+        address,
+    }): Path<PinPath>,
+) -> StatusCode {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if channel.unpin(address) {
+        info!("Unpinned {} in channel '{}'", address, path.name);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn start_experiment(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+    Json(experiment): Json<ExperimentConfig>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    let versions = channel.provider().versions();
+    if experiment.percentage > 100
+        || !experiment
+            .versions
+            .iter()
+            .all(|version| versions.contains(version))
+    {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    info!(
+        "Starting experiment with versions {:?} for {}% of channel '{}'",
+        experiment.versions, experiment.percentage, path.name
+    );
+    channel.set_experiment(Some(experiment));
+    StatusCode::NO_CONTENT
+}
+
+async fn stop_experiment(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    info!("Stopping experiment in channel '{}'", path.name);
+    channel.set_experiment(None);
+    StatusCode::NO_CONTENT
+}
+
+async fn schedule_activation(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+    Json(activation): Json<Activation>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if !channel.provider().versions().contains(&activation.version) {
+        return StatusCode::BAD_REQUEST;
+    }
+
+    info!(
+        "Version {} of channel '{}' goes live at {}",
+        activation.version, path.name, activation.go_live_at
+    );
+    channel.schedule(activation);
+    StatusCode::NO_CONTENT
+}
+
+async fn remove_activation(
+    State(server): State<Arc<Server>>,
+    Path(ActivationPath {
+        tenant,
+        name,
+        version,
+    }): Path<ActivationPath>,
+) -> StatusCode {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if channel.unschedule(version) {
+        info!(
+            "Removed scheduled activation of version {} in channel '{}'",
+            version, path.name
+        );
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn stage_patch_tree(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+    Extension(patch_root): Extension<Option<Arc<Root>>>,
+    Json(body): Json<StagingBody>,
+) -> Result<(StatusCode, Json<Validation>), (StatusCode, String)> {
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+
+    let patch_dir = confine(patch_root.as_deref(), &body.patch_dir)?;
+    let (provider, validation) = deployment::validate(&channel, patch_dir).await;
+    if !validation.problems.is_empty() {
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(validation)));
+    }
+
+    info!(
+        "Staged '{}' with {} versions for channel '{}'",
+        validation.patch_dir.display(),
+        validation.versions.len(),
+        path.name
+    );
+    channel.stage(provider);
+    Ok((StatusCode::OK, Json(validation)))
+}
+
+async fn discard_patch_tree(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+) -> StatusCode {
+    let Some(channel) = find_channel(&server, &path) else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    if channel.discard_staged() {
+        info!("Discarded staged patch tree of channel '{}'", path.name);
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+async fn activate_patch_tree(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+
+    channel
+        .activate_staged()
+        .map_err(|err| (StatusCode::CONFLICT, err))?;
+    info!(
+        "Channel '{}' serves '{}' now",
+        path.name,
+        channel.provider().patch_dir().display()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn switch_back(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ChannelPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+
+    channel
+        .switch_back()
+        .map_err(|err| (StatusCode::CONFLICT, err))?;
+    info!(
+        "Channel '{}' switched back to '{}'",
+        path.name,
+        channel.provider().patch_dir().display()
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Only lets requests carrying the replication token through. Without a
+/// configured token, replication is disabled.
+async fn require_token(
+    State(token): State<Option<Arc<str>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match token {
+        Some(token) if carries_token(&request, &token) => next.run(request).await,
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+/// Only lets requests carrying the admin token through, if one is configured.
+async fn require_admin_token(
+    State(token): State<Option<Arc<str>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match token {
+        Some(token) if !carries_token(&request, &token) => StatusCode::UNAUTHORIZED.into_response(),
+        _ => next.run(request).await,
+    }
+}
+
+fn carries_token(request: &Request, token: &str) -> bool {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(AUTHORIZATION_PREFIX))
+        .is_some_and(|provided| tokens_match(token, provided))
+}
+
+/// Compares the tokens through their HMAC, so the comparison takes as long
+/// no matter how much of a guess is right.
+fn tokens_match(expected: &str, provided: &str) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, expected.as_bytes());
+    let tag = hmac::sign(&key, expected.as_bytes());
+    hmac::verify(&key, provided.as_bytes(), tag.as_ref()).is_ok()
+}
+
+async fn stage_replicated_file(
+    State(server): State<Arc<Server>>,
+    Path(ReplicatedFilePath {
+        tenant,
+        name,
+        version,
+        file,
+    }): Path<ReplicatedFilePath>,
+    content: Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+    if server.is_read_only() {
+        return Err((StatusCode::CONFLICT, "storage is read-only".to_string()));
+    }
+
+    replication::stage_file(
+        &channel.provider(),
+        version,
+        std::path::Path::new(&file),
+        &content,
+    )
+    .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn activate_replicated_version(
+    State(server): State<Arc<Server>>,
+    Path(ActivationPath {
+        tenant,
+        name,
+        version,
+    }): Path<ActivationPath>,
+    manifest: String,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+    if server.is_read_only() {
+        return Err((StatusCode::CONFLICT, "storage is read-only".to_string()));
+    }
+
+    let activated = Arc::clone(&channel);
+    let files = tokio::task::spawn_blocking(move || {
+        replication::activate(&activated.provider(), version, &manifest)
+    })
+    .await
+    .unwrap()
+    .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    info!(
+        "Activated replicated version {} of channel '{}' with {} files",
+        version, path.name, files
+    );
+    Ok(StatusCode::CREATED)
+}
+
+/// Serves a staged or retired version to all clients.
+async fn promote_version(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ActivationPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let (name, version) = change_state(&server, path, PatchState::Live)?;
+    info!("Promoted version {} of channel '{}'", version, name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stops serving a version, like a skipped one.
+async fn retire_version(
+    State(server): State<Arc<Server>>,
+    Path(path): Path<ActivationPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let (name, version) = change_state(&server, path, PatchState::Retired)?;
+    info!("Retired version {} of channel '{}'", version, name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn change_state(
+    server: &Server,
+    ActivationPath {
+        tenant,
+        name,
+        version,
+    }: ActivationPath,
+    state: PatchState,
+) -> Result<(String, u16), (StatusCode, String)> {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+    if !server.can_store_states() {
+        return Err((
+            StatusCode::CONFLICT,
+            "storage is read-only and there is no state directory".to_string(),
+        ));
+    }
+
+    // Versions clients are sent to have to stay servable.
+    if state == PatchState::Retired && channel.targeted_versions().contains(&version) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("version {} is targeted", version),
+        ));
+    }
+    channel
+        .provider()
+        .set_state(version, state)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    Ok((path.name, version))
+}
+
+/// Serves a version that was put into the patch directory of the channel,
+/// e.g. by `import`, without restarting.
+async fn load_version(
+    State(server): State<Arc<Server>>,
+    Path(ActivationPath {
+        tenant,
+        name,
+        version,
+    }): Path<ActivationPath>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+    if !server.can_store_states() {
+        return Err((
+            StatusCode::CONFLICT,
+            "storage is read-only and there is no state directory".to_string(),
+        ));
+    }
+
+    let loaded = Arc::clone(&channel);
+    let files = tokio::task::spawn_blocking(move || loaded.provider().load_version(version))
+        .await
+        .unwrap()
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    info!(
+        "Loaded version {} of channel '{}' with {} files",
+        version, path.name, files
+    );
+    Ok(StatusCode::CREATED)
+}
+
+async fn get_changelog(
+    State(server): State<Arc<Server>>,
+    Path(ActivationPath {
+        tenant,
+        name,
+        version,
+    }): Path<ActivationPath>,
+) -> Result<Json<Changelog>, StatusCode> {
+    let channel =
+        find_channel(&server, &ChannelPath { tenant, name }).ok_or(StatusCode::NOT_FOUND)?;
+    let changelog = channel
+        .provider()
+        .changelog_of(version)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(Changelog::clone(&changelog)))
+}
+
+/// Replaces the changelog of a version, which is written into its directory.
+async fn set_changelog(
+    State(server): State<Arc<Server>>,
+    Path(ActivationPath {
+        tenant,
+        name,
+        version,
+    }): Path<ActivationPath>,
+    Json(changelog): Json<Changelog>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let path = ChannelPath { tenant, name };
+    let Some(channel) = find_channel(&server, &path) else {
+        return Err((StatusCode::NOT_FOUND, "channel not found".to_string()));
+    };
+    if server.is_read_only() {
+        return Err((StatusCode::CONFLICT, "storage is read-only".to_string()));
+    }
+
+    channel
+        .provider()
+        .set_changelog(version, changelog)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    info!(
+        "Changed the changelog of version {} of channel '{}'",
+        version, path.name
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
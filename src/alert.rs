@@ -0,0 +1,191 @@
+use crate::listener::Listener;
+use crate::metrics::{Outcome, Snapshot};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+/// The number of steps the window of outcome alerts moves in.
+const WINDOW_STEPS: u32 = 10;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// URL alerts are posted to as JSON, in addition to being logged.
+    pub webhook: Option<String>,
+    pub outcomes: Option<OutcomeAlertConfig>,
+}
+
+/// Alerts when the share of patch requests of a listener answered with an
+/// update or rejection rises above a threshold, e.g. because a new version
+/// was activated by accident or is missing files.
+#[derive(Deserialize, Clone, Copy)]
+pub struct OutcomeAlertConfig {
+    /// The duration over which requests are considered.
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// Fewer requests within the window are ignored, as a few outdated
+    /// clients would be enough to raise an alert otherwise.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+    /// The share of updated clients, between 0 and 1, above which an alert
+    /// is raised.
+    pub max_updated: Option<f64>,
+    /// The share of rejected clients, between 0 and 1, above which an alert
+    /// is raised.
+    pub max_rejected: Option<f64>,
+}
+
+fn default_min_requests() -> u64 {
+    20
+}
+
+#[derive(Serialize)]
+struct ListenerFailure<'a> {
+    text: String,
+    tenant: &'a str,
+    port: u16,
+}
+
+#[derive(Serialize)]
+struct OutcomeSpike<'a> {
+    text: String,
+    tenant: &'a str,
+    port: u16,
+    outcome: Outcome,
+    ratio: f64,
+}
+
+/// Reports failures that need the attention of an operator.
+pub struct Alerts {
+    client: reqwest::Client,
+    webhook: Option<String>,
+    outcomes: Option<OutcomeAlertConfig>,
+}
+
+impl Alerts {
+    pub fn new(config: AlertConfig) -> Alerts {
+        Alerts {
+            client: reqwest::Client::new(),
+            webhook: config.webhook,
+            outcomes: config.outcomes,
+        }
+    }
+
+    pub fn outcomes(&self) -> Option<OutcomeAlertConfig> {
+        self.outcomes
+    }
+
+    pub async fn listener_failed(&self, tenant: &str, port: u16, reason: &str) {
+        let text = format!(
+            "Listener on port {} of tenant '{}' stopped unexpectedly: {}",
+            port, tenant, reason
+        );
+        error!("{}", text);
+        self.post(&ListenerFailure { text, tenant, port }).await;
+    }
+
+    pub async fn outcome_spiked(&self, tenant: &str, port: u16, outcome: Outcome, ratio: f64) {
+        let text = format!(
+            "{:.0}% of the clients on port {} of tenant '{}' were {} recently",
+            ratio * 100.0,
+            port,
+            tenant,
+            match outcome {
+                Outcome::UpToDate => "up to date",
+                Outcome::Updated => "updated",
+                Outcome::Rejected => "rejected",
+            }
+        );
+        warn!("{}", text);
+        self.post(&OutcomeSpike {
+            text,
+            tenant,
+            port,
+            outcome,
+            ratio,
+        })
+        .await;
+    }
+
+    async fn post<T: Serialize>(&self, alert: &T) {
+        let Some(webhook) = &self.webhook else {
+            return;
+        };
+
+        let result = self
+            .client
+            .post(webhook)
+            .json(alert)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            warn!("Could not deliver alert to webhook: {}", err);
+        }
+    }
+}
+
+/// Compares the outcomes of the listener's requests within the configured
+/// window with the thresholds, alerting once each time one is exceeded.
+pub async fn watch_outcomes(
+    alerts: Arc<Alerts>,
+    listener: Arc<Listener>,
+    config: OutcomeAlertConfig,
+    cancel_token: CancellationToken,
+) {
+    let mut interval = time::interval(config.window / WINDOW_STEPS);
+    let mut snapshots = VecDeque::<Snapshot>::new();
+    let mut exceeded = [false; 2];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = cancel_token.cancelled() => return,
+        }
+
+        snapshots.push_back(listener.metrics.snapshot());
+        if snapshots.len() > WINDOW_STEPS as usize + 1 {
+            snapshots.pop_front();
+        }
+        let (oldest, newest) = (snapshots[0], snapshots[snapshots.len() - 1]);
+        let requests = newest.requests() - oldest.requests();
+        if requests < config.min_requests {
+            continue;
+        }
+
+        let checks = [
+            (
+                Outcome::Updated,
+                newest.updated - oldest.updated,
+                config.max_updated,
+            ),
+            (
+                Outcome::Rejected,
+                newest.rejected - oldest.rejected,
+                config.max_rejected,
+            ),
+        ];
+        for ((outcome, count, threshold), exceeded) in checks.into_iter().zip(&mut exceeded) {
+            let Some(threshold) = threshold else {
+                continue;
+            };
+
+            let ratio = count as f64 / requests as f64;
+            if ratio > threshold && !*exceeded {
+                alerts
+                    .outcome_spiked(listener.tenant.name(), listener.port, outcome, ratio)
+                    .await;
+            } else if ratio <= threshold && *exceeded {
+                info!(
+                    "Outcomes on port {} of tenant '{}' are back below the threshold",
+                    listener.port,
+                    listener.tenant.name()
+                );
+            }
+            *exceeded = ratio > threshold;
+        }
+    }
+}
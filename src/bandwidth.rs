@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Limits the bandwidth used by the built-in fileserver, e.g. to keep a patch
+/// day from saturating an uplink shared with the game server.
+#[derive(Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Bytes per second of a single download.
+    pub per_download: Option<u64>,
+    /// Bytes per second of all downloads together.
+    pub total: Option<u64>,
+}
+
+/// Paces downloads according to the configured limits, which can be changed
+/// while downloads are running.
+pub struct Bandwidth {
+    config: RwLock<BandwidthConfig>,
+    total: Mutex<ByteBucket>,
+}
+
+/// A token bucket of bytes holding up to a second worth of bandwidth. Bytes
+/// are taken before they're available, with the sender waiting for the
+/// bucket to be refilled afterwards.
+pub struct ByteBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bandwidth {
+    pub fn new(config: BandwidthConfig) -> Bandwidth {
+        Bandwidth {
+            config: RwLock::new(config),
+            total: Mutex::new(ByteBucket::default()),
+        }
+    }
+
+    pub fn config(&self) -> BandwidthConfig {
+        *self.config.read().unwrap()
+    }
+
+    pub fn set_config(&self, config: BandwidthConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// Waits until the download may send the bytes.
+    pub async fn throttle(&self, download: &mut ByteBucket, bytes: usize) {
+        let config = self.config();
+        let own_wait = config
+            .per_download
+            .map_or(Duration::ZERO, |rate| download.take(rate, bytes));
+        let total_wait = config.total.map_or(Duration::ZERO, |rate| {
+            self.total.lock().unwrap().take(rate, bytes)
+        });
+        let wait = own_wait.max(total_wait);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Default for ByteBucket {
+    fn default() -> Self {
+        ByteBucket {
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+}
+
+impl ByteBucket {
+    /// Takes the bytes, returning how long to wait until they're covered.
+    fn take(&mut self, rate: u64, bytes: usize) -> Duration {
+        let rate = rate.max(1) as f64;
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refilled).min(rate) - bytes as f64;
+        self.last_refill = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::hash;
+use crate::patch_manifest::{ListedFile, PatchManifest};
+use crate::{read_patch, Patch};
+use log::info;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What an ingest moved into the blob store.
+pub struct Ingested {
+    pub versions: usize,
+    pub files: usize,
+    /// Bytes no longer stored, as an identical file already was.
+    pub saved: u64,
+}
+
+/// Where a file of a version is stored, as the directory and the path
+/// relative to it. Files with a checksum in the `manifest.toml` of their
+/// version are stored in the blob store under their checksum, if there is
+/// one, and inside their version directory otherwise.
+pub fn locate(
+    patch_dir: &Path,
+    blob_dir: Option<&Path>,
+    version: u16,
+    manifest: Option<&PatchManifest>,
+    file: &Path,
+) -> (PathBuf, PathBuf) {
+    let hash = manifest.and_then(|manifest| manifest.get(file)?.sha256.as_deref());
+    match blob_dir.zip(hash) {
+        Some((blob_dir, hash)) => (blob_dir.to_path_buf(), PathBuf::from(hash)),
+        None => (
+            patch_dir.to_path_buf(),
+            PathBuf::from(version.to_string()).join(file),
+        ),
+    }
+}
+
+/// Moves the files of every version inside the patch directory into the
+/// blob store, keeping a single copy of identical files. Each version is
+/// left with a `manifest.toml` listing its files and their checksums.
+/// Listed files that are still inside a version directory are moved as well.
+pub fn ingest(patch_dir: &Path, blob_dir: &Path) -> io::Result<Ingested> {
+    fs::create_dir_all(blob_dir)?;
+    let mut ingested = Ingested {
+        versions: 0,
+        files: 0,
+        saved: 0,
+    };
+    let mut moved = Vec::new();
+    for entry in patch_dir.read_dir()?.filter_map(Result::ok) {
+        let Ok(version) = entry.file_name().to_string_lossy().parse::<u16>() else {
+            continue;
+        };
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let patch = read_patch(version, &entry.path())?;
+        let (files, saved) = ingest_version(&entry.path(), blob_dir, &patch)?;
+        if !files.is_empty() {
+            info!(
+                "Moved {} files of version {} into '{}'",
+                files.len(),
+                version,
+                blob_dir.display()
+            );
+        }
+        ingested.versions += 1;
+        ingested.files += files.len();
+        ingested.saved += saved;
+        moved.push((entry.path(), files));
+    }
+
+    // Only removed once every version was ingested, as files may be
+    // symlinks to files of other versions.
+    for (version_dir, files) in moved {
+        remove_files(&version_dir, &files)?;
+    }
+    Ok(ingested)
+}
+
+/// Moves the files of a single version into the blob store, e.g. right after
+/// it was added. Returns the number of files moved.
+pub fn ingest_one(version_dir: &Path, version: u16, blob_dir: &Path) -> io::Result<usize> {
+    fs::create_dir_all(blob_dir)?;
+    let patch = read_patch(version, version_dir)?;
+    let (files, _) = ingest_version(version_dir, blob_dir, &patch)?;
+    remove_files(version_dir, &files)?;
+    Ok(files.len())
+}
+
+/// Stores the files of the version in the blob store and lists them in its
+/// `manifest.toml`, returning the files to remove and the bytes saved.
+fn ingest_version(
+    version_dir: &Path,
+    blob_dir: &Path,
+    patch: &Patch,
+) -> io::Result<(Vec<PathBuf>, u64)> {
+    let mut listed = Vec::new();
+    let mut moved = Vec::new();
+    let mut saved = 0;
+    for file in patch.files.iter() {
+        let path = version_dir.join(file);
+        let known = patch
+            .manifest
+            .as_ref()
+            .and_then(|manifest| manifest.get(file));
+        if !path.is_file() {
+            // Already moved by an earlier ingest.
+            if let Some(known) = known.filter(|known| known.sha256.is_some()) {
+                listed.push(known.clone());
+                continue;
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is missing", path.display()),
+            ));
+        }
+
+        let hash = hash::sha256_of(&path)?;
+        let size = fs::metadata(&path)?.len();
+        let blob = blob_dir.join(&hash);
+        if blob.exists() {
+            saved += size;
+        } else {
+            // Linked if possible, as the file is only removed once the
+            // version lists the blob. Symlinks are linked to their target.
+            let source = fs::canonicalize(&path)?;
+            fs::hard_link(&source, &blob).or_else(|_| fs::copy(&source, &blob).map(|_| ()))?;
+        }
+        listed.push(ListedFile {
+            path: file.clone(),
+            size: size as u32,
+            sha256: Some(hash),
+            in_pk2: known.and_then(|known| known.in_pk2),
+        });
+        moved.push(path);
+    }
+
+    PatchManifest::write(version_dir, listed, patch.deleted.to_vec())?;
+    Ok((moved, saved))
+}
+
+/// Removes the files and the directories left empty by them.
+fn remove_files(version_dir: &Path, files: &[PathBuf]) -> io::Result<()> {
+    let mut parents = HashSet::new();
+    for path in files {
+        fs::remove_file(path)?;
+        parents.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+    }
+    // Deepest directories first, so their parents can be removed after them.
+    let mut parents = parents
+        .into_iter()
+        .filter(|parent| parent.starts_with(version_dir) && parent != version_dir)
+        .collect::<Vec<_>>();
+    parents.sort_by_key(|parent| Reverse(parent.components().count()));
+    for parent in parents {
+        // Fails for directories that still contain other files.
+        let _ = fs::remove_dir(parent);
+    }
+    Ok(())
+}
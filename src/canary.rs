@@ -0,0 +1,42 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::net::IpAddr;
+
+/// Clients that should always receive the newest version of a channel, ahead
+/// of the regular target and any rollout. A client is a canary if its address
+/// is inside one of the networks or its patch request module contains one of
+/// the tokens.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct CanaryList {
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub networks: Vec<IpNet>,
+    pub tokens: Vec<String>,
+}
+
+impl CanaryList {
+    pub fn contains(&self, client: IpAddr, module: &str) -> bool {
+        self.networks
+            .iter()
+            .any(|network| network.contains(&client))
+            || self.tokens.iter().any(|token| module.contains(token))
+    }
+}
+
+/// Parses either a network in CIDR notation or a single address.
+pub fn parse_network(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("'{}' is neither an IP address nor a network", value))
+}
+
+pub fn deserialize_networks<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|network| parse_network(network).map_err(serde::de::Error::custom))
+        .collect()
+}
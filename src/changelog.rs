@@ -0,0 +1,78 @@
+use crate::protocol::GatewayNotice;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Describes a version for humans, inside its directory.
+pub const FILE_NAME: &str = "changelog.toml";
+
+/// Where a new changelog is written to before replacing the old one.
+pub const PARTIAL_FILE_NAME: &str = "changelog.toml.partial";
+
+/// What changed with a version, e.g. to show in the launcher once it's live.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Changelog {
+    pub title: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    /// The release notes, one change per entry.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// When the version was released. Defaults to when the changelog was
+    /// last modified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<DateTime<Utc>>,
+}
+
+impl Changelog {
+    /// Reads the changelog of the version directory, if it has one.
+    pub fn load(version_dir: &Path) -> io::Result<Option<Changelog>> {
+        let path = version_dir.join(FILE_NAME);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut changelog = toml::from_str::<Changelog>(&content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {}: {}", FILE_NAME, err),
+            )
+        })?;
+        if changelog.published.is_none() {
+            changelog.published = fs::metadata(&path)?.modified().ok().map(DateTime::from);
+        }
+        Ok(Some(changelog))
+    }
+
+    /// Replaces the changelog of the version directory.
+    pub fn write(&self, version_dir: &Path) -> io::Result<()> {
+        let content = toml::to_string(self).map_err(io::Error::other)?;
+        // Written next to it first, so a crash never leaves half a changelog.
+        let partial = version_dir.join(PARTIAL_FILE_NAME);
+        fs::write(&partial, content)?;
+        fs::rename(partial, version_dir.join(FILE_NAME))
+    }
+
+    /// The notice announcing the version, with the description followed by
+    /// the release notes as its article.
+    pub fn to_gateway_notice(&self) -> GatewayNotice {
+        let mut article = self.description.clone();
+        for note in &self.notes {
+            if !article.is_empty() {
+                article.push('\n');
+            }
+            article.push_str("- ");
+            article.push_str(note);
+        }
+        GatewayNotice {
+            subject: self.title.clone(),
+            article,
+            published: self.published.unwrap_or_else(Utc::now),
+        }
+    }
+}
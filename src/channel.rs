@@ -0,0 +1,987 @@
+use crate::canary::CanaryList;
+use crate::cold_storage::{ColdStorage, ColdStorageConfig};
+use crate::config::{DowngradeConfig, VersionFileserver};
+use crate::delta::DeltaConfig;
+use crate::early_access::EarlyAccess;
+use crate::experiment::{Experiment, ExperimentConfig};
+use crate::mirror::Mirror;
+use crate::patch_signing::PublicKey;
+use crate::promotion::PatchState;
+use crate::protocol::GatewayNotice;
+use crate::region::{GeoIp, Region};
+use crate::retention::RetentionConfig;
+use crate::rollout::Rollout;
+use crate::schedule::{self, Activation};
+use crate::upstream::UpstreamConfig;
+use crate::url_check::UrlCheck;
+use crate::{load_patches, PatchFileserver, PatchProvider};
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// A named set of patches (e.g. stable or beta) that is served on its own listeners.
+pub struct Channel {
+    name: String,
+    provider: RwLock<Arc<PatchProvider>>,
+    /// A patch tree validated to replace the live one.
+    staged: RwLock<Option<Arc<PatchProvider>>>,
+    /// The patch tree that was live before the last switch, to switch back to.
+    previous: RwLock<Option<Arc<PatchProvider>>>,
+    target: RwLock<Option<u16>>,
+    rollout: RwLock<Option<Rollout>>,
+    canaries: CanaryList,
+    pins: RwLock<BTreeMap<IpAddr, u16>>,
+    activations: RwLock<Vec<Activation>>,
+    experiment: RwLock<Option<Arc<Experiment>>>,
+    regions: RwLock<Vec<Region>>,
+    geoip: Option<Arc<GeoIp>>,
+    max_downgrade_depth: Option<usize>,
+    downgrades: DowngradeConfig,
+    modules: Vec<String>,
+    contents: Vec<u8>,
+    verify_hashes: bool,
+    version_names: BTreeMap<u16, String>,
+    changelog_notice: bool,
+    early_access: Vec<EarlyAccess>,
+    mirrors: Vec<Mirror>,
+    upstream: Option<UpstreamConfig>,
+    retention: Option<RetentionConfig>,
+    cold_storage: Option<ColdStorage>,
+    url_check: Option<UrlCheck>,
+    version_fileservers: Vec<VersionFileserver>,
+}
+
+/// Why a client gets patched to a specific version.
+#[derive(Clone, Copy, Debug)]
+pub enum TargetReason {
+    Default,
+    Pinned,
+    Scheduled,
+    Canary,
+    Rollout,
+    Region,
+    Experiment(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Target {
+    pub version: u16,
+    pub reason: TargetReason,
+}
+
+/// What is known about a client when deciding which version to patch it to.
+pub struct ClientInfo<'a> {
+    pub address: IpAddr,
+    pub port: u16,
+    pub module: &'a str,
+}
+
+impl Channel {
+    pub fn load(name: String, patch_dir: PathBuf, fileserver: PatchFileserver) -> Channel {
+        let provider = load_provider(&name, patch_dir, fileserver);
+        Channel {
+            name,
+            provider: RwLock::new(Arc::new(provider)),
+            staged: RwLock::new(None),
+            previous: RwLock::new(None),
+            target: RwLock::new(None),
+            rollout: RwLock::new(None),
+            canaries: CanaryList::default(),
+            pins: RwLock::new(BTreeMap::new()),
+            activations: RwLock::new(Vec::new()),
+            experiment: RwLock::new(None),
+            regions: RwLock::new(Vec::new()),
+            geoip: None,
+            max_downgrade_depth: None,
+            downgrades: DowngradeConfig::default(),
+            modules: Vec::new(),
+            contents: Vec::new(),
+            verify_hashes: false,
+            version_names: BTreeMap::new(),
+            changelog_notice: false,
+            early_access: Vec::new(),
+            mirrors: Vec::new(),
+            upstream: None,
+            retention: None,
+            cold_storage: None,
+            url_check: None,
+            version_fileservers: Vec::new(),
+        }
+    }
+
+    /// Sets the version clients get patched to by default, instead of the
+    /// latest live version.
+    pub fn with_target(self, target: Option<u16>) -> Channel {
+        Channel {
+            target: RwLock::new(target),
+            ..self
+        }
+    }
+
+    /// The version clients get patched to by default: the configured target
+    /// if there is one, otherwise the latest live version.
+    pub fn default_target(&self) -> Option<u16> {
+        self.target_override()
+            .or_else(|| self.latest_live_version())
+    }
+
+    /// The size in bytes of the update to the default target.
+    pub fn update_size(&self) -> Option<u64> {
+        self.default_target()
+            .map(|target| self.provider().update_size(target))
+    }
+
+    pub fn target_override(&self) -> Option<u16> {
+        *self.target.read().unwrap()
+    }
+
+    pub fn set_target(&self, target: Option<u16>) {
+        *self.target.write().unwrap() = target;
+    }
+
+    /// Patches clients back to `version`, or to the version before the
+    /// newest one clients currently get, right away. Rollouts, experiments
+    /// and scheduled activations of newer versions are stopped, so they don't
+    /// keep serving a broken version, as are activations that already went
+    /// live. Regions and pins on newer versions are moved to the target.
+    /// Returns the versions rolled back from and to.
+    pub fn rollback(&self, version: Option<u16>) -> Result<(u16, u16), String> {
+        let now = Utc::now();
+        let versions = self.provider().versions();
+        let current = self
+            .served_versions(now)
+            .into_iter()
+            .max()
+            .ok_or_else(|| "There is no version to roll back from".to_string())?;
+        let target = match version {
+            Some(version) if versions.contains(&version) => version,
+            Some(version) => return Err(format!("Version {} doesn't exist", version)),
+            None => versions
+                .into_iter()
+                .filter(|version| *version < current)
+                .max()
+                .ok_or_else(|| format!("There is no version before {}", current))?,
+        };
+
+        // Activations that went live take precedence over the target.
+        self.set_target(Some(target));
+        self.activations
+            .write()
+            .unwrap()
+            .retain(|activation| !activation.is_live(now) && activation.version <= target);
+        if self
+            .rollout()
+            .is_some_and(|rollout| rollout.version > target)
+        {
+            self.set_rollout(None);
+        }
+        let newer_experiment = self.experiment().is_some_and(|experiment| {
+            experiment
+                .config()
+                .versions
+                .iter()
+                .any(|version| *version > target)
+        });
+        if newer_experiment {
+            self.set_experiment(None);
+        }
+        for region in self.regions.write().unwrap().iter_mut() {
+            region.target = region.target.min(target);
+        }
+        for pinned in self.pins.write().unwrap().values_mut() {
+            *pinned = (*pinned).min(target);
+        }
+        Ok((current, target))
+    }
+
+    /// The versions anything points clients at: the default target, pins,
+    /// a rollout, an experiment, regions and scheduled activations.
+    pub fn targeted_versions(&self) -> BTreeSet<u16> {
+        let mut targeted = self.pins().into_values().collect::<BTreeSet<_>>();
+        targeted.extend(self.default_target());
+        targeted.extend(self.rollout().map(|rollout| rollout.version));
+        targeted.extend(self.regions().iter().map(|region| region.target));
+        targeted.extend(
+            self.experiment()
+                .into_iter()
+                .flat_map(|experiment| experiment.config().versions),
+        );
+        targeted.extend(
+            self.activations()
+                .iter()
+                .map(|activation| activation.version),
+        );
+        targeted
+    }
+
+    /// The versions clients without a pin or canary access are currently
+    /// patched to through the default target or active schedule, a rollout,
+    /// an experiment or their region.
+    fn served_versions(&self, now: DateTime<Utc>) -> BTreeSet<u16> {
+        let mut served = BTreeSet::new();
+        served.extend(
+            schedule::active_version(&self.activations(), now).or_else(|| self.default_target()),
+        );
+        served.extend(self.rollout().map(|rollout| rollout.version));
+        served.extend(
+            self.experiment()
+                .into_iter()
+                .flat_map(|experiment| experiment.config().versions),
+        );
+        served.extend(self.regions().iter().map(|region| region.target));
+        served
+    }
+
+    pub fn with_canaries(self, canaries: CanaryList) -> Channel {
+        Channel { canaries, ..self }
+    }
+
+    pub fn with_modules(self, modules: Vec<String>) -> Channel {
+        Channel { modules, ..self }
+    }
+
+    pub fn modules(&self) -> &[String] {
+        &self.modules
+    }
+
+    pub fn accepts_module(&self, module: &str) -> bool {
+        self.modules.iter().any(|accepted| accepted == module)
+    }
+
+    pub fn with_contents(self, contents: Vec<u8>) -> Channel {
+        Channel { contents, ..self }
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.contents
+    }
+
+    /// Whether the channel serves the content part, which all channels
+    /// without any configured content parts do.
+    pub fn accepts_content(&self, content: u8) -> bool {
+        self.contents.is_empty() || self.contents.contains(&content)
+    }
+
+    /// Reads files listed with a checksum from the blob store, see
+    /// [crate::blob_store].
+    pub fn with_blob_dir(self, blob_dir: Option<PathBuf>) -> Channel {
+        self.provider().set_blob_dir(blob_dir);
+        self
+    }
+
+    /// Offers deltas between versions of files to launchers, see
+    /// [crate::delta].
+    pub fn with_deltas(self, deltas: Option<DeltaConfig>) -> Channel {
+        self.provider().set_deltas(deltas);
+        self
+    }
+
+    /// Quarantines versions that aren't signed with the key, see
+    /// [crate::patch_signing].
+    pub fn with_public_key(self, public_key: Option<PublicKey>) -> Channel {
+        self.provider().set_public_key(public_key);
+        self
+    }
+
+    /// Only advertises files whose checksum matches the manifest of their version.
+    pub fn with_hash_verification(self, verify_hashes: bool) -> Channel {
+        Channel {
+            verify_hashes,
+            ..self
+        }
+    }
+
+    pub fn verifies_hashes(&self) -> bool {
+        self.verify_hashes
+    }
+
+    /// Names versions for humans, e.g. `1.2.3 - Winter Update` for version 31.
+    pub fn with_version_names(self, version_names: BTreeMap<u16, String>) -> Channel {
+        Channel {
+            version_names,
+            ..self
+        }
+    }
+
+    pub fn with_changelog_notice(self, changelog_notice: bool) -> Channel {
+        Channel {
+            changelog_notice,
+            ..self
+        }
+    }
+
+    /// The notice announcing the version, if the channel publishes
+    /// changelogs and the version has one.
+    pub fn changelog_notice(&self, version: u16) -> Option<GatewayNotice> {
+        if !self.changelog_notice {
+            return None;
+        }
+
+        let changelog = self.provider().changelog_of(version)?;
+        Some(changelog.to_gateway_notice())
+    }
+
+    pub fn with_early_access(self, early_access: Vec<EarlyAccess>) -> Channel {
+        Channel {
+            early_access,
+            ..self
+        }
+    }
+
+    pub fn with_mirrors(self, mirrors: Vec<Mirror>) -> Channel {
+        Channel { mirrors, ..self }
+    }
+
+    pub fn mirrors(&self) -> &[Mirror] {
+        &self.mirrors
+    }
+
+    pub fn with_upstream(self, upstream: Option<UpstreamConfig>) -> Channel {
+        Channel { upstream, ..self }
+    }
+
+    pub fn upstream(&self) -> Option<&UpstreamConfig> {
+        self.upstream.as_ref()
+    }
+
+    pub fn with_retention(self, retention: Option<RetentionConfig>) -> Channel {
+        Channel { retention, ..self }
+    }
+
+    pub fn retention(&self) -> Option<&RetentionConfig> {
+        self.retention.as_ref()
+    }
+
+    pub fn with_cold_storage(self, cold_storage: Option<ColdStorageConfig>) -> Channel {
+        Channel {
+            cold_storage: cold_storage.map(ColdStorage::new),
+            ..self
+        }
+    }
+
+    pub fn cold_storage(&self) -> Option<&ColdStorage> {
+        self.cold_storage.as_ref()
+    }
+
+    /// The fileservers of the mirrors that received all of the versions.
+    pub fn mirrors_for(&self, versions: &BTreeSet<u16>) -> Vec<PatchFileserver> {
+        self.mirrors
+            .iter()
+            .filter(|mirror| mirror.has_synced(versions))
+            .map(|mirror| mirror.fileserver().clone())
+            .collect()
+    }
+
+    /// Checks advertised URLs before sending them to clients.
+    pub fn with_url_check(self, url_check: Option<UrlCheck>) -> Channel {
+        Channel { url_check, ..self }
+    }
+
+    pub fn url_check(&self) -> Option<&UrlCheck> {
+        self.url_check.as_ref()
+    }
+
+    pub fn with_version_fileservers(self, version_fileservers: Vec<VersionFileserver>) -> Channel {
+        Channel {
+            version_fileservers,
+            ..self
+        }
+    }
+
+    /// The fileserver hosting the version instead of the regular one, if any.
+    /// Versions evicted to cold storage are hosted there.
+    pub fn fileserver_of_version(&self, version: u16) -> Option<&PatchFileserver> {
+        if let Some(cold_storage) = &self.cold_storage {
+            if self.provider().is_evicted(version) {
+                return Some(cold_storage.fileserver());
+            }
+        }
+
+        self.version_fileservers
+            .iter()
+            .find(|entry| (entry.min..=entry.max).contains(&version))
+            .map(|entry| &entry.fileserver)
+    }
+
+    /// Versions the client may not see yet, as they're in early access or
+    /// staged and the client isn't a canary.
+    pub fn hidden_versions(&self, client: &ClientInfo) -> BTreeSet<u16> {
+        let now = Utc::now();
+        let mut hidden = self
+            .early_access
+            .iter()
+            .filter(|early_access| early_access.hides_from(client.address, now))
+            .map(|early_access| early_access.version)
+            .collect::<BTreeSet<_>>();
+        if !self.canaries.contains(client.address, client.module) {
+            hidden.extend(self.provider().versions_in(PatchState::Staged));
+        }
+        hidden
+    }
+
+    pub fn version_names(&self) -> &BTreeMap<u16, String> {
+        &self.version_names
+    }
+
+    pub fn version_name(&self, version: u16) -> Option<&str> {
+        self.version_names.get(&version).map(String::as_str)
+    }
+
+    /// The name of the version if it has one, otherwise its number.
+    pub fn display_version(&self, version: u16) -> String {
+        match self.version_name(version) {
+            Some(name) => format!("{} ({})", name, version),
+            None => version.to_string(),
+        }
+    }
+
+    pub fn with_max_downgrade_depth(self, max_downgrade_depth: Option<usize>) -> Channel {
+        Channel {
+            max_downgrade_depth,
+            ..self
+        }
+    }
+
+    pub fn with_downgrades(self, downgrades: DowngradeConfig) -> Channel {
+        Channel { downgrades, ..self }
+    }
+
+    pub fn downgrades(&self) -> DowngradeConfig {
+        self.downgrades
+    }
+
+    /// Checks whether going from `current` to `target` is allowed, i.e. that
+    /// downgrades are enabled and it stays within the number of versions a
+    /// client may be reverted by.
+    pub fn is_downgrade_permitted(&self, current: u16, target: u16) -> bool {
+        if current <= target {
+            return true;
+        }
+
+        if !self.downgrades.enabled {
+            return false;
+        }
+
+        let Some(max_depth) = self.max_downgrade_depth else {
+            return true;
+        };
+
+        let depth = self
+            .provider()
+            .versions()
+            .into_iter()
+            .filter(|version| *version > target && *version <= current)
+            .count();
+        depth <= max_depth
+    }
+
+    pub fn with_regions(self, regions: Vec<Region>, geoip: Option<Arc<GeoIp>>) -> Channel {
+        Channel {
+            regions: RwLock::new(regions),
+            geoip,
+            ..self
+        }
+    }
+
+    pub fn regions(&self) -> Vec<Region> {
+        self.regions.read().unwrap().clone()
+    }
+
+    /// Finds the region of the client, preferring the region of the listener
+    /// the client connected to over the location of the client.
+    pub fn region_for(&self, client: &ClientInfo) -> Option<Region> {
+        let regions = self.regions.read().unwrap();
+        if let Some(region) = regions
+            .iter()
+            .find(|region| region.ports.contains(&client.port))
+        {
+            return Some(region.clone());
+        }
+
+        let country = self.geoip.as_ref()?.country_of(client.address)?;
+        regions
+            .iter()
+            .find(|region| region.countries.contains(&country))
+            .cloned()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The patch tree currently served. It may be switched at any time, so
+    /// everything belonging together should be read from the same one.
+    pub fn provider(&self) -> Arc<PatchProvider> {
+        Arc::clone(&self.provider.read().unwrap())
+    }
+
+    pub fn staged(&self) -> Option<Arc<PatchProvider>> {
+        self.staged.read().unwrap().clone()
+    }
+
+    pub fn previous(&self) -> Option<Arc<PatchProvider>> {
+        self.previous.read().unwrap().clone()
+    }
+
+    /// Keeps the patch tree to replace the served one once activated,
+    /// replacing a tree staged before.
+    pub fn stage(&self, provider: PatchProvider) {
+        *self.staged.write().unwrap() = Some(Arc::new(provider));
+    }
+
+    /// Discards the staged patch tree, returning whether there was one.
+    pub fn discard_staged(&self) -> bool {
+        self.staged.write().unwrap().take().is_some()
+    }
+
+    /// Serves the staged patch tree from now on, keeping the one served so
+    /// far to switch back to.
+    pub fn activate_staged(&self) -> Result<(), String> {
+        let mut staged = self.staged.write().unwrap();
+        let Some(provider) = staged.as_ref() else {
+            return Err("No patch tree is staged".to_string());
+        };
+        self.check_target_exists(provider)?;
+
+        let provider = staged.take().unwrap();
+        let previous = std::mem::replace(&mut *self.provider.write().unwrap(), provider);
+        *self.previous.write().unwrap() = Some(previous);
+        Ok(())
+    }
+
+    /// Serves the patch tree that was served before the last switch again.
+    /// Switching back again returns to the tree served now.
+    pub fn switch_back(&self) -> Result<(), String> {
+        let mut previous = self.previous.write().unwrap();
+        let Some(provider) = previous.as_ref() else {
+            return Err("No patch tree was served before".to_string());
+        };
+        self.check_target_exists(provider)?;
+
+        let mut current = self.provider.write().unwrap();
+        std::mem::swap(&mut *current, previous.as_mut().unwrap());
+        Ok(())
+    }
+
+    /// Advertises the fileserver for the served patch tree as well as the
+    /// staged and previous ones.
+    pub fn set_fileserver(&self, fileserver: PatchFileserver) {
+        let providers = [Some(self.provider()), self.staged(), self.previous()];
+        for provider in providers.into_iter().flatten() {
+            provider.set_fileserver(fileserver.clone());
+        }
+    }
+
+    fn check_target_exists(&self, provider: &PatchProvider) -> Result<(), String> {
+        match self.target_override() {
+            Some(target) if !provider.versions().contains(&target) => Err(format!(
+                "The target {} doesn't exist in '{}'",
+                target,
+                provider.patch_dir().display()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn pins(&self) -> BTreeMap<IpAddr, u16> {
+        self.pins.read().unwrap().clone()
+    }
+
+    /// Pins the client to the version, replacing an existing pin of the client.
+    pub fn pin(&self, address: IpAddr, version: u16) {
+        self.pins.write().unwrap().insert(address, version);
+    }
+
+    /// Removes the pin of the client, returning whether there was one.
+    pub fn unpin(&self, address: IpAddr) -> bool {
+        self.pins.write().unwrap().remove(&address).is_some()
+    }
+
+    pub fn rollout(&self) -> Option<Rollout> {
+        *self.rollout.read().unwrap()
+    }
+
+    pub fn set_rollout(&self, rollout: Option<Rollout>) {
+        *self.rollout.write().unwrap() = rollout;
+    }
+
+    pub fn experiment(&self) -> Option<Arc<Experiment>> {
+        self.experiment.read().unwrap().clone()
+    }
+
+    /// Starts a new experiment, discarding the statistics of the previous one.
+    pub fn set_experiment(&self, experiment: Option<ExperimentConfig>) {
+        *self.experiment.write().unwrap() =
+            experiment.map(|config| Arc::new(Experiment::new(config)));
+    }
+
+    pub fn activations(&self) -> Vec<Activation> {
+        self.activations.read().unwrap().clone()
+    }
+
+    /// Registers the activation, replacing an existing one for the same version.
+    pub fn schedule(&self, activation: Activation) {
+        let mut activations = self.activations.write().unwrap();
+        activations.retain(|existing| existing.version != activation.version);
+        activations.push(activation);
+    }
+
+    /// Removes the activation for the given version, returning whether there was one.
+    pub fn unschedule(&self, version: u16) -> bool {
+        let mut activations = self.activations.write().unwrap();
+        let previous_len = activations.len();
+        activations.retain(|existing| existing.version != version);
+        activations.len() != previous_len
+    }
+
+    /// The highest version of this channel that isn't waiting to go live or
+    /// staged.
+    pub fn latest_live_version(&self) -> Option<u16> {
+        let now = Utc::now();
+        let activations = self.activations.read().unwrap();
+        let provider = self.provider();
+        provider
+            .versions()
+            .into_iter()
+            .filter(|version| {
+                !activations
+                    .iter()
+                    .any(|activation| activation.version == *version && !activation.is_live(now))
+            })
+            .filter(|version| provider.state_of(*version) != PatchState::Staged)
+            .max()
+    }
+
+    /// Determines the version the given client should be patched to, taking
+    /// pins, canaries, experiments, an active rollout, regions, scheduled
+    /// activations and early access into account.
+    pub fn target_for(&self, client: &ClientInfo, default_target: u16) -> Target {
+        if let Some(version) = self.pins.read().unwrap().get(&client.address) {
+            return Target {
+                version: *version,
+                reason: TargetReason::Pinned,
+            };
+        }
+
+        let target = self.unrestricted_target_for(client, default_target);
+        let hidden = self.hidden_versions(client);
+        if !hidden.contains(&target.version) {
+            return target;
+        }
+
+        // Clients without early access get the newest version they may see.
+        let version = self
+            .provider()
+            .versions()
+            .into_iter()
+            .filter(|version| *version < target.version && !hidden.contains(version))
+            .max()
+            .unwrap_or(target.version);
+        Target { version, ..target }
+    }
+
+    fn unrestricted_target_for(&self, client: &ClientInfo, default_target: u16) -> Target {
+        if self.canaries.contains(client.address, client.module) {
+            if let Some(latest) = self.provider().latest_version() {
+                return Target {
+                    version: latest,
+                    reason: TargetReason::Canary,
+                };
+            }
+        }
+
+        if let Some(experiment) = self.experiment() {
+            if let Some(variant) = experiment.variant_for(client.address) {
+                return Target {
+                    version: experiment.version(variant),
+                    reason: TargetReason::Experiment(variant),
+                };
+            }
+        }
+
+        if let Some(rollout) = self
+            .rollout()
+            .filter(|rollout| rollout.applies_to(client.address))
+        {
+            return Target {
+                version: rollout.version,
+                reason: TargetReason::Rollout,
+            };
+        }
+
+        if let Some(region) = self.region_for(client) {
+            return Target {
+                version: region.target,
+                reason: TargetReason::Region,
+            };
+        }
+
+        match schedule::active_version(&self.activations.read().unwrap(), Utc::now()) {
+            Some(version) => Target {
+                version,
+                reason: TargetReason::Scheduled,
+            },
+            None => Target {
+                version: default_target,
+                reason: TargetReason::Default,
+            },
+        }
+    }
+}
+
+/// Loads the versions inside the patch directory, quarantining those that
+/// can't be served.
+pub fn load_provider(name: &str, patch_dir: PathBuf, fileserver: PatchFileserver) -> PatchProvider {
+    let (patches, quarantined) = load_patches(&patch_dir);
+    let provider = PatchProvider::new(patch_dir, fileserver);
+    for patch in patches {
+        let version = patch.version;
+        if !provider.add_patch(patch) {
+            warn!(
+                "Ignoring duplicate version {} of channel '{}'",
+                version, name
+            );
+        }
+    }
+    for quarantined in quarantined {
+        warn!(
+            "Quarantined '{}' of channel '{}': {}",
+            quarantined.directory, name, quarantined.reason
+        );
+        provider.quarantine(quarantined);
+    }
+    provider
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Route;
+    use crate::Patch;
+    use chrono::TimeDelta;
+    use std::ops::RangeInclusive;
+    use std::{env, fs, process};
+
+    /// Loads a channel from a patch directory containing the given versions.
+    fn load(name: &str, versions: RangeInclusive<u16>) -> Arc<Channel> {
+        let dir = env::temp_dir().join(format!("channel-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        for version in versions {
+            let version_dir = dir.join(version.to_string());
+            fs::create_dir_all(&version_dir).unwrap();
+            fs::write(version_dir.join("Media.pk2"), "media").unwrap();
+        }
+        let channel = Channel::load(name.to_string(), dir.clone(), PatchFileserver::default());
+        fs::remove_dir_all(&dir).unwrap();
+        Arc::new(channel)
+    }
+
+    /// A channel with versions 1 to 3 that is patched to version 2.
+    fn channel(name: &str) -> Channel {
+        let dir = env::temp_dir().join(format!("channel-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let channel = Channel::load(name.to_string(), dir.clone(), PatchFileserver::default());
+        fs::remove_dir_all(&dir).unwrap();
+        for version in 1..=3 {
+            channel.provider().add_patch(Patch {
+                version,
+                files: Box::new([PathBuf::from("Media.pk2")]),
+                deleted: Box::default(),
+                manifest: None,
+                changelog: None,
+            });
+        }
+        channel.set_target(Some(2));
+        channel
+    }
+
+    fn region(target: u16) -> Region {
+        Region {
+            name: "eu".to_string(),
+            target,
+            ports: vec![15779],
+            countries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn channels_have_their_own_patch_chain_and_target() {
+        let stable = load("stable", 1..=2);
+        let beta = load("beta", 1..=3);
+        assert_eq!(stable.provider().versions(), [1, 2]);
+        assert_eq!(beta.provider().versions(), [1, 2, 3]);
+        assert_eq!(stable.default_target(), Some(2));
+        assert_eq!(beta.default_target(), Some(3));
+
+        stable.set_target(Some(1));
+        assert_eq!(stable.default_target(), Some(1));
+        assert_eq!(beta.default_target(), Some(3));
+    }
+
+    #[test]
+    fn routes_follow_the_target_of_their_channel() {
+        let channel = load("routes", 1..=3);
+        let route = Route::new(Arc::clone(&channel), None);
+        let version_route = Route::new(Arc::clone(&channel), Some(2));
+        assert_eq!(route.target(), Some(3));
+
+        channel.set_target(Some(1));
+        assert_eq!(route.target(), Some(1));
+        assert_eq!(version_route.target(), Some(2));
+    }
+
+    #[test]
+    fn targeted_versions_include_everything_pointing_at_a_version() {
+        let channel = channel("targeted").with_regions(vec![region(1)], None);
+        assert_eq!(channel.targeted_versions(), BTreeSet::from([1, 2]));
+
+        channel.set_target(None);
+        channel.pin(IpAddr::from([192, 0, 2, 1]), 1);
+        channel.set_rollout(Some(Rollout {
+            version: 2,
+            percentage: 10,
+        }));
+        channel.set_experiment(Some(ExperimentConfig {
+            versions: [1, 2],
+            percentage: 20,
+        }));
+        channel.schedule(Activation {
+            version: 4,
+            go_live_at: Utc::now() + TimeDelta::hours(1),
+        });
+        assert_eq!(channel.targeted_versions(), BTreeSet::from([1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn rollback_returns_to_the_version_before_the_target() {
+        let channel = channel("target");
+        assert_eq!(channel.rollback(None), Ok((2, 1)));
+        assert_eq!(channel.target_override(), Some(1));
+    }
+
+    #[test]
+    fn rollback_starts_at_an_active_schedule() {
+        let channel = channel("schedule");
+        channel.schedule(Activation {
+            version: 3,
+            go_live_at: Utc::now() - TimeDelta::hours(1),
+        });
+        assert_eq!(channel.rollback(None), Ok((3, 2)));
+        assert!(channel.activations().is_empty());
+        assert_eq!(channel.target_override(), Some(2));
+    }
+
+    #[test]
+    fn rollback_stops_a_rollout_of_a_newer_version() {
+        let channel = channel("rollout");
+        channel.set_rollout(Some(Rollout {
+            version: 3,
+            percentage: 10,
+        }));
+        assert_eq!(channel.rollback(None), Ok((3, 2)));
+        assert!(channel.rollout().is_none());
+        assert_eq!(channel.target_override(), Some(2));
+    }
+
+    #[test]
+    fn rollback_stops_an_experiment_with_a_newer_version() {
+        let channel = channel("experiment");
+        channel.set_experiment(Some(ExperimentConfig {
+            versions: [2, 3],
+            percentage: 20,
+        }));
+        assert_eq!(channel.rollback(None), Ok((3, 2)));
+        assert!(channel.experiment().is_none());
+    }
+
+    #[test]
+    fn rollback_moves_regions_on_newer_versions_to_the_target() {
+        let newer = channel("newer-region").with_regions(vec![region(3)], None);
+        assert_eq!(newer.rollback(None), Ok((3, 2)));
+        assert_eq!(newer.regions()[0].target, 2);
+
+        let older = channel("older-region").with_regions(vec![region(1)], None);
+        assert_eq!(older.rollback(Some(2)), Ok((2, 2)));
+        assert_eq!(older.regions()[0].target, 1);
+    }
+
+    #[test]
+    fn rollback_moves_pins_on_newer_versions_to_the_target() {
+        let channel = channel("pins");
+        let newer = IpAddr::from([192, 0, 2, 1]);
+        let older = IpAddr::from([192, 0, 2, 2]);
+        channel.pin(newer, 3);
+        channel.pin(older, 1);
+        assert_eq!(channel.rollback(None), Ok((2, 1)));
+        assert_eq!(channel.pins(), BTreeMap::from([(newer, 1), (older, 1)]));
+    }
+
+    #[test]
+    fn targets_follow_pins_canaries_experiments_rollouts_regions_and_schedules() {
+        let address = IpAddr::from([192, 0, 2, 1]);
+        let client = ClientInfo {
+            address,
+            port: 15779,
+            module: "SR_Client",
+        };
+        let target = |channel: &Channel| {
+            let target = channel.target_for(&client, 1);
+            (target.version, target.reason)
+        };
+        let channel = channel("priority");
+        for version in 4..=6 {
+            channel.provider().add_patch(Patch {
+                version,
+                files: Box::new([PathBuf::from("Media.pk2")]),
+                deleted: Box::default(),
+                manifest: None,
+                changelog: None,
+            });
+        }
+        assert!(matches!(target(&channel), (1, TargetReason::Default)));
+
+        channel.schedule(Activation {
+            version: 2,
+            go_live_at: Utc::now() - TimeDelta::hours(1),
+        });
+        assert!(matches!(target(&channel), (2, TargetReason::Scheduled)));
+
+        let channel = channel.with_regions(vec![region(3)], None);
+        assert!(matches!(target(&channel), (3, TargetReason::Region)));
+
+        channel.set_rollout(Some(Rollout {
+            version: 4,
+            percentage: 100,
+        }));
+        assert!(matches!(target(&channel), (4, TargetReason::Rollout)));
+
+        channel.set_experiment(Some(ExperimentConfig {
+            versions: [5, 5],
+            percentage: 100,
+        }));
+        assert!(matches!(target(&channel), (5, TargetReason::Experiment(_))));
+
+        let channel = channel.with_canaries(CanaryList {
+            networks: Vec::new(),
+            tokens: vec!["SR_Client".to_string()],
+        });
+        assert!(matches!(target(&channel), (6, TargetReason::Canary)));
+
+        channel.pin(address, 3);
+        assert!(matches!(target(&channel), (3, TargetReason::Pinned)));
+    }
+
+    #[test]
+    fn rollback_to_an_unknown_version_fails() {
+        let channel = channel("unknown");
+        channel.set_rollout(Some(Rollout {
+            version: 3,
+            percentage: 10,
+        }));
+        assert!(channel.rollback(Some(4)).is_err());
+        assert!(channel.rollout().is_some());
+    }
+}
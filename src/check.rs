@@ -0,0 +1,219 @@
+use crate::channel;
+use crate::config::{self, Config, TenantConfig};
+use crate::deployment;
+use crate::opcode_map::OpcodeMap;
+use crate::patch_chain;
+use crate::patch_signing::PublicKey;
+use crate::PatchFileserver;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use toml::Table;
+
+/// The result of checking the config and patch directories without starting
+/// the server.
+#[derive(Serialize)]
+pub struct Report {
+    pub valid: bool,
+    pub channels: Vec<ChannelReport>,
+    /// Everything not belonging to a single channel, like an unreadable
+    /// config or colliding ports.
+    pub problems: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ChannelReport {
+    pub tenant: String,
+    pub name: String,
+    pub patch_dir: PathBuf,
+    pub versions: Vec<u16>,
+    pub problems: Vec<String>,
+}
+
+/// Loads the config and every patch directory it refers to like the server
+/// would on start, collecting all problems instead of stopping at the first.
+pub fn check(path: &Path, command_line: Table) -> Report {
+    let mut config = match Config::resolve(path, command_line) {
+        Ok(config) => config,
+        Err(err) => {
+            return Report {
+                valid: false,
+                channels: Vec::new(),
+                problems: vec![format!("Config is invalid: {}", err)],
+            }
+        }
+    };
+
+    let mut channels = Vec::new();
+    let mut problems = Vec::new();
+    let mut addresses = BTreeMap::<u16, Vec<(String, IpAddr)>>::new();
+    for tenant_config in config.take_tenants() {
+        let tenant_bind = tenant_config
+            .bind
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        for (report, ports) in check_tenant(&tenant_config) {
+            for port in ports {
+                let bind = tenant_config
+                    .listeners
+                    .iter()
+                    .find(|listener| listener.port == port)
+                    .and_then(|listener| listener.overrides.bind)
+                    .unwrap_or(tenant_bind);
+                let users = addresses.entry(port).or_default();
+                // Channels of the same tenant may share a port.
+                if let Some((other, _)) = users.iter().find(|(other, other_bind)| {
+                    *other != tenant_config.name && overlap(bind, *other_bind)
+                }) {
+                    problems.push(format!(
+                        "Port {} is used by tenants '{}' and '{}'",
+                        port, other, tenant_config.name
+                    ));
+                }
+                users.push((tenant_config.name.clone(), bind));
+            }
+            channels.push(report);
+        }
+    }
+
+    if let Err(err) = OpcodeMap::new(&config.opcodes) {
+        problems.push(err);
+    }
+
+    if let Some(admin) = &config.admin {
+        if admin.token.is_none() && !admin.bind.ip().is_loopback() {
+            problems.push(format!(
+                "Admin API on {} is reachable from other hosts and requires a token",
+                admin.bind
+            ));
+        }
+        let port = admin.bind.port();
+        let collides = addresses.get(&port).is_some_and(|users| {
+            users
+                .iter()
+                .any(|(_, bind)| overlap(admin.bind.ip(), *bind))
+        });
+        if collides {
+            problems.push(format!(
+                "Port {} is used by the admin API and a listener",
+                port
+            ));
+        }
+    }
+
+    Report {
+        valid: problems.is_empty() && channels.iter().all(|channel| channel.problems.is_empty()),
+        channels,
+        problems,
+    }
+}
+
+/// Checks the channels of the tenant, along with the ports they're served on.
+fn check_tenant(tenant_config: &TenantConfig) -> Vec<(ChannelReport, Vec<u16>)> {
+    if tenant_config.channels.is_empty() {
+        let patch_dir = tenant_config
+            .patch_dir
+            .clone()
+            .unwrap_or_else(config::default_patch_dir);
+        let mut report = check_channel(
+            &tenant_config.name,
+            "default",
+            patch_dir,
+            None,
+            None,
+            &[],
+            None,
+        );
+        // Every version gets its own port.
+        let base_port = tenant_config.base_port.unwrap_or(config::DEFAULT_BASE_PORT);
+        let mut ports = Vec::new();
+        for version in &report.versions {
+            match base_port.checked_add(*version) {
+                Some(port) => ports.push(port),
+                None => report.problems.push(format!(
+                    "Version {} can't be served above base port {}",
+                    version, base_port
+                )),
+            }
+        }
+        return vec![(report, ports)];
+    }
+
+    tenant_config
+        .channels
+        .iter()
+        .map(|channel_config| {
+            let report = check_channel(
+                &tenant_config.name,
+                &channel_config.name,
+                channel_config.patch_dir.clone(),
+                channel_config.blob_dir.clone(),
+                channel_config.public_key.as_deref(),
+                &channel_config.skipped_versions,
+                channel_config.target,
+            );
+            let mut ports = channel_config.ports.clone();
+            for region in &channel_config.regions {
+                ports.extend(&region.ports);
+            }
+            ports.sort_unstable();
+            ports.dedup();
+            (report, ports)
+        })
+        .collect()
+}
+
+fn check_channel(
+    tenant: &str,
+    name: &str,
+    patch_dir: PathBuf,
+    blob_dir: Option<PathBuf>,
+    public_key: Option<&str>,
+    skipped: &[u16],
+    target: Option<u16>,
+) -> ChannelReport {
+    let mut report = ChannelReport {
+        tenant: tenant.to_string(),
+        name: name.to_string(),
+        patch_dir: patch_dir.clone(),
+        versions: Vec::new(),
+        problems: Vec::new(),
+    };
+    if !patch_dir.is_dir() {
+        report
+            .problems
+            .push("The patch directory doesn't exist".to_string());
+        return report;
+    }
+
+    let provider = channel::load_provider(name, patch_dir, PatchFileserver::default());
+    provider.set_blob_dir(blob_dir);
+    for version in skipped {
+        provider.skip(*version);
+    }
+    match public_key.map(PublicKey::parse).transpose() {
+        Ok(public_key) => provider.set_public_key(public_key),
+        Err(err) => report
+            .problems
+            .push(format!("The public key is invalid: {}", err)),
+    }
+    report.problems.extend(deployment::check_files(&provider));
+    report.problems.extend(patch_chain::validate(&provider));
+    report.versions = provider.versions();
+    match target.or_else(|| provider.latest_version()) {
+        None => report
+            .problems
+            .push("The patch directory contains no patches".to_string()),
+        Some(target) if !report.versions.contains(&target) => report.problems.push(format!(
+            "Target {} is skipped, quarantined or doesn't exist",
+            target
+        )),
+        Some(_) => {}
+    }
+    report
+}
+
+/// Whether sockets bound to both addresses would conflict.
+fn overlap(first: IpAddr, second: IpAddr) -> bool {
+    first == second || first.is_unspecified() || second.is_unspecified()
+}
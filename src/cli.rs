@@ -0,0 +1,158 @@
+use clap::{Parser, Subcommand};
+use clap_complete::Shell;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+/// Serves patches to Silkroad Online clients.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// The config file. Defaults are used if it doesn't exist.
+    #[arg(long, default_value = "./config.toml")]
+    pub config: PathBuf,
+    /// The patch directory used if no channels are configured.
+    #[arg(long)]
+    pub patch_dir: Option<PathBuf>,
+    /// The IP of the fileserver advertised to clients.
+    #[arg(long)]
+    pub fileserver_ip: Option<String>,
+    /// The host of the fileserver advertised to clients.
+    #[arg(long)]
+    pub fileserver_host: Option<String>,
+    /// The path on the fileserver the patch directory is served from.
+    #[arg(long)]
+    pub base_path: Option<String>,
+    /// The address listeners bind to, e.g. `::` for IPv6.
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// Without channels, every version is served on this port plus the version.
+    #[arg(long)]
+    pub base_port: Option<u16>,
+    /// Which logs to show, in the format of `RUST_LOG`, e.g. `debug`.
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Shows a live dashboard instead of the logs. Requires the `tui` feature.
+    #[arg(long)]
+    pub tui: bool,
+    /// Answers from the fixtures inside the directory instead of serving patches.
+    #[arg(long, value_name = "DIR")]
+    pub fixtures: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Checks the config and patch directories without starting the server,
+    /// printing a JSON report. Exits with 1 if there are problems.
+    #[command(long_flag = "check-config")]
+    CheckConfig,
+    /// Compares the served files with the checksums of a reference client.
+    CompareReference { reference: PathBuf },
+    /// Connects like a client and prints the answers for the given version.
+    Probe { address: String, version: u32 },
+    /// Moves the files of channels with a `blob_dir` into their blob store.
+    /// The server should be stopped while ingesting.
+    Ingest,
+    /// Builds a version of a channel from the files that differ between two
+    /// client installs, including the files inside their pk2 archives.
+    Diff {
+        channel: String,
+        version: u16,
+        old: PathBuf,
+        new: PathBuf,
+    },
+    /// Installs the versions inside a zip or tar archive into the patch
+    /// directory of a channel.
+    Import {
+        channel: String,
+        archive: PathBuf,
+        /// The version of an archive containing a single version. Defaults
+        /// to the one after the latest version of the channel.
+        #[arg(long)]
+        version: Option<u16>,
+        /// The admin API of the running server, to serve the versions right
+        /// away instead of on its next start.
+        #[arg(long)]
+        admin_url: Option<String>,
+        /// The token of the admin API, if it requires one.
+        #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+        admin_token: Option<String>,
+    },
+    /// Bundles versions of a channel into a tar archive, e.g. to seed a
+    /// mirror or another instance, which installs them with `import`.
+    Export {
+        channel: String,
+        archive: PathBuf,
+        /// The first version to bundle. Defaults to the first version.
+        #[arg(long)]
+        from: Option<u16>,
+        /// The last version to bundle. Defaults to the latest version.
+        #[arg(long)]
+        to: Option<u16>,
+    },
+    /// Writes a new private key for signing versions to the file and prints
+    /// its public key.
+    GenerateKey { key: PathBuf },
+    /// Signs a version of a channel with the private key inside the file.
+    Sign {
+        channel: String,
+        version: u16,
+        #[arg(long)]
+        key: PathBuf,
+    },
+    /// Makes the missing deltas of channels with `deltas` configured.
+    Deltas,
+    /// Moves old versions of channels with `cold_storage` configured to
+    /// their cold storage.
+    Evict,
+    /// Pushes a version of a channel to another instance.
+    Replicate {
+        channel: String,
+        version: u16,
+        /// The admin API of the other instance.
+        url: String,
+        #[arg(long, env = "REPLICATION_TOKEN", hide_env_values = true)]
+        token: String,
+    },
+    /// Prints the completion script for the shell.
+    Completions { shell: Shell },
+    /// Prints the man page.
+    Man,
+}
+
+impl Cli {
+    /// The settings given as options, laid out like the config file.
+    pub fn settings(&self) -> Table {
+        let mut settings = Table::new();
+        if let Some(patch_dir) = &self.patch_dir {
+            settings.insert(
+                "patch_dir".to_string(),
+                Value::String(patch_dir.to_string_lossy().to_string()),
+            );
+        }
+        if let Some(bind) = &self.bind {
+            settings.insert("bind".to_string(), Value::String(bind.to_string()));
+        }
+        if let Some(base_port) = self.base_port {
+            settings.insert("base_port".to_string(), Value::Integer(base_port.into()));
+        }
+
+        let mut fileserver = Table::new();
+        let options = [
+            ("ip", &self.fileserver_ip),
+            ("host", &self.fileserver_host),
+            ("base_path", &self.base_path),
+        ];
+        for (key, option) in options {
+            if let Some(value) = option {
+                fileserver.insert(key.to_string(), Value::String(value.clone()));
+            }
+        }
+        if !fileserver.is_empty() {
+            settings.insert("fileserver".to_string(), Value::Table(fileserver));
+        }
+        settings
+    }
+}
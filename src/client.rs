@@ -0,0 +1,74 @@
+use crate::protocol::{
+    GatewayNotice, GatewayNoticeRequest, IdentityInformation, PatchRequest, PatchResult,
+    PatchServerProtocol,
+};
+use skrillax_stream::handshake::PassiveSecuritySetup;
+use skrillax_stream::stream::{SilkroadStreamRead, SilkroadStreamWrite, SilkroadTcpExt};
+use std::error::Error;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+type ClientResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// The locality official clients identify with.
+const LOCALITY: u8 = 0x12;
+
+/// A client speaking the patch protocol, to exercise the full handshake and
+/// request flow against a running server, e.g. one started with fixtures.
+pub struct PatchClient {
+    reader: SilkroadStreamRead<OwnedReadHalf>,
+    writer: SilkroadStreamWrite<OwnedWriteHalf>,
+}
+
+impl PatchClient {
+    pub async fn connect(address: impl ToSocketAddrs) -> ClientResult<PatchClient> {
+        let stream = TcpStream::connect(address).await?;
+        let (mut reader, mut writer) = stream.into_silkroad_stream();
+        PassiveSecuritySetup::handle(&mut reader, &mut writer).await?;
+        Ok(PatchClient { reader, writer })
+    }
+
+    pub async fn identify(&mut self, module: &str) -> ClientResult<IdentityInformation> {
+        self.writer
+            .write_packet(IdentityInformation {
+                module_name: module.to_string(),
+                locality: LOCALITY,
+            })
+            .await?;
+        loop {
+            if let PatchServerProtocol::IdentityInformation(identity) = self.next().await? {
+                return Ok(identity);
+            }
+        }
+    }
+
+    pub async fn request_patch(&mut self, module: &str, version: u32) -> ClientResult<PatchResult> {
+        self.writer
+            .write_packet(PatchRequest {
+                content: LOCALITY,
+                module: module.to_string(),
+                version,
+            })
+            .await?;
+        loop {
+            if let PatchServerProtocol::PatchResponse(response) = self.next().await? {
+                return Ok(response.result);
+            }
+        }
+    }
+
+    pub async fn notices(&mut self) -> ClientResult<Vec<GatewayNotice>> {
+        self.writer
+            .write_packet(GatewayNoticeRequest { unknown: LOCALITY })
+            .await?;
+        loop {
+            if let PatchServerProtocol::GatewayNoticeResponse(response) = self.next().await? {
+                return Ok(response.notices);
+            }
+        }
+    }
+
+    async fn next(&mut self) -> ClientResult<PatchServerProtocol> {
+        Ok(*self.reader.next_packet::<PatchServerProtocol>().await?)
+    }
+}
@@ -0,0 +1,174 @@
+use crate::hash;
+use crate::patch_manifest::{ListedFile, PatchManifest};
+use crate::pk2::Pk2Archive;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// What a version built from two client installs contains.
+pub struct Diff {
+    pub changed: usize,
+    pub deleted: usize,
+}
+
+/// Builds the directory of a version from the files that differ between an
+/// old and a new client install. Archives are compared through the files
+/// inside them, e.g. `Media.pk2` as `Media/<file>`. The version lists its
+/// files in a `manifest.toml`, with those from archives stored in their pk2,
+/// and the files missing from the new install as deleted.
+pub fn diff(old: &Path, new: &Path, version_dir: &Path) -> Result<Diff, String> {
+    if version_dir.exists() {
+        return Err(format!("'{}' already exists", version_dir.display()));
+    }
+
+    let mut old = ClientInstall::open(old)?;
+    let mut new = ClientInstall::open(new)?;
+    let mut changed = Vec::new();
+    for file in new.files.keys().cloned().collect::<Vec<_>>() {
+        let unchanged = old.files.contains_key(&file)
+            && same_content(&mut old, &mut new, &file)
+                .map_err(|err| format!("'{}': {}", file.display(), err))?;
+        if !unchanged {
+            changed.push(file);
+        }
+    }
+    let deleted = old
+        .files
+        .keys()
+        .filter(|file| !new.files.contains_key(*file))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // Moved into place once complete, so a failed diff never leaves half a
+    // version behind that would be served.
+    let partial_dir = version_dir.with_file_name(format!(
+        ".{}.partial",
+        version_dir.file_name().unwrap().to_string_lossy()
+    ));
+    if partial_dir.exists() {
+        fs::remove_dir_all(&partial_dir).map_err(|err| err.to_string())?;
+    }
+    let mut listed = Vec::new();
+    for file in &changed {
+        let target = partial_dir.join(file);
+        fs::create_dir_all(target.parent().unwrap()).map_err(|err| err.to_string())?;
+        let size = new
+            .copy(file, &target)
+            .map_err(|err| format!("could not copy '{}': {}", file.display(), err))?;
+        listed.push(ListedFile {
+            path: file.clone(),
+            size: size as u32,
+            sha256: None,
+            in_pk2: Some(matches!(new.files[file], Location::Archived(..))),
+        });
+    }
+    fs::create_dir_all(&partial_dir).map_err(|err| err.to_string())?;
+    PatchManifest::write(&partial_dir, listed, deleted.clone()).map_err(|err| err.to_string())?;
+    fs::rename(&partial_dir, version_dir).map_err(|err| err.to_string())?;
+
+    Ok(Diff {
+        changed: changed.len(),
+        deleted: deleted.len(),
+    })
+}
+
+fn same_content(old: &mut ClientInstall, new: &mut ClientInstall, file: &Path) -> io::Result<bool> {
+    if old.size(file)? != new.size(file)? {
+        return Ok(false);
+    }
+
+    Ok(old.checksum(file)? == new.checksum(file)?)
+}
+
+/// The files of a client install, relative to it, with the files inside
+/// its archives in place of the archives.
+struct ClientInstall {
+    files: BTreeMap<PathBuf, Location>,
+    archives: Vec<Pk2Archive>,
+}
+
+enum Location {
+    Loose(PathBuf),
+    /// The index of the archive and of the file inside it.
+    Archived(usize, usize),
+}
+
+impl ClientInstall {
+    fn open(install: &Path) -> Result<ClientInstall, String> {
+        if !install.is_dir() {
+            return Err(format!("'{}' is not a directory", install.display()));
+        }
+
+        let mut client = ClientInstall {
+            files: BTreeMap::new(),
+            archives: Vec::new(),
+        };
+        for entry in WalkDir::new(install) {
+            let entry = entry.map_err(|err| err.to_string())?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let relative = entry.path().strip_prefix(install).unwrap().to_path_buf();
+            if !is_archive(&relative) {
+                client
+                    .files
+                    .insert(relative, Location::Loose(entry.into_path()));
+                continue;
+            }
+
+            let archive = Pk2Archive::open(entry.path())
+                .map_err(|err| format!("'{}': {}", entry.path().display(), err))?;
+            for (index, file) in archive.files().iter().enumerate() {
+                client.files.insert(
+                    relative.with_extension("").join(&file.path),
+                    Location::Archived(client.archives.len(), index),
+                );
+            }
+            client.archives.push(archive);
+        }
+        Ok(client)
+    }
+
+    fn size(&self, file: &Path) -> io::Result<u64> {
+        match self.files[file] {
+            Location::Loose(ref path) => Ok(fs::metadata(path)?.len()),
+            Location::Archived(archive, index) => {
+                Ok(u64::from(self.archives[archive].files()[index].size))
+            }
+        }
+    }
+
+    fn checksum(&mut self, file: &Path) -> io::Result<String> {
+        match self.files[file] {
+            Location::Loose(ref path) => hash::sha256_of(path),
+            Location::Archived(archive, index) => {
+                let content = self.archives[archive].read(index)?;
+                Ok(format!("{:x}", Sha256::digest(content)))
+            }
+        }
+    }
+
+    /// Copies the file to the target, returning its size.
+    fn copy(&mut self, file: &Path, target: &Path) -> io::Result<u64> {
+        match self.files[file] {
+            Location::Loose(ref path) => fs::copy(path, target),
+            Location::Archived(archive, index) => {
+                let content = self.archives[archive].read(index)?;
+                fs::write(target, &content)?;
+                Ok(content.len() as u64)
+            }
+        }
+    }
+}
+
+/// Whether the file is an archive at the top of the client install.
+fn is_archive(file: &Path) -> bool {
+    file.components().count() == 1
+        && file
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("pk2"))
+}
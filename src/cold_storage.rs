@@ -0,0 +1,150 @@
+use crate::hash;
+use crate::mirror::{Mirror, MirrorConfig};
+use crate::patch_manifest::{ListedFile, PatchManifest};
+use crate::upstream;
+use crate::{file_url, PatchFile, PatchFileserver, PatchProvider};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+/// Old versions moved to another fileserver, e.g. an object store, to free
+/// up the patch directory. Evicted versions stay listed in their
+/// `manifest.toml`, and clients that still need them are sent to the cold
+/// storage for their files, or get them once they're copied back.
+#[derive(Deserialize, Clone)]
+pub struct ColdStorageConfig {
+    /// Where clients download the files of evicted versions from.
+    pub fileserver: PatchFileserver,
+    /// Files are uploaded to `<upload_url>/<version>/<file>` via HTTP PUT.
+    pub upload_url: String,
+    /// The latest versions, which are never evicted.
+    pub keep_versions: usize,
+    /// Copies evicted versions back into the patch directory when a client
+    /// needs them, instead of sending the client to the cold storage.
+    #[serde(default)]
+    pub rehydrate: bool,
+}
+
+pub struct ColdStorage {
+    config: ColdStorageConfig,
+    /// Held while copying a version back, so it's only copied once.
+    rehydrating: Mutex<()>,
+}
+
+impl ColdStorage {
+    pub fn new(config: ColdStorageConfig) -> ColdStorage {
+        ColdStorage {
+            config,
+            rehydrating: Mutex::new(()),
+        }
+    }
+
+    pub fn fileserver(&self) -> &PatchFileserver {
+        &self.config.fileserver
+    }
+
+    pub fn rehydrates(&self) -> bool {
+        self.config.rehydrate
+    }
+
+    /// Copies the files of an evicted version back into the patch directory,
+    /// checking them against their checksums.
+    pub async fn rehydrate(&self, provider: &PatchProvider, version: u16) -> Result<(), String> {
+        let _rehydrating = self.rehydrating.lock().await;
+        if !provider.is_evicted(version) {
+            return Ok(());
+        }
+
+        let client = reqwest::Client::new();
+        for file in provider.files_of(version) {
+            let file = PatchFile {
+                file,
+                patch: version,
+            };
+            // Files inside the blob store are never evicted.
+            let path = provider.local_path(&file);
+            if path.is_file() {
+                continue;
+            }
+
+            let mut partial = path.clone().into_os_string();
+            partial.push(".partial");
+            let partial = PathBuf::from(partial);
+            let url = file_url(&self.config.fileserver, provider, &file);
+            upstream::download_file(&client, &url, &partial).await?;
+            if let Some(expected) = provider.hash_of(&file) {
+                let actual = hash::sha256_of(&partial).map_err(|err| err.to_string())?;
+                if actual != expected {
+                    let _ = fs::remove_file(&partial);
+                    return Err(format!("checksum of '{}' doesn't match", url));
+                }
+            }
+            fs::rename(&partial, &path).map_err(|err| err.to_string())?;
+        }
+        provider.set_evicted(version, false)
+    }
+}
+
+/// Uploads all but the latest `keep_versions` versions to the cold storage
+/// and removes their files from the patch directory. Their `manifest.toml`
+/// keeps listing their files, with checksums unless they're meant for a blob
+/// store. Returns the number of versions evicted.
+pub async fn evict(provider: &PatchProvider, config: &ColdStorageConfig) -> Result<usize, String> {
+    let mirror = Mirror::new(MirrorConfig {
+        name: "cold storage".to_string(),
+        fileserver: config.fileserver.clone(),
+        upload_url: config.upload_url.clone(),
+    });
+    let client = reqwest::Client::new();
+    let versions = provider.versions();
+    let evictable = versions.len().saturating_sub(config.keep_versions);
+    let mut evicted = 0;
+    for version in versions.into_iter().take(evictable) {
+        if provider.is_evicted(version) {
+            continue;
+        }
+
+        let version_dir = provider.patch_dir().join(version.to_string());
+        let mut listed = Vec::new();
+        let mut local = Vec::new();
+        for file in provider.files_of(version) {
+            mirror
+                .upload(&client, provider, version, &file)
+                .await
+                .map_err(|err| format!("could not upload '{}': {}", file.display(), err))?;
+
+            let patch_file = PatchFile {
+                file: file.clone(),
+                patch: version,
+            };
+            let path = provider.local_path(&patch_file);
+            let mut entry = provider
+                .listed(version, |manifest| manifest.get(&file).cloned())
+                .unwrap_or_else(|| ListedFile {
+                    path: file.clone(),
+                    size: provider.file_size(&patch_file),
+                    sha256: None,
+                    in_pk2: None,
+                });
+            // Checksums would move files into the blob store, if there is one.
+            if entry.sha256.is_none() && provider.blob_dir().is_none() {
+                entry.sha256 = Some(hash::sha256_of(&path).map_err(|err| err.to_string())?);
+            }
+            listed.push(entry);
+            if path.starts_with(&version_dir) {
+                local.push(path);
+            }
+        }
+
+        // Only removed once the listing says where the files are.
+        PatchManifest::write(&version_dir, listed, provider.deleted_of(version))
+            .map_err(|err| err.to_string())?;
+        provider.set_evicted(version, true)?;
+        for path in local {
+            fs::remove_file(&path).map_err(|err| format!("'{}': {}", path.display(), err))?;
+        }
+        evicted += 1;
+    }
+    Ok(evicted)
+}
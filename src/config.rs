@@ -0,0 +1,881 @@
+use crate::alert::AlertConfig;
+use crate::canary::CanaryList;
+use crate::cold_storage::ColdStorageConfig;
+use crate::delta::DeltaConfig;
+use crate::early_access::EarlyAccess;
+use crate::experiment::ExperimentConfig;
+use crate::file_hosting::FileHostingConfig;
+use crate::gateway_proxy::GatewayProxyConfig;
+use crate::latency::Latency;
+use crate::limits::LimitsConfig;
+use crate::maintenance::MaintenanceWindow;
+use crate::mirror::MirrorConfig;
+use crate::opcode_map::OpcodeMapping;
+use crate::patch_chain::ChainValidation;
+use crate::protocol::{
+    Farm, LoginBlock, LoginError, LoginResponse, LoginResult, PatchError, Shard, ShardListResponse,
+};
+use crate::region::Region;
+use crate::retention::RetentionConfig;
+use crate::rollout::Rollout;
+use crate::schedule::Activation;
+use crate::tenant::{Notice, DEFAULT_TENANT};
+use crate::upstream::UpstreamConfig;
+use crate::url_check::UrlCheckConfig;
+use crate::PatchFileserver;
+use chrono::{DateTime, FixedOffset, Timelike, Utc};
+use serde::{Deserialize, Deserializer};
+use serde_path_to_error::Segment;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use toml::{Table, Value};
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    /// The patch directory used if no channels are configured, `./patches`
+    /// by default.
+    pub patch_dir: Option<PathBuf>,
+    pub fileserver: PatchFileserver,
+    pub notices: Vec<Notice>,
+    pub channels: Vec<ChannelConfig>,
+    pub listeners: Vec<ListenerConfig>,
+    pub limits: LimitsConfig,
+    pub latency: Option<Latency>,
+    pub bind: Option<IpAddr>,
+    pub base_port: Option<u16>,
+    /// Additional patch services next to the one configured at the top level.
+    pub tenants: Vec<TenantConfig>,
+    pub admin: Option<AdminConfig>,
+    pub file_hosting: Option<FileHostingConfig>,
+    pub maintenance: Vec<MaintenanceWindow>,
+    /// MaxMind country database used to determine the region of clients.
+    pub geoip_database: Option<PathBuf>,
+    pub downgrades: DowngradeConfig,
+    pub alerts: AlertConfig,
+    pub response_size: ResponseSizeConfig,
+    /// How often patch directories are checked for changes made on disk.
+    #[serde(with = "humantime_serde")]
+    pub scan_interval: Option<Duration>,
+    pub scrub: Option<ScrubConfig>,
+    /// How often new versions are uploaded to the mirrors of all channels.
+    #[serde(with = "humantime_serde")]
+    pub mirror_sync_interval: Option<Duration>,
+    pub storage: StorageConfig,
+    /// Whether gaps, empty version directories, duplicate versions and
+    /// quarantined directories prevent a channel from starting.
+    pub chain_validation: ChainValidation,
+    /// Directory to write a transcript of every connection into.
+    pub transcripts: Option<PathBuf>,
+    /// File to append protocol events of all connections to, as JSON lines.
+    pub events: Option<PathBuf>,
+    pub compatibility: CompatibilityConfig,
+    pub notice_time: NoticeTimeConfig,
+    pub shard_list: ShardListConfig,
+    pub login_rejection: LoginRejectionConfig,
+    pub gateway_proxy: Option<GatewayProxyConfig>,
+    pub unknown_opcodes: UnknownOpcodes,
+    /// Opcodes of customized clients, see [OpcodeMapping].
+    pub opcodes: Vec<OpcodeMapping>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Guarantees that nothing is ever written into a patch directory, e.g.
+    /// because it's a read-only mount.
+    pub read_only: bool,
+    /// Directory holding everything the server keeps next to the patches,
+    /// such as manifests, in a subdirectory per tenant and channel. If not
+    /// set, it's kept inside the patch directory.
+    pub state_dir: Option<PathBuf>,
+}
+
+/// Regularly compares a few files with their manifest to detect corruption.
+#[derive(Deserialize, Clone, Copy)]
+pub struct ScrubConfig {
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// The number of files checked per channel each time.
+    #[serde(default = "default_scrub_files")]
+    pub files: usize,
+}
+
+fn default_scrub_files() -> usize {
+    100
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct DowngradeConfig {
+    /// Whether clients ahead of their target get reverted at all.
+    pub enabled: bool,
+    /// The error clients are answered with if downgrades are disabled.
+    pub rejection: Rejection,
+}
+
+impl Default for DowngradeConfig {
+    fn default() -> Self {
+        DowngradeConfig {
+            enabled: true,
+            rejection: Rejection::InvalidVersion,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct ResponseSizeConfig {
+    /// The size in bytes above which a patch response is considered too large.
+    pub max_size: Option<usize>,
+    /// The error sent instead of oversized responses. If not set, oversized
+    /// responses are only logged.
+    pub rejection: Option<Rejection>,
+}
+
+/// Details of responses in which server implementations differ, to match
+/// what clients picky about them saw from the official servers.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CompatibilityConfig {
+    /// The unused byte sent with up to date responses.
+    pub up_to_date_byte: u8,
+    /// The separator of directories in the paths of patch files.
+    pub path_separator: char,
+    /// The module name the server identifies itself with.
+    pub module_name: String,
+    /// The locality the server identifies itself with, which differs
+    /// between localizations of the client.
+    pub locality: u8,
+    pub notice_format: NoticeFormat,
+    pub request_format: RequestFormat,
+}
+
+/// The layout of notices sent to clients.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoticeFormat {
+    #[default]
+    Standard,
+    /// Without the published date, as older clients crash on it.
+    Legacy,
+}
+
+/// The layout of patch requests, which differs between client builds.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequestFormat {
+    #[default]
+    Standard,
+    /// With a 16 bit version, as sent by older clients.
+    Legacy,
+    /// Tells the layouts apart by the length of each request.
+    Auto,
+}
+
+impl Default for CompatibilityConfig {
+    fn default() -> Self {
+        CompatibilityConfig {
+            up_to_date_byte: 0,
+            path_separator: '/',
+            module_name: "GatewayServer".to_string(),
+            locality: 0x12,
+            notice_format: NoticeFormat::default(),
+            request_format: RequestFormat::default(),
+        }
+    }
+}
+
+/// What happens when a client sends a packet that isn't part of the patch
+/// protocol. It's logged with its payload either way.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownOpcodes {
+    /// Closes the connection.
+    #[default]
+    Disconnect,
+    /// Skips the packet and keeps reading, e.g. for modified clients sending
+    /// packets of their own.
+    Ignore,
+}
+
+/// The servers listed to launchers asking the gateway for them, none by
+/// default.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ShardListConfig {
+    pub farms: Vec<FarmConfig>,
+    pub shards: Vec<ShardConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FarmConfig {
+    pub id: u8,
+    pub name: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ShardConfig {
+    pub id: u16,
+    pub name: String,
+    #[serde(default)]
+    pub online: u16,
+    #[serde(default)]
+    pub capacity: u16,
+    /// Whether the server can be logged into, shown as offline otherwise.
+    #[serde(default)]
+    pub operating: bool,
+    pub farm_id: u8,
+}
+
+impl ShardListConfig {
+    pub fn to_response(&self) -> ShardListResponse {
+        ShardListResponse {
+            farms: self
+                .farms
+                .iter()
+                .map(|farm| Farm {
+                    id: farm.id,
+                    name: farm.name.clone(),
+                })
+                .collect(),
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| Shard {
+                    id: shard.id,
+                    name: shard.name.clone(),
+                    online: shard.online,
+                    capacity: shard.capacity,
+                    operating: shard.operating,
+                    farm_id: shard.farm_id,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The answer to clients trying to log in, as the patch server isn't a full
+/// gateway. Launchers show the message as the reason the account is blocked.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LoginRejectionConfig {
+    pub message: String,
+    /// The end of the block shown along with the message, the current time
+    /// by default.
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Default for LoginRejectionConfig {
+    fn default() -> Self {
+        LoginRejectionConfig {
+            message: "Please log in through the full gateway server.".to_string(),
+            until: None,
+        }
+    }
+}
+
+impl LoginRejectionConfig {
+    pub fn to_response(&self) -> LoginResponse {
+        LoginResponse {
+            result: LoginResult::Error {
+                error: LoginError::Blocked {
+                    block: LoginBlock::Punishment {
+                        reason: self.message.clone(),
+                        until: self.until.unwrap_or_else(Utc::now),
+                    },
+                },
+            },
+        }
+    }
+}
+
+/// How the published date of notices is sent. Launchers show the date as
+/// is, so it's shifted into the local time of the server beforehand.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct NoticeTimeConfig {
+    /// The offset of the local time from UTC, e.g. `+02:00`.
+    #[serde(deserialize_with = "deserialize_offset")]
+    pub utc_offset: Option<FixedOffset>,
+    pub precision: NoticePrecision,
+}
+
+/// The part of the published date that is kept, the rest is set to zero.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoticePrecision {
+    #[default]
+    Exact,
+    Minutes,
+    /// Only the date, as the time of a notice is rarely meaningful.
+    Day,
+}
+
+impl NoticeTimeConfig {
+    pub fn localize(&self, published: DateTime<Utc>) -> DateTime<Utc> {
+        let offset = self.utc_offset.unwrap_or(FixedOffset::east_opt(0).unwrap());
+        let local = published.with_timezone(&offset).naive_local();
+        let local = match self.precision {
+            NoticePrecision::Exact => local,
+            NoticePrecision::Minutes => local.with_second(0).unwrap().with_nanosecond(0).unwrap(),
+            NoticePrecision::Day => local.date().and_hms_opt(0, 0, 0).unwrap(),
+        };
+        local.and_utc()
+    }
+}
+
+fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<FixedOffset>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|offset| offset.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// The errors a patch request can be rejected with.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rejection {
+    InvalidVersion,
+    Offline,
+    InvalidClient,
+    PatchDisabled,
+}
+
+impl From<Rejection> for PatchError {
+    fn from(value: Rejection) -> Self {
+        match value {
+            Rejection::InvalidVersion => PatchError::InvalidVersion,
+            Rejection::Offline => PatchError::Offline,
+            Rejection::InvalidClient => PatchError::InvalidClient,
+            Rejection::PatchDisabled => PatchError::PatchDisabled,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TenantConfig {
+    pub name: String,
+    #[serde(default)]
+    pub fileserver: PatchFileserver,
+    #[serde(default)]
+    pub notices: Vec<Notice>,
+    pub channels: Vec<ChannelConfig>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Limits shared by all listeners of the tenant.
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Delays patch responses, to simulate slow networks.
+    #[serde(default)]
+    pub latency: Option<Latency>,
+    /// The address all listeners of the tenant bind to, `0.0.0.0` by
+    /// default. `::` accepts both IPv6 and IPv4 clients.
+    #[serde(default)]
+    pub bind: Option<IpAddr>,
+    /// The patch directory used if no channels are configured.
+    #[serde(default)]
+    pub patch_dir: Option<PathBuf>,
+    /// If no channels are configured, every version is served on this port
+    /// plus the version, [32000](DEFAULT_BASE_PORT) by default.
+    #[serde(default)]
+    pub base_port: Option<u16>,
+}
+
+/// Settings of a single port, overriding those of its tenant.
+#[derive(Deserialize)]
+pub struct ListenerConfig {
+    pub port: u16,
+    #[serde(flatten)]
+    pub overrides: ListenerOverrides,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ListenerOverrides {
+    pub bind: Option<IpAddr>,
+    pub fileserver: Option<PatchFileserver>,
+    pub notices: Option<Vec<Notice>>,
+    pub module_name: Option<String>,
+    pub locality: Option<u8>,
+    pub request_format: Option<RequestFormat>,
+    /// Forces the listener to be offline (`true`) or keeps it available
+    /// during maintenance windows (`false`).
+    pub maintenance: Option<bool>,
+    /// Limits of this listener, applied in addition to those of the tenant.
+    #[serde(default)]
+    pub limits: LimitsConfig,
+}
+
+#[derive(Deserialize)]
+pub struct AdminConfig {
+    /// Only reachable from the same host by default.
+    #[serde(default = "default_admin_bind")]
+    pub bind: SocketAddr,
+    /// Required by every request except replication ones, as `Authorization:
+    /// Bearer <token>`. Without it, the admin API only listens on loopback
+    /// addresses.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Allows a primary instance to push new versions to this one, using
+    /// this token. Replication is disabled without it.
+    #[serde(default)]
+    pub replication_token: Option<String>,
+    /// Patch directories given through the admin API, for new tenants or
+    /// staged patch trees, are relative to this directory and can't lead
+    /// outside of it. Without it, they're refused.
+    #[serde(default)]
+    pub patch_root: Option<PathBuf>,
+}
+
+fn default_admin_bind() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 32080))
+}
+
+#[derive(Deserialize)]
+pub struct ChannelConfig {
+    pub name: String,
+    #[serde(default = "default_patch_dir")]
+    pub patch_dir: PathBuf,
+    pub ports: Vec<u16>,
+    /// Module names (as sent in the identity or patch request) of the clients
+    /// this channel is meant for. Allows multiple channels to share a port.
+    #[serde(default)]
+    pub modules: Vec<String>,
+    /// Content parts (as sent in the patch request) this channel serves, e.g.
+    /// to keep the files of the client and its media apart.
+    #[serde(default)]
+    pub contents: Vec<u8>,
+    /// The version clients of this channel get patched to. If not set, the
+    /// highest version inside the patch directory that isn't scheduled for
+    /// a later point in time is used.
+    #[serde(default)]
+    pub target: Option<u16>,
+    /// Serves a different version to a share of the clients of this channel,
+    /// see [Rollout]. Can be adjusted at runtime through the admin API.
+    #[serde(default)]
+    pub rollout: Option<Rollout>,
+    /// Serves two candidate versions to separate groups of clients.
+    #[serde(default)]
+    pub experiment: Option<ExperimentConfig>,
+    #[serde(default)]
+    pub canaries: CanaryList,
+    /// Clients that are always patched to a fixed version, regardless of
+    /// anything else. Can be adjusted at runtime through the admin API.
+    #[serde(default)]
+    pub pins: BTreeMap<IpAddr, u16>,
+    /// The maximum number of versions a client may be reverted by. Clients
+    /// further ahead of the target are rejected with an invalid version.
+    #[serde(default)]
+    pub max_downgrade_depth: Option<usize>,
+    /// Handles clients on specific versions differently, e.g. by sending them
+    /// to another channel or rejecting them.
+    #[serde(default)]
+    pub version_ranges: Vec<VersionRangeConfig>,
+    /// Regions with their own target. Ports of regions are served in
+    /// addition to the ports of the channel.
+    #[serde(default)]
+    pub regions: Vec<Region>,
+    /// Stores files once per checksum, shared between versions. Existing
+    /// versions are moved into it with the `ingest` command.
+    pub blob_dir: Option<PathBuf>,
+    /// The Ed25519 key versions have to be signed with, as hex. Versions
+    /// without a valid `manifest.sig` are quarantined.
+    pub public_key: Option<String>,
+    /// Binary deltas between versions of large files, offered to launchers
+    /// on the built-in fileserver. Made with the `deltas` command.
+    pub deltas: Option<DeltaConfig>,
+    /// Checks files against the manifest of their version (`<version>.sha256`
+    /// inside the patch directory) before sending them to clients.
+    #[serde(default)]
+    pub verify_hashes: bool,
+    /// Versions that are served as if they were never published, e.g. because
+    /// they turned out to be broken. Their files come from the neighbouring
+    /// versions instead.
+    #[serde(default)]
+    pub skipped_versions: Vec<u16>,
+    /// Only serves versions added to the patch directory from now on to
+    /// canaries, until they're promoted through the admin API.
+    #[serde(default)]
+    pub stage_new_versions: bool,
+    /// Versions that should replace the target at a given point in time.
+    #[serde(default)]
+    pub schedule: Vec<Activation>,
+    /// Names of versions shown to humans, as the protocol only knows numbers.
+    #[serde(default)]
+    pub version_names: Vec<VersionName>,
+    /// Shows the changelog of the version clients get patched to as a notice.
+    #[serde(default)]
+    pub changelog_notice: bool,
+    /// The version expected to be the highest inside the patch directory, to
+    /// notice when a new version was not uploaded before a restart.
+    #[serde(default)]
+    pub expected_version: Option<u16>,
+    /// Refuses to start instead of warning if `expected_version` doesn't match.
+    #[serde(default)]
+    pub fail_on_drift: bool,
+    #[serde(default)]
+    pub early_access: Vec<EarlyAccess>,
+    #[serde(default)]
+    pub mirrors: Vec<MirrorConfig>,
+    #[serde(default)]
+    pub upstream: Option<UpstreamConfig>,
+    /// Retires, or deletes, versions that are no longer needed.
+    #[serde(default)]
+    pub retention: Option<RetentionConfig>,
+    /// Moves old versions to another fileserver to free up the patch directory.
+    #[serde(default)]
+    pub cold_storage: Option<ColdStorageConfig>,
+    /// Checks that the first file of an update is available on the
+    /// fileserver before advertising it, falling back to other mirrors.
+    #[serde(default)]
+    pub check_urls: Option<UrlCheckConfig>,
+    #[serde(default)]
+    pub version_fileservers: Vec<VersionFileserver>,
+}
+
+/// A fileserver hosting the versions between `min` and `max` (inclusive) of
+/// a channel instead of its regular fileserver, e.g. an archive for old
+/// versions.
+#[derive(Deserialize)]
+pub struct VersionFileserver {
+    #[serde(default)]
+    pub min: u16,
+    #[serde(default = "max_patch_version")]
+    pub max: u16,
+    pub fileserver: PatchFileserver,
+}
+
+fn max_patch_version() -> u16 {
+    u16::MAX
+}
+
+#[derive(Deserialize, Clone)]
+pub struct VersionName {
+    pub version: u16,
+    pub name: String,
+}
+
+/// Clients with a current version between `min` and `max` (inclusive) are
+/// either rejected, or patched to `target` of `channel`. If no channel is
+/// given, the channel the range belongs to is used, and if no target is given
+/// the regular target of that channel is used.
+#[derive(Deserialize)]
+pub struct VersionRangeConfig {
+    #[serde(default)]
+    pub min: u32,
+    #[serde(default = "max_version")]
+    pub max: u32,
+    pub target: Option<u16>,
+    pub channel: Option<String>,
+    pub reject: Option<Rejection>,
+}
+
+fn max_version() -> u32 {
+    u32::MAX
+}
+
+/// The port of version 0 if no channels are configured.
+pub const DEFAULT_BASE_PORT: u16 = 32000;
+
+pub fn default_patch_dir() -> PathBuf {
+    PathBuf::from("./patches")
+}
+
+impl Config {
+    /// Resolves the settings from layers, each overriding the ones before:
+    /// the config file, if it exists, the given command line options and
+    /// [environment variables](ENV_PREFIX).
+    pub fn resolve(path: &Path, command_line: Table) -> Result<Config, String> {
+        let mut settings = if path.exists() {
+            let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+            toml::from_str(&content).map_err(|err| err.to_string())?
+        } else {
+            Table::new()
+        };
+        merge(&mut settings, command_line);
+        let config = apply_environment(settings, env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Refuses settings that can't work together.
+    fn validate(&self) -> Result<(), String> {
+        // The built-in fileserver only serves `/<tenant>/<version>/<file>`.
+        if self.file_hosting.is_some() {
+            let fileservers = [(DEFAULT_TENANT, &self.fileserver)].into_iter().chain(
+                self.tenants
+                    .iter()
+                    .map(|tenant| (tenant.name.as_str(), &tenant.fileserver)),
+            );
+            for (tenant, fileserver) in fileservers {
+                if fileserver.path_template.is_some() {
+                    return Err(format!(
+                        "The fileserver of tenant '{}' has a path_template, which file_hosting doesn't support",
+                        tenant
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the configured tenants, including the one configured at the
+    /// top level if it has channels or is the only one. With file hosting,
+    /// their fileservers point to the built-in one.
+    pub fn take_tenants(&mut self) -> Vec<TenantConfig> {
+        let mut tenants = Vec::new();
+        if !self.channels.is_empty() || self.tenants.is_empty() {
+            tenants.push(TenantConfig {
+                name: DEFAULT_TENANT.to_string(),
+                fileserver: mem::take(&mut self.fileserver),
+                notices: mem::take(&mut self.notices),
+                channels: mem::take(&mut self.channels),
+                listeners: mem::take(&mut self.listeners),
+                limits: mem::take(&mut self.limits),
+                latency: self.latency.take(),
+                bind: self.bind.take(),
+                patch_dir: self.patch_dir.take(),
+                base_port: self.base_port.take(),
+            });
+        }
+        tenants.append(&mut self.tenants);
+        if let Some(file_hosting) = &self.file_hosting {
+            for tenant in &mut tenants {
+                file_hosting.advertise(&tenant.name, &mut tenant.fileserver);
+            }
+        }
+        tenants
+    }
+}
+
+/// Prefix of environment variables overriding settings, followed by the path
+/// to the setting in upper case, with `__` between nested keys and indexes
+/// into lists, e.g. `PATCH_SERVER_FILESERVER__HOST` or
+/// `PATCH_SERVER_CHANNELS__0__PORTS`. Values are parsed as TOML, falling back
+/// to a plain string.
+pub const ENV_PREFIX: &str = "PATCH_SERVER_";
+
+/// Overrides the settings with the [environment variables](ENV_PREFIX) among
+/// `variables` and deserializes the result.
+fn apply_environment(
+    settings: Table,
+    variables: impl IntoIterator<Item = (String, String)>,
+) -> Result<Config, String> {
+    let mut overrides = variables
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let keys = key
+                .strip_prefix(ENV_PREFIX)?
+                .to_lowercase()
+                .split("__")
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            Some((keys, key, value))
+        })
+        .collect::<Vec<_>>();
+    // Entries of lists are added in order of their index.
+    overrides.sort_by_cached_key(|(keys, _, _)| {
+        keys.iter()
+            .map(|key| (key.parse::<usize>().ok(), key.clone()))
+            .collect::<Vec<_>>()
+    });
+
+    let mut settings = Value::Table(settings);
+    // Overrides that were parsed as something other than a string.
+    let mut typed = Vec::new();
+    for (keys, key, value) in overrides {
+        let parsed = toml::from_str::<Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"));
+        let parsed = match parsed {
+            Some(parsed) => {
+                if !parsed.is_str() {
+                    typed.push((keys.clone(), value));
+                }
+                parsed
+            }
+            None => Value::String(value),
+        };
+        set(&mut settings, &keys, parsed).map_err(|err| format!("{}: {}", key, err))?;
+    }
+
+    loop {
+        let error = match serde_path_to_error::deserialize(settings.clone()) {
+            Ok(config) => return Ok(config),
+            Err(error) => error,
+        };
+        // Values that look like a number or boolean, such as a host made of
+        // digits, are used as strings if the setting doesn't take their type.
+        let path = error
+            .path()
+            .iter()
+            .map(|segment| match segment {
+                Segment::Seq { index } => index.to_string(),
+                Segment::Map { key } => key.clone(),
+                Segment::Enum { variant } => variant.clone(),
+                Segment::Unknown => String::new(),
+            })
+            .collect::<Vec<_>>();
+        let Some(index) = typed.iter().position(|(keys, _)| path.starts_with(keys)) else {
+            return Err(error.to_string());
+        };
+        let (keys, value) = typed.swap_remove(index);
+        set(&mut settings, &keys, Value::String(value))?;
+    }
+}
+
+/// Sets the setting at the path of `keys` below `entry`, creating missing
+/// tables and lists. Keys that are numbers index into lists, where the index
+/// after the last entry adds a new one.
+fn set(entry: &mut Value, keys: &[String], value: Value) -> Result<(), String> {
+    let Some((key, keys)) = keys.split_first() else {
+        *entry = value;
+        return Ok(());
+    };
+
+    let entry = match (entry, key.parse::<usize>()) {
+        (Value::Array(entries), Ok(index)) => {
+            if index == entries.len() {
+                entries.push(empty_entry(keys));
+            }
+            let len = entries.len();
+            entries
+                .get_mut(index)
+                .ok_or_else(|| format!("There is no entry {} in a list of {}", index, len))?
+        }
+        (entry, _) => {
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            entry
+                .as_table_mut()
+                .unwrap()
+                .entry(key.clone())
+                .or_insert_with(|| empty_entry(keys))
+        }
+    };
+    set(entry, keys, value)
+}
+
+/// An empty list if the next key is an index, an empty table otherwise.
+fn empty_entry(keys: &[String]) -> Value {
+    match keys.first().map(|key| key.parse::<usize>()) {
+        Some(Ok(_)) => Value::Array(Vec::new()),
+        _ => Value::Table(Table::new()),
+    }
+}
+
+/// Merges `overrides` into `settings`, replacing everything but tables,
+/// which are merged key by key.
+fn merge(settings: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (settings.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(value)) => merge(existing, value),
+            (_, value) => {
+                settings.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(config: &str, variables: &[(&str, &str)]) -> Result<Config, String> {
+        let variables = variables
+            .iter()
+            .map(|(key, value)| (format!("{}{}", ENV_PREFIX, key), value.to_string()));
+        apply_environment(toml::from_str(config).unwrap(), variables)
+    }
+
+    #[test]
+    fn environment_values_are_parsed_as_toml() {
+        let config = resolve(
+            "",
+            &[
+                ("BASE_PORT", "15000"),
+                ("FILESERVER__HOST", "patch.example.com"),
+                ("FILESERVER__PORT", "8080"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.base_port, Some(15000));
+        assert_eq!(config.fileserver.host, "patch.example.com");
+        assert_eq!(config.fileserver.port, 8080);
+    }
+
+    #[test]
+    fn environment_values_of_string_settings_stay_strings() {
+        let config = resolve(
+            "",
+            &[("FILESERVER__HOST", "1234"), ("FILESERVER__IP", "10")],
+        )
+        .unwrap();
+        assert_eq!(config.fileserver.host, "1234");
+        assert_eq!(config.fileserver.ip, "10");
+    }
+
+    #[test]
+    fn environment_values_of_the_wrong_type_are_rejected() {
+        let error = resolve("", &[("BASE_PORT", "high")]).err().unwrap();
+        assert!(error.starts_with("base_port: "), "{}", error);
+    }
+
+    #[test]
+    fn environment_keys_index_into_channels() {
+        let config = resolve(
+            "[[channels]]\nname = \"stable\"\nports = [15779]\n",
+            &[
+                ("CHANNELS__2__NAME", "beta"),
+                ("CHANNELS__2__PORTS", "[15980]"),
+                ("CHANNELS__0__PORTS__1", "15780"),
+                ("CHANNELS__0__TARGET", "31"),
+                ("CHANNELS__1__NAME", "2024"),
+                ("CHANNELS__1__PORTS__0", "15880"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.channels.len(), 3);
+        assert_eq!(config.channels[0].name, "stable");
+        assert_eq!(config.channels[0].ports, [15779, 15780]);
+        assert_eq!(config.channels[0].target, Some(31));
+        assert_eq!(config.channels[1].name, "2024");
+        assert_eq!(config.channels[1].ports, [15880]);
+        assert_eq!(config.channels[2].name, "beta");
+    }
+
+    #[test]
+    fn path_templates_are_rejected_with_file_hosting() {
+        let file_hosting = "[file_hosting]\nbind = \"0.0.0.0:8080\"\n";
+        let template = "path_template = \"{base}/{hash}/{filename}\"\n";
+        let top_level = format!("[fileserver]\n{}{}", template, file_hosting);
+        let tenant = format!(
+            "[[tenants]]\nname = \"eu\"\nchannels = []\n[tenants.fileserver]\n{}{}",
+            template, file_hosting
+        );
+        for config in [top_level, tenant] {
+            let config = resolve(&config, &[]).unwrap();
+            assert!(config.validate().is_err());
+        }
+        let config = resolve(&format!("[fileserver]\n{}", template), &[]).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn environment_keys_beyond_the_end_of_lists_are_rejected() {
+        let error = resolve("", &[("CHANNELS__1__NAME", "beta")]).err().unwrap();
+        assert!(
+            error.starts_with("PATCH_SERVER_CHANNELS__1__NAME: "),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{format_size, PatchFile, PatchProvider};
+use log::info;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Binary deltas between the versions of large files, which launchers
+/// download instead of the whole file if they have the version the delta
+/// was made from. Clients using the patch protocol always get whole files.
+#[derive(Deserialize, Clone)]
+pub struct DeltaConfig {
+    /// Where deltas are stored, as `<base>/<version>/<file>.<extension>`.
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub format: DeltaFormat,
+    /// Smaller files are always downloaded whole.
+    #[serde(default = "default_min_size")]
+    pub min_size: u64,
+}
+
+fn default_min_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// The tool deltas are made with, which has to be installed to generate
+/// them. Launchers need the same tool to apply them.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeltaFormat {
+    #[default]
+    Xdelta3,
+    Bsdiff,
+}
+
+impl DeltaFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            DeltaFormat::Xdelta3 => "vcdiff",
+            DeltaFormat::Bsdiff => "bsdiff",
+        }
+    }
+
+    fn command(self, old: &Path, new: &Path, delta: &Path) -> Command {
+        let mut command = match self {
+            DeltaFormat::Xdelta3 => {
+                let mut command = Command::new("xdelta3");
+                command.args(["-e", "-s"]);
+                command
+            }
+            DeltaFormat::Bsdiff => Command::new("bsdiff"),
+        };
+        command.arg(old).arg(new).arg(delta);
+        command
+    }
+}
+
+impl DeltaConfig {
+    /// The delta turning the file of version `base` into the one of `version`,
+    /// relative to the delta directory.
+    pub fn path_of(&self, base: u16, version: u16, file: &Path) -> PathBuf {
+        let mut name = file.as_os_str().to_os_string();
+        name.push(".");
+        name.push(self.format.extension());
+        PathBuf::from(base.to_string())
+            .join(version.to_string())
+            .join(name)
+    }
+}
+
+/// Makes the missing deltas of every file at least `min_size` large, from
+/// the previous version of the file. Deltas that aren't smaller than the
+/// file itself are discarded. Returns the number of deltas made.
+pub fn generate(provider: &PatchProvider, config: &DeltaConfig) -> Result<usize, String> {
+    let mut generated = 0;
+    for version in provider.versions() {
+        for file in provider.files_of(version) {
+            let Some(base) = version
+                .checked_sub(1)
+                .and_then(|previous| provider.version_up_to(&file, previous))
+            else {
+                continue;
+            };
+
+            let delta = config.dir.join(config.path_of(base, version, &file));
+            if delta.exists() {
+                continue;
+            }
+            let new = provider.local_path(&PatchFile {
+                file: file.clone(),
+                patch: version,
+            });
+            let size = fs::metadata(&new).map_err(|err| err.to_string())?.len();
+            if size < config.min_size {
+                continue;
+            }
+            let old = provider.local_path(&PatchFile {
+                file: file.clone(),
+                patch: base,
+            });
+
+            // Written next to it first, so an interrupted run never leaves
+            // a broken delta behind.
+            let mut partial = delta.clone().into_os_string();
+            partial.push(".partial");
+            let partial = PathBuf::from(partial);
+            fs::create_dir_all(partial.parent().unwrap()).map_err(|err| err.to_string())?;
+            let _ = fs::remove_file(&partial);
+            let status = config
+                .format
+                .command(&old, &new, &partial)
+                .status()
+                .map_err(|err| format!("could not run the delta tool: {}", err))?;
+            if !status.success() {
+                return Err(format!(
+                    "the delta of '{}' of version {} failed with {}",
+                    file.display(),
+                    version,
+                    status
+                ));
+            }
+
+            let delta_size = fs::metadata(&partial).map_err(|err| err.to_string())?.len();
+            if delta_size >= size {
+                fs::remove_file(&partial).map_err(|err| err.to_string())?;
+                continue;
+            }
+            fs::rename(&partial, &delta).map_err(|err| err.to_string())?;
+            info!(
+                "Made a delta of '{}' from version {} to {}, {} instead of {}",
+                file.display(),
+                base,
+                version,
+                format_size(delta_size),
+                format_size(size)
+            );
+            generated += 1;
+        }
+    }
+    Ok(generated)
+}
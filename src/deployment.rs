@@ -0,0 +1,100 @@
+use crate::channel::{self, Channel};
+use crate::url_check::{UrlCheck, UrlCheckConfig};
+use crate::{file_url, FileProblem, PatchFile, PatchProvider};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::task;
+
+/// The result of checking a patch tree before it's staged.
+#[derive(Serialize)]
+pub struct Validation {
+    pub patch_dir: PathBuf,
+    pub versions: Vec<u16>,
+    /// Everything keeping the tree from being staged.
+    pub problems: Vec<String>,
+}
+
+/// Loads the patch tree and checks it as thoroughly as possible, as it can
+/// be activated at once: every file has to be readable and match its
+/// manifest, if there is one, and the first file of every version has to be
+/// available on the fileserver.
+pub async fn validate(channel: &Channel, patch_dir: PathBuf) -> (PatchProvider, Validation) {
+    let live = channel.provider();
+    let name = channel.name().to_string();
+    let fileserver = live.fileserver();
+    let skipped = live.skipped();
+    let blob_dir = live.blob_dir();
+    let public_key = live.public_key();
+    let states = live.states();
+    let loaded_dir = patch_dir.clone();
+    let (provider, mut problems) = task::spawn_blocking(move || {
+        let provider = channel::load_provider(&name, loaded_dir, fileserver);
+        provider.set_blob_dir(blob_dir);
+        provider.set_public_key(public_key);
+        provider.set_states(states);
+        for version in skipped {
+            provider.skip(version);
+        }
+
+        let problems = check_files(&provider);
+        (provider, problems)
+    })
+    .await
+    .unwrap();
+
+    // Results of earlier checks may be outdated by now.
+    let url_check = UrlCheck::new(UrlCheckConfig {
+        cache: Duration::ZERO,
+    });
+    for version in provider.versions() {
+        let Some(file) = provider.files_of(version).into_iter().next() else {
+            continue;
+        };
+
+        let file = PatchFile {
+            file,
+            patch: version,
+        };
+        let url = file_url(&provider.fileserver(), &provider, &file);
+        if !url_check.is_available(&url).await {
+            problems.push(format!("'{}' is unavailable on the fileserver", url));
+        }
+    }
+
+    let validation = Validation {
+        patch_dir,
+        versions: provider.versions(),
+        problems,
+    };
+    (provider, validation)
+}
+
+/// Checks that every version could be loaded, is signed if there is a public
+/// key and every file is readable and matches its manifest, if there is one.
+pub fn check_files(provider: &PatchProvider) -> Vec<String> {
+    let mut problems = provider
+        .quarantined()
+        .into_iter()
+        .map(|quarantined| format!("'{}' {}", quarantined.directory, quarantined.reason))
+        .collect::<Vec<_>>();
+    let scan = provider.scan();
+    problems.extend(scan.problems.iter().map(describe));
+    problems.extend(provider.scrub(scan.checked).iter().map(describe));
+    problems.extend(
+        provider
+            .verify_signatures(&provider.versions())
+            .iter()
+            .map(describe),
+    );
+    problems
+}
+
+fn describe(problem: &FileProblem) -> String {
+    format!(
+        "File '{}' of version {}: {}",
+        problem.file.display(),
+        problem.version,
+        problem.error
+    )
+}
@@ -0,0 +1,101 @@
+use axum::http::header::{REFERER, USER_AGENT};
+use axum::http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Keeps the built-in fileserver to game clients, so others can't pull the
+/// patches through it.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct DownloadFilterConfig {
+    /// Parts of the user agents allowed to download, e.g. the name of the
+    /// launcher. Any user agent is allowed if empty.
+    pub user_agents: Vec<String>,
+    /// Prefixes of the referers allowed to download. Launchers don't send a
+    /// referer, so requests without one are always allowed, while links on
+    /// other websites are refused.
+    pub referers: Vec<String>,
+    /// The maximum number of files a single address downloads at the same time.
+    pub max_downloads_per_ip: Option<usize>,
+}
+
+pub struct DownloadFilter {
+    config: DownloadFilterConfig,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Keeps a download counted as active until dropped.
+pub struct DownloadPermit {
+    filter: Arc<DownloadFilter>,
+    address: IpAddr,
+}
+
+impl DownloadFilter {
+    pub fn new(config: DownloadFilterConfig) -> DownloadFilter {
+        DownloadFilter {
+            config,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the request, answering refused requests with `403 Forbidden`
+    /// and addresses with too many downloads with `429 Too Many Requests`.
+    pub fn admit(
+        self: &Arc<Self>,
+        address: IpAddr,
+        headers: &HeaderMap,
+    ) -> Result<DownloadPermit, StatusCode> {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        if !self.config.user_agents.is_empty() {
+            let user_agent = header(USER_AGENT).unwrap_or_default();
+            if !self
+                .config
+                .user_agents
+                .iter()
+                .any(|allowed| user_agent.contains(allowed.as_str()))
+            {
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+        if let Some(referer) = header(REFERER) {
+            if !self.config.referers.is_empty()
+                && !self
+                    .config
+                    .referers
+                    .iter()
+                    .any(|allowed| referer.starts_with(allowed.as_str()))
+            {
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+
+        let mut active = self.active.lock().unwrap();
+        let downloads = active.get(&address).copied().unwrap_or_default();
+        if self
+            .config
+            .max_downloads_per_ip
+            .is_some_and(|max| downloads >= max)
+        {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        active.insert(address, downloads + 1);
+        Ok(DownloadPermit {
+            filter: Arc::clone(self),
+            address,
+        })
+    }
+}
+
+impl Drop for DownloadPermit {
+    fn drop(&mut self) {
+        let mut active = self.filter.active.lock().unwrap();
+        if let Some(downloads) = active.get_mut(&self.address) {
+            *downloads -= 1;
+            if *downloads == 0 {
+                active.remove(&self.address);
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::protocol::{
+    GatewayNoticeRequest, GatewayNoticeResponse, IdentityInformation, KeepAlive,
+    LegacyGatewayNoticeResponse, LoginRequest, LoginResponse, PatchError, PatchProtocol,
+    PatchRequest, PatchResponse, PatchResult, ShardListRequest, ShardListResponse,
+};
+use log::{log_enabled, trace, Level};
+use skrillax_packet::Packet;
+use skrillax_serde::Serialize;
+use std::fmt::{Debug, Write};
+use std::net::SocketAddr;
+
+/// Log target of packet dumps. They are enabled by setting the level of this
+/// target to trace, e.g. `RUST_LOG=info,packets=trace`.
+pub const TARGET: &str = "packets";
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Notes on the fields of a packet which its decoded form doesn't show, such
+/// as the values enums are encoded as.
+pub trait Annotate {
+    fn annotations(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl Annotate for KeepAlive {}
+impl Annotate for GatewayNoticeRequest {}
+impl Annotate for GatewayNoticeResponse {}
+impl Annotate for LegacyGatewayNoticeResponse {}
+impl Annotate for ShardListRequest {}
+impl Annotate for LoginRequest {}
+impl Annotate for LoginResponse {}
+
+impl Annotate for ShardListResponse {
+    fn annotations(&self) -> Vec<String> {
+        vec![format!(
+            "shards: {} in {} farms",
+            self.shards.len(),
+            self.farms.len()
+        )]
+    }
+}
+
+impl Annotate for IdentityInformation {
+    fn annotations(&self) -> Vec<String> {
+        vec![format!("locality: 0x{:02X}", self.locality)]
+    }
+}
+
+impl Annotate for PatchRequest {
+    fn annotations(&self) -> Vec<String> {
+        vec![
+            format!("content: 0x{:02X}", self.content),
+            format!("version: shown as v1.{:03} by the client", self.version),
+        ]
+    }
+}
+
+impl Annotate for PatchResponse {
+    fn annotations(&self) -> Vec<String> {
+        let error = match &self.result {
+            PatchResult::UpToDate { .. } => return vec!["result: 1 (up to date)".to_string()],
+            PatchResult::Problem { error } => error,
+        };
+        let mut annotations = vec!["result: 2 (problem)".to_string()];
+        match error {
+            PatchError::InvalidVersion => annotations.push("error: 1 (invalid version)".into()),
+            PatchError::Update { patch_files, .. } => {
+                annotations.push("error: 2 (update)".to_string());
+                annotations.push(format!(
+                    "patch_files: {} files, {} bytes in total",
+                    patch_files.len(),
+                    patch_files
+                        .iter()
+                        .map(|file| u64::from(file.size))
+                        .sum::<u64>()
+                ));
+            }
+            PatchError::Offline => annotations.push("error: 3 (offline)".into()),
+            PatchError::InvalidClient => annotations.push("error: 4 (invalid client)".into()),
+            PatchError::PatchDisabled => annotations.push("error: 5 (patch disabled)".into()),
+        }
+        annotations
+    }
+}
+
+/// The bytes as space separated hex, e.g. `01 A2 FF`.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 3);
+    for byte in bytes {
+        write!(hex, "{:02X} ", byte).unwrap();
+    }
+    hex.truncate(hex.trim_end().len());
+    hex
+}
+
+/// Logs the opcode, length and hex payload of the packet, followed by its
+/// decoded form, if packet dumps are enabled.
+pub fn dump<P: Packet + Serialize + Debug + Annotate>(
+    peer: SocketAddr,
+    direction: Direction,
+    packet: &P,
+) {
+    if !log_enabled!(target: TARGET, Level::Trace) {
+        return;
+    }
+
+    let bytes = packet.to_bytes();
+    trace!(
+        target: TARGET,
+        "{} {} {} (0x{:04X}), {} bytes: {}",
+        peer,
+        match direction {
+            Direction::Inbound => "->",
+            Direction::Outbound => "<-",
+        },
+        P::NAME,
+        P::ID,
+        bytes.len(),
+        hex(&bytes)
+    );
+    let mut decoded = format!("{:#?}", packet);
+    for annotation in packet.annotations() {
+        write!(decoded, "\n// {}", annotation).unwrap();
+    }
+    trace!(target: TARGET, "{} {}: {}", peer, P::NAME, decoded);
+}
+
+/// Logs the opcode and length of a packet passed between the client and the
+/// gateway. Their payload isn't logged, as it contains credentials.
+pub fn dump_forwarded(peer: SocketAddr, direction: Direction, opcode: u16, size: usize) {
+    trace!(
+        target: TARGET,
+        "{} {} forwarded (0x{:04X}), {} bytes",
+        peer,
+        match direction {
+            Direction::Inbound => "->",
+            Direction::Outbound => "<-",
+        },
+        opcode,
+        size
+    );
+}
+
+pub fn dump_inbound(peer: SocketAddr, packet: &PatchProtocol) {
+    match packet {
+        PatchProtocol::KeepAlive(packet) => dump(peer, Direction::Inbound, packet),
+        PatchProtocol::PatchRequest(packet) => dump(peer, Direction::Inbound, packet),
+        PatchProtocol::IdentityInformation(packet) => dump(peer, Direction::Inbound, packet),
+        PatchProtocol::GatewayNoticeRequest(packet) => dump(peer, Direction::Inbound, packet),
+        PatchProtocol::ShardListRequest(packet) => dump(peer, Direction::Inbound, packet),
+        PatchProtocol::LoginRequest(packet) => dump(peer, Direction::Inbound, &packet.redacted()),
+    }
+}
@@ -0,0 +1,25 @@
+use crate::canary::deserialize_networks;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::IpAddr;
+
+/// Makes `version` visible only to clients inside `networks` until `until`
+/// has passed. Everyone else is served as if the version didn't exist.
+#[derive(Clone, Deserialize)]
+pub struct EarlyAccess {
+    pub version: u16,
+    #[serde(deserialize_with = "deserialize_networks")]
+    pub networks: Vec<IpNet>,
+    pub until: DateTime<Utc>,
+}
+
+impl EarlyAccess {
+    pub fn hides_from(&self, client: IpAddr, now: DateTime<Utc>) -> bool {
+        now < self.until
+            && !self
+                .networks
+                .iter()
+                .any(|network| network.contains(&client))
+    }
+}
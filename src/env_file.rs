@@ -0,0 +1,84 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Sets the variables of a `.env` file that aren't set already, so local
+/// settings can be kept out of the repository. Lines have the form
+/// `KEY=value`, with optional quotes around the value, and `#` starts a comment.
+///
+/// Setting variables isn't safe while other threads may read them, so this
+/// has to run before the runtime is started.
+pub fn load(path: &Path) -> Result<(), String> {
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Could not read '{}': {}", path.display(), err))?;
+    let variables = parse(&content).map_err(|err| format!("{} of '{}'", err, path.display()))?;
+    for (key, value) in variables {
+        // Variables set explicitly always take precedence.
+        if env::var_os(key).is_none() {
+            env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+fn parse(content: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut variables = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line
+            .split_once('=')
+            .filter(|(key, _)| !key.trim().is_empty())
+        else {
+            return Err(format!(
+                "Line {} should be of the form KEY=value",
+                index + 1
+            ));
+        };
+        let value = value.trim();
+        let value = unquote(value, '"')
+            .or_else(|| unquote(value, '\''))
+            .unwrap_or(value);
+        variables.push((key.trim(), value));
+    }
+    Ok(variables)
+}
+
+fn unquote(value: &str, quote: char) -> Option<&str> {
+    value
+        .strip_prefix(quote)
+        .and_then(|value| value.strip_suffix(quote))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_variables() {
+        let content = "# Local settings\n\nRUST_LOG=debug\nexport TOKEN = \"se cret\"\nNAME='x'\n";
+        assert_eq!(
+            parse(content),
+            Ok(vec![
+                ("RUST_LOG", "debug"),
+                ("TOKEN", "se cret"),
+                ("NAME", "x")
+            ])
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_malformed_variables() {
+        let error = "Line 3 should be of the form KEY=value".to_string();
+        assert_eq!(parse("A=1\n\nRUST_LOG\n"), Err(error.clone()));
+        assert_eq!(parse("A=1\n# B\n=value\n"), Err(error));
+    }
+}
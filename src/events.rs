@@ -0,0 +1,86 @@
+use crate::listener::Listener;
+use crate::metrics::Outcome;
+use chrono::Utc;
+use log::warn;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A protocol level event of a connection, for offline analysis.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    HandshakeCompleted,
+    RequestReceived {
+        module: &'a str,
+        version: u32,
+    },
+    PlanComputed {
+        channel: &'a str,
+        version: u32,
+        target: u16,
+        files: usize,
+        size: u64,
+    },
+    ResponseSent {
+        packet: &'static str,
+        outcome: Option<Outcome>,
+    },
+    ConnectionClosed {
+        duration_ms: u64,
+        packets_received: u64,
+        packets_sent: u64,
+        bytes_sent: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    time: String,
+    tenant: &'a str,
+    port: u16,
+    peer: SocketAddr,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+/// Appends the events of all connections as JSON lines to a single file.
+/// Does nothing if no file is configured.
+pub struct Events {
+    file: Option<Mutex<File>>,
+}
+
+impl Events {
+    pub fn open(path: Option<&Path>) -> Events {
+        let file = path.map(|path| {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("Should be able to open the event file");
+            Mutex::new(file)
+        });
+        Events { file }
+    }
+
+    pub fn emit(&self, listener: &Listener, peer: SocketAddr, event: Event) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let entry = Entry {
+            time: Utc::now().to_rfc3339(),
+            tenant: listener.tenant.name(),
+            port: listener.port,
+            peer,
+            event,
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        if let Err(err) = writeln!(file.lock().unwrap(), "{}", line) {
+            warn!("Could not write event: {}", err);
+        }
+    }
+}
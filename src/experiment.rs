@@ -0,0 +1,86 @@
+use crate::metrics::Outcome;
+use crate::rollout::client_bucket;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Serves two candidate versions to disjoint groups of clients. A share of
+/// `percentage` of all clients takes part, split evenly between both versions.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct ExperimentConfig {
+    pub versions: [u16; 2],
+    pub percentage: u8,
+}
+
+#[derive(Default)]
+pub struct VariantStatistics {
+    requests: AtomicU64,
+    up_to_date: AtomicU64,
+    updated: AtomicU64,
+    failed: AtomicU64,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct VariantCounts {
+    pub version: u16,
+    pub requests: u64,
+    pub up_to_date: u64,
+    pub updated: u64,
+    pub failed: u64,
+}
+
+pub struct Experiment {
+    config: ExperimentConfig,
+    statistics: [VariantStatistics; 2],
+}
+
+impl Experiment {
+    pub fn new(config: ExperimentConfig) -> Experiment {
+        Experiment {
+            config,
+            statistics: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> ExperimentConfig {
+        self.config
+    }
+
+    pub fn counts(&self) -> [VariantCounts; 2] {
+        [0, 1].map(|variant| {
+            let statistics = &self.statistics[variant];
+            VariantCounts {
+                version: self.version(variant),
+                requests: statistics.requests.load(Ordering::Relaxed),
+                up_to_date: statistics.up_to_date.load(Ordering::Relaxed),
+                updated: statistics.updated.load(Ordering::Relaxed),
+                failed: statistics.failed.load(Ordering::Relaxed),
+            }
+        })
+    }
+
+    /// Returns the variant the client belongs to, if it takes part at all.
+    /// Experiments take the highest buckets, as rollouts start from the
+    /// lowest ones, so both only share clients once they add up to more
+    /// than all of them.
+    pub fn variant_for(&self, client: IpAddr) -> Option<usize> {
+        let first = 100u8.saturating_sub(self.config.percentage);
+        let bucket = client_bucket(client);
+        (bucket >= first).then_some(usize::from((bucket - first) % 2))
+    }
+
+    pub fn version(&self, variant: usize) -> u16 {
+        self.config.versions[variant]
+    }
+
+    pub fn record(&self, variant: usize, outcome: Outcome) {
+        let statistics = &self.statistics[variant];
+        statistics.requests.fetch_add(1, Ordering::Relaxed);
+        let counter = match outcome {
+            Outcome::UpToDate => &statistics.up_to_date,
+            Outcome::Updated => &statistics.updated,
+            Outcome::Rejected => &statistics.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,137 @@
+use axum::body::Bytes;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io::{self, Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncSeekExt};
+
+/// Keeps small files of the built-in fileserver in memory, as most patches
+/// consist of a few dozen of them requested by every client at once.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct FileCacheConfig {
+    /// Bytes of files kept in memory, with the least recently used files
+    /// dropped first. Files are always read from disk if zero.
+    pub size: u64,
+    /// Larger files are always read from disk.
+    pub max_file_size: u64,
+}
+
+impl Default for FileCacheConfig {
+    fn default() -> Self {
+        FileCacheConfig {
+            size: 0,
+            max_file_size: 1024 * 1024,
+        }
+    }
+}
+
+pub struct FileCache {
+    config: FileCacheConfig,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    files: HashMap<PathBuf, CachedFile>,
+    size: u64,
+    /// Increases with every use, to tell which file was used least recently.
+    uses: u64,
+}
+
+struct CachedFile {
+    content: Bytes,
+    /// Files changed on disk are read again.
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+impl FileCache {
+    pub fn new(config: FileCacheConfig) -> FileCache {
+        FileCache {
+            config,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Opens the file at the offset, from memory if it's cached. Small files
+    /// are read into the cache instead of being opened.
+    pub async fn open(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        offset: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+        let modified = metadata.modified().ok();
+        let content = match self.get(path, modified) {
+            Some(content) => Some(content),
+            None if metadata.len() <= self.config.max_file_size
+                && metadata.len() <= self.config.size =>
+            {
+                let content = Bytes::from(fs::read(path).await?);
+                self.insert(path, content.clone(), modified);
+                Some(content)
+            }
+            None => None,
+        };
+
+        match content {
+            Some(content) => {
+                let offset = (offset as usize).min(content.len());
+                Ok(Box::new(Cursor::new(content.slice(offset..))))
+            }
+            None => {
+                let mut file = File::open(path).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+
+    fn get(&self, path: &Path, modified: Option<SystemTime>) -> Option<Bytes> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.uses += 1;
+        let uses = entries.uses;
+        let cached = entries.files.get_mut(path)?;
+        if cached.modified != modified {
+            return None;
+        }
+
+        cached.last_used = uses;
+        Some(cached.content.clone())
+    }
+
+    fn insert(&self, path: &Path, content: Bytes, modified: Option<SystemTime>) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(replaced) = entries.files.remove(path) {
+            entries.size -= replaced.content.len() as u64;
+        }
+        while entries.size + content.len() as u64 > self.config.size {
+            let Some(least_used) = entries
+                .files
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            let evicted = entries.files.remove(&least_used).unwrap();
+            entries.size -= evicted.content.len() as u64;
+        }
+
+        entries.size += content.len() as u64;
+        let last_used = entries.uses;
+        entries.files.insert(
+            path.to_path_buf(),
+            CachedFile {
+                content,
+                modified,
+                last_used,
+            },
+        );
+    }
+}
@@ -0,0 +1,933 @@
+use crate::bandwidth::{BandwidthConfig, ByteBucket};
+use crate::channel::{Channel, ClientInfo};
+use crate::download_filter::{DownloadFilter, DownloadFilterConfig, DownloadPermit};
+use crate::file_cache::{FileCache, FileCacheConfig};
+use crate::safe_path::{self, Root};
+use crate::server::Server;
+use crate::signing::{SigningConfig, UrlSigner};
+use crate::{file_url, PatchFile, PatchFileserver, PatchProvider};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use log::{debug, info, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::task;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+
+/// Serves the patch files over HTTP, replacing a separate fileserver. Files
+/// of a tenant are served as `/<tenant>/<version>/<file>`, and tenants
+/// advertise this server to their clients.
+#[derive(Deserialize)]
+pub struct FileHostingConfig {
+    pub bind: SocketAddr,
+    /// The IP advertised to clients. Defaults to the address bound to, or
+    /// the IP of the tenant's fileserver if bound to all addresses.
+    pub ip: Option<String>,
+    /// The host advertised to clients, defaulting like the IP.
+    pub host: Option<String>,
+    /// Serves the files over HTTPS instead.
+    pub tls: Option<TlsConfig>,
+    /// Also speaks HTTP/2, negotiated over HTTPS or, without TLS, with
+    /// clients starting with it right away.
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    /// The number of files a single HTTP/2 connection downloads at once.
+    #[serde(default = "default_max_streams")]
+    pub max_streams: u32,
+    /// The number of connections served at once.
+    pub max_connections: Option<usize>,
+    /// Sent with every file for caches in front of the fileserver, e.g.
+    /// `public, max-age=86400`.
+    pub cache_control: Option<String>,
+    #[serde(default)]
+    pub cache: FileCacheConfig,
+    /// Can be changed at runtime through the admin API.
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    pub signing: Option<SigningConfig>,
+    #[serde(default)]
+    pub filter: DownloadFilterConfig,
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+fn default_max_streams() -> u32 {
+    100
+}
+
+/// The format of dates in headers, always in GMT.
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Large enough to keep the overhead of throttling low, small enough to
+/// pace downloads smoothly.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate, followed by its chain.
+    pub cert: PathBuf,
+    /// PEM file with the private key.
+    pub key: PathBuf,
+}
+
+impl FileHostingConfig {
+    /// Points the fileserver of the tenant to this server.
+    pub fn advertise(&self, tenant: &str, fileserver: &mut PatchFileserver) {
+        let bound_ip = Some(self.bind.ip())
+            .filter(|ip| !ip.is_unspecified())
+            .map(|ip| ip.to_string());
+        if let Some(ip) = self.ip.clone().or(bound_ip.clone()) {
+            fileserver.ip = ip;
+        }
+        if let Some(host) = self.host.clone().or(bound_ip) {
+            fileserver.host = host;
+        }
+        fileserver.port = self.bind.port();
+        fileserver.base_path = format!("/{}", tenant);
+        // Clients can only be told about HTTPS through full URLs.
+        fileserver.scheme = self.tls.as_ref().map(|_| "https".to_string());
+        fileserver.signer = self.signing.as_ref().map(UrlSigner::new);
+    }
+}
+
+#[derive(Clone)]
+struct HostingState {
+    server: Arc<Server>,
+    /// The separator of directories in advertised paths.
+    path_separator: char,
+    signer: Option<UrlSigner>,
+    filter: Arc<DownloadFilter>,
+    cache_control: Option<HeaderValue>,
+    cache: Arc<FileCache>,
+}
+
+#[derive(Deserialize)]
+struct Signature {
+    expires: Option<u64>,
+    signature: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Checksums {
+    version: u16,
+    files: BTreeMap<PathBuf, String>,
+}
+
+#[derive(Deserialize)]
+struct FilePath {
+    tenant: String,
+    version: u16,
+    file: String,
+}
+
+#[derive(Deserialize)]
+struct DeltaPath {
+    tenant: String,
+    base: u16,
+    version: u16,
+    file: String,
+}
+
+/// The files a client needs to update, for launchers that download them
+/// on their own.
+#[derive(Serialize)]
+struct UpdatePlan {
+    target: u16,
+    files: Vec<PlannedFile>,
+}
+
+#[derive(Serialize)]
+struct PlannedFile {
+    path: PathBuf,
+    version: u16,
+    size: u32,
+    in_pk2: bool,
+    url: String,
+    /// Offered if the client has the version of the file it was made from,
+    /// otherwise the whole file has to be downloaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<PlannedDelta>,
+}
+
+#[derive(Serialize)]
+struct PlannedDelta {
+    base: u16,
+    size: u64,
+    url: String,
+}
+
+pub async fn serve(
+    config: FileHostingConfig,
+    path_separator: char,
+    server: Arc<Server>,
+    cancel_token: CancellationToken,
+) {
+    server.bandwidth().set_config(config.bandwidth);
+    // Hashed up front, so launchers don't have to wait for it.
+    let hashed = Arc::clone(&server);
+    task::spawn_blocking(move || compute_checksums(&hashed));
+
+    let router = Router::new()
+        .route("/{tenant}/checksums/{version}", get(checksums))
+        .route("/{tenant}/updates/{current}/{target}", get(update_plan))
+        .route(
+            "/{tenant}/deltas/{base}/{version}/{*file}",
+            get(download_delta),
+        )
+        .route("/{tenant}/{version}/{*file}", get(download))
+        .with_state(HostingState {
+            server,
+            path_separator,
+            signer: config.signing.as_ref().map(UrlSigner::new),
+            filter: Arc::new(DownloadFilter::new(config.filter)),
+            cache_control: config.cache_control.as_deref().map(|cache_control| {
+                HeaderValue::from_str(cache_control)
+                    .expect("cache_control should be a valid header")
+            }),
+            cache: Arc::new(FileCache::new(config.cache)),
+        });
+
+    let acceptor = config.tls.as_ref().map(|tls| {
+        tls_acceptor(tls, config.http2).expect("Should be able to load the TLS certificate")
+    });
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http2().max_concurrent_streams(config.max_streams);
+    if !config.http2 {
+        builder = builder.http1_only();
+    }
+    let connections = config
+        .max_connections
+        .map(|max_connections| Arc::new(Semaphore::new(max_connections)));
+
+    let listener = TcpListener::bind(config.bind).await.unwrap();
+    info!(
+        "Serving patch files on {}{}",
+        config.bind,
+        if acceptor.is_some() {
+            " over HTTPS"
+        } else {
+            ""
+        }
+    );
+    loop {
+        // Further connections wait in the backlog of the socket until one
+        // of the served ones closes.
+        let permit = match &connections {
+            Some(connections) => tokio::select! {
+                permit = Arc::clone(connections).acquire_owned() => Some(permit.unwrap()),
+                _ = cancel_token.cancelled() => return,
+            },
+            None => None,
+        };
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("Could not accept download connection: {}", err);
+                    continue;
+                }
+            },
+            _ = cancel_token.cancelled() => return,
+        };
+
+        let acceptor = acceptor.clone();
+        let builder = builder.clone();
+        let service = TowerToHyperService::new(router.clone().layer(Extension(ConnectInfo(peer))));
+        let cancel_token = cancel_token.clone();
+        // The TLS handshake happens in the task as well, so slow clients
+        // don't hold up others.
+        tokio::spawn(async move {
+            let _permit = permit;
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => serve_connection(&builder, stream, service, &cancel_token).await,
+                    Err(err) => {
+                        debug!("TLS handshake with {} failed: {}", peer, err);
+                        return;
+                    }
+                },
+                None => serve_connection(&builder, stream, service, &cancel_token).await,
+            };
+            if let Err(err) = result {
+                debug!("Download connection of {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+fn tls_acceptor(config: &TlsConfig, http2: bool) -> Result<TlsAcceptor, String> {
+    let chain = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("'{}': {}", config.cert.display(), err))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|err| format!("'{}': {}", config.key.display(), err))?;
+    let mut server_config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+            .map_err(|err| err.to_string())?;
+    if http2 {
+        server_config.alpn_protocols.push(b"h2".to_vec());
+    }
+    server_config.alpn_protocols.push(b"http/1.1".to_vec());
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Serves requests over HTTP/1.1 or HTTP/2, whichever the client starts
+/// with, until the connection is closed or the server shuts down.
+async fn serve_connection<I>(
+    builder: &auto::Builder<TokioExecutor>,
+    stream: I,
+    service: TowerToHyperService<Router>,
+    cancel_token: &CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::select! {
+        result = builder.serve_connection(TokioIo::new(stream), service) => result,
+        _ = cancel_token.cancelled() => Ok(()),
+    }
+}
+
+fn compute_checksums(server: &Server) {
+    for tenant in server.tenants() {
+        for channel in tenant.channels() {
+            let provider = channel.provider();
+            let mut files = 0;
+            for version in provider.versions() {
+                match provider.checksums(version) {
+                    Ok(checksums) => files += checksums.len(),
+                    Err(err) => warn!(
+                        "Could not compute the checksums of version {} of channel '{}': {}",
+                        version,
+                        channel.name(),
+                        err
+                    ),
+                }
+            }
+            info!(
+                "Computed the checksums of {} files of channel '{}'",
+                files,
+                channel.name()
+            );
+        }
+    }
+}
+
+/// Lists the SHA-256 checksums of the files of a version, for launchers to
+/// verify their downloads with. Versions hidden from the launcher, such as
+/// staged ones, aren't listed.
+async fn checksums(
+    State(state): State<HostingState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path((tenant, version)): Path<(String, u16)>,
+    headers: HeaderMap,
+) -> Result<Json<Checksums>, StatusCode> {
+    let _permit = state.filter.admit(peer.ip().to_canonical(), &headers)?;
+    let tenant = state
+        .server
+        .find_tenant(&tenant)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let provider = providers_serving(tenant.channels(), Some(&launcher(peer)), version)
+        .into_iter()
+        .find(|provider| !provider.files_of(version).is_empty())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let files = task::spawn_blocking(move || provider.checksums(version))
+        .await
+        .unwrap()
+        .map_err(|err| {
+            warn!(
+                "Could not compute the checksums of version {}: {}",
+                version, err
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(Checksums { version, files }))
+}
+
+/// Lists the files a client on `current` needs to reach `target` in the first
+/// channel of the tenant serving it, together with the deltas it can use
+/// instead. Versions hidden from the launcher are planned around, like for
+/// game clients, and can't be the target.
+async fn update_plan(
+    State(state): State<HostingState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path((tenant, current, target)): Path<(String, u16, u16)>,
+    headers: HeaderMap,
+) -> Result<Json<UpdatePlan>, StatusCode> {
+    let _permit = state.filter.admit(peer.ip().to_canonical(), &headers)?;
+    let tenant = state
+        .server
+        .find_tenant(&tenant)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let client = launcher(peer);
+    let (provider, hidden) = tenant
+        .channels()
+        .iter()
+        .find_map(|channel| {
+            let provider = channel.provider();
+            let hidden = channel.hidden_versions(&client);
+            (provider.versions().contains(&target) && !hidden.contains(&target))
+                .then_some((provider, hidden))
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let files = task::spawn_blocking(move || {
+        let fileserver = provider.fileserver();
+        provider
+            .collect_files_hiding(current, target, &hidden)
+            .into_iter()
+            .map(|file| PlannedFile {
+                path: file.file.clone(),
+                version: file.patch,
+                size: provider.file_size(&file),
+                in_pk2: provider.in_pk2(&file),
+                url: file_url(&fileserver, &provider, &file),
+                delta: planned_delta(&provider, &fileserver, &file, current),
+            })
+            .collect()
+    })
+    .await
+    .unwrap();
+    Ok(Json(UpdatePlan { target, files }))
+}
+
+/// The patch trees of the channels serving the version to the client, in
+/// the order of the channels. Excluded versions, such as retired or
+/// quarantined ones, are never served. Versions hidden from the client, such
+/// as staged ones, are only served through signed paths, given as `None`, as
+/// those are only handed to clients allowed to see the version.
+fn providers_serving(
+    channels: &[Arc<Channel>],
+    client: Option<&ClientInfo>,
+    version: u16,
+) -> Vec<Arc<PatchProvider>> {
+    channels
+        .iter()
+        .filter(|channel| {
+            client.is_none_or(|client| !channel.hidden_versions(client).contains(&version))
+        })
+        .map(|channel| channel.provider())
+        .filter(|provider| provider.versions().contains(&version))
+        .collect()
+}
+
+/// Describes a launcher to the channels. Launchers don't connect to a
+/// listener or send a module, so only canaries and early access by address
+/// apply to them.
+fn launcher(peer: SocketAddr) -> ClientInfo<'static> {
+    ClientInfo {
+        address: peer.ip().to_canonical(),
+        port: 0,
+        module: "",
+    }
+}
+
+fn planned_delta(
+    provider: &PatchProvider,
+    fileserver: &PatchFileserver,
+    file: &PatchFile,
+    current: u16,
+) -> Option<PlannedDelta> {
+    let deltas = provider.deltas()?;
+    let (base, relative) = provider.delta_for(file, current)?;
+    let size = std::fs::metadata(deltas.dir.join(&relative)).ok()?.len();
+    let path = format!(
+        "{}/deltas/{}",
+        fileserver.base_path,
+        relative.to_str()?.replace('\\', "/")
+    );
+    let path = match &fileserver.signer {
+        Some(signer) => format!("{}{}", path, signer.sign(&path)),
+        None => path,
+    };
+    Some(PlannedDelta {
+        base,
+        size,
+        url: fileserver.url_of(&path),
+    })
+}
+
+/// Streams a delta between two versions of a file, like [download].
+async fn download_delta(
+    State(state): State<HostingState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(path): Path<DeltaPath>,
+    Query(signature): Query<Signature>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let signed_path = format!(
+        "/{}/deltas/{}/{}/{}",
+        path.tenant, path.base, path.version, path.file
+    );
+    check_signature(&state, &signed_path, &signature)?;
+    let permit = state.filter.admit(peer.ip().to_canonical(), &headers)?;
+    let delta = safe_path::normalize(&path.file).ok_or(StatusCode::NOT_FOUND)?;
+
+    let tenant = state
+        .server
+        .find_tenant(&path.tenant)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let client = state.signer.is_none().then(|| launcher(peer));
+    let (deltas, file) = providers_serving(tenant.channels(), client.as_ref(), path.version)
+        .into_iter()
+        .find_map(|provider| {
+            let deltas = provider.deltas()?;
+            let file = delta
+                .to_str()?
+                .strip_suffix(deltas.format.extension())?
+                .strip_suffix('.')?;
+            let file = PathBuf::from(file);
+            provider
+                .has_file(path.version, &file)
+                .then_some((deltas, file))
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let local_path = Root::new(&deltas.dir)
+        .and_then(|root| root.resolve(&deltas.path_of(path.base, path.version, &file)))
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    serve_file(state, &headers, permit, local_path, None).await
+}
+
+/// Streams the file, or the requested range of it, from the first channel of
+/// the tenant that serves it. Only files known to a channel are served, so
+/// nothing outside of the patch directories can be requested, and only of
+/// versions the channel serves to the client. With signing, the path has to
+/// carry a valid, unexpired signature.
+async fn download(
+    State(state): State<HostingState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(path): Path<FilePath>,
+    Query(signature): Query<Signature>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    // Clients request the files with the separator they were advertised with.
+    let file = path.file.replace(state.path_separator, "/");
+    let signed_path = format!("/{}/{}/{}", path.tenant, path.version, file);
+    check_signature(&state, &signed_path, &signature)?;
+    let permit = state.filter.admit(peer.ip().to_canonical(), &headers)?;
+    let file = safe_path::normalize(&file).ok_or(StatusCode::NOT_FOUND)?;
+
+    let tenant = state
+        .server
+        .find_tenant(&path.tenant)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let client = state.signer.is_none().then(|| launcher(peer));
+    let provider = providers_serving(tenant.channels(), client.as_ref(), path.version)
+        .into_iter()
+        .find(|provider| provider.has_file(path.version, &file))
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let requested = PatchFile {
+        file,
+        patch: path.version,
+    };
+    // The file may have been replaced by a symlink since it was listed.
+    let local_path = provider.resolve(&requested).map_err(|err| {
+        warn!("Refusing to serve '{}': {}", path.file, err);
+        StatusCode::NOT_FOUND
+    })?;
+    let hash = provider.hash_of(&requested);
+    serve_file(state, &headers, permit, local_path, hash).await
+}
+
+/// Fails unless the path carries a valid, unexpired signature, if paths are
+/// signed.
+fn check_signature(
+    state: &HostingState,
+    path: &str,
+    signature: &Signature,
+) -> Result<(), StatusCode> {
+    let Some(signer) = &state.signer else {
+        return Ok(());
+    };
+
+    let valid = match (signature.expires, &signature.signature) {
+        (Some(expires), Some(signature)) => signer.verify(path, expires, signature),
+        _ => false,
+    };
+    if !valid {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// Streams the file, or the range of it requested in the headers, answering
+/// conditional requests with its checksum, if known, as the entity tag.
+async fn serve_file(
+    state: HostingState,
+    headers: &HeaderMap,
+    permit: DownloadPermit,
+    local_path: PathBuf,
+    hash: Option<String>,
+) -> Result<Response, StatusCode> {
+    let metadata = fs::metadata(&local_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let size = metadata.len();
+    let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+    // Files that are the same in several versions share their checksum, so
+    // caches only keep them once.
+    let etag = match hash {
+        Some(hash) => format!("\"{}\"", hash),
+        None => format!(
+            "W/\"{:x}-{:x}\"",
+            size,
+            modified.map_or(0, |modified| modified.timestamp())
+        ),
+    };
+    let mut cache_headers = HeaderMap::new();
+    cache_headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+    if let Some(modified) = modified {
+        let date = modified.format(HTTP_DATE_FORMAT).to_string();
+        cache_headers.insert(LAST_MODIFIED, HeaderValue::from_str(&date).unwrap());
+    }
+    if let Some(cache_control) = &state.cache_control {
+        cache_headers.insert(CACHE_CONTROL, cache_control.clone());
+    }
+
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let not_modified = match header(IF_NONE_MATCH) {
+        Some(tags) => tags
+            .split(',')
+            .any(|tag| tag.trim() == etag || tag.trim() == "*"),
+        None => header(IF_MODIFIED_SINCE)
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .zip(modified)
+            .is_some_and(|(since, modified)| modified.timestamp() <= since.timestamp()),
+    };
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    // A range of a different version of the file than the client has would
+    // corrupt it, so the whole file is sent instead.
+    let range = match (header(RANGE), header(IF_RANGE)) {
+        (Some(range), None) => ByteRange::parse(range, size),
+        (Some(range), Some(tag)) if tag == etag => ByteRange::parse(range, size),
+        _ => ByteRange::Full,
+    };
+    let open = |offset| async move {
+        state
+            .cache
+            .open(&local_path, &metadata, offset)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+    };
+    let response = match range {
+        ByteRange::Full => (
+            StatusCode::OK,
+            cache_headers,
+            [
+                (CONTENT_TYPE, "application/octet-stream".to_string()),
+                (CONTENT_LENGTH, size.to_string()),
+                (ACCEPT_RANGES, "bytes".to_string()),
+            ],
+            throttled_body(state.server.clone(), open(0).await?, permit),
+        )
+            .into_response(),
+        ByteRange::Partial(start, end) => {
+            let length = end - start + 1;
+            (
+                StatusCode::PARTIAL_CONTENT,
+                cache_headers,
+                [
+                    (CONTENT_TYPE, "application/octet-stream".to_string()),
+                    (CONTENT_LENGTH, length.to_string()),
+                    (ACCEPT_RANGES, "bytes".to_string()),
+                    (CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)),
+                ],
+                throttled_body(
+                    state.server.clone(),
+                    open(start).await?.take(length),
+                    permit,
+                ),
+            )
+                .into_response()
+        }
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(CONTENT_RANGE, format!("bytes */{}", size))],
+        )
+            .into_response(),
+    };
+    Ok(response)
+}
+
+/// Streams the content of the reader, paced by the bandwidth limits.
+/// The download counts as active until the body is sent or dropped.
+fn throttled_body(
+    server: Arc<Server>,
+    reader: impl AsyncRead + Unpin + Send + 'static,
+    permit: DownloadPermit,
+) -> Body {
+    let chunks = ReaderStream::with_capacity(reader, CHUNK_SIZE);
+    let chunks = stream::unfold(
+        (chunks, ByteBucket::default(), permit),
+        move |(mut chunks, mut bucket, permit)| {
+            let server = Arc::clone(&server);
+            async move {
+                let chunk = chunks.next().await?;
+                if let Ok(bytes) = &chunk {
+                    server.bandwidth().throttle(&mut bucket, bytes.len()).await;
+                }
+                Some((chunk, (chunks, bucket, permit)))
+            }
+        },
+    );
+    Body::from_stream(chunks)
+}
+
+/// The part of a file requested with a `Range` header, used by launchers to
+/// resume interrupted downloads.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    /// The first and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses a single range of bytes. Multiple ranges, other units and
+    /// malformed headers are answered with the whole file, as allowed.
+    fn parse(range: &str, size: u64) -> ByteRange {
+        let Some(range) = range.trim().strip_prefix("bytes=") else {
+            return ByteRange::Full;
+        };
+        let Some((start, end)) = range.split_once('-') else {
+            return ByteRange::Full;
+        };
+        if range.contains(',') {
+            return ByteRange::Full;
+        }
+
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => return ByteRange::Unsatisfiable,
+                Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+                Err(_) => return ByteRange::Full,
+            },
+            (start, "") => match start.parse::<u64>() {
+                Ok(start) => (start, size.saturating_sub(1)),
+                Err(_) => return ByteRange::Full,
+            },
+            (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+                (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+                _ => return ByteRange::Full,
+            },
+        };
+        if start >= size {
+            return ByteRange::Unsatisfiable;
+        }
+        ByteRange::Partial(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canary::CanaryList;
+    use crate::early_access::EarlyAccess;
+    use crate::promotion::PatchState;
+    use crate::{Patch, Quarantined};
+    use chrono::TimeDelta;
+    use std::{env, process};
+
+    /// A channel with versions 1 to 3, each containing `Media.pk2`.
+    fn channel(name: &str) -> Channel {
+        let dir = env::temp_dir().join(format!("file-hosting-{}-{}", process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let channel = Channel::load(name.to_string(), dir.clone(), PatchFileserver::default());
+        std::fs::remove_dir_all(&dir).unwrap();
+        for version in 1..=3 {
+            channel.provider().add_patch(Patch {
+                version,
+                files: Box::new([PathBuf::from("Media.pk2")]),
+                deleted: Box::default(),
+                manifest: None,
+                changelog: None,
+            });
+        }
+        channel
+    }
+
+    fn client() -> ClientInfo<'static> {
+        launcher(SocketAddr::from(([192, 0, 2, 1], 50000)))
+    }
+
+    fn served(channel: Channel, client: Option<&ClientInfo>, version: u16) -> bool {
+        !providers_serving(&[Arc::new(channel)], client, version).is_empty()
+    }
+
+    #[test]
+    fn live_versions_are_served() {
+        assert!(served(channel("live"), Some(&client()), 2));
+        assert!(!served(channel("unknown"), Some(&client()), 4));
+    }
+
+    #[test]
+    fn staged_versions_are_only_served_to_canaries_or_signed_paths() {
+        let staged = || {
+            let channel = channel("staged");
+            channel.provider().set_state(3, PatchState::Staged).unwrap();
+            channel
+        };
+        assert!(!served(staged(), Some(&client()), 3));
+        assert!(served(staged(), Some(&client()), 2));
+        assert!(served(staged(), None, 3));
+
+        let canaries = CanaryList {
+            networks: vec!["192.0.2.0/24".parse().unwrap()],
+            tokens: Vec::new(),
+        };
+        assert!(served(staged().with_canaries(canaries), Some(&client()), 3));
+    }
+
+    #[test]
+    fn early_access_versions_are_only_served_inside_their_networks_or_signed_paths() {
+        let early_access = |network: &str| {
+            channel("early-access").with_early_access(vec![EarlyAccess {
+                version: 3,
+                networks: vec![network.parse().unwrap()],
+                until: Utc::now() + TimeDelta::days(1),
+            }])
+        };
+        assert!(!served(early_access("10.0.0.0/8"), Some(&client()), 3));
+        assert!(served(early_access("192.0.2.0/24"), Some(&client()), 3));
+        assert!(served(early_access("10.0.0.0/8"), None, 3));
+    }
+
+    #[test]
+    fn retired_versions_are_never_served() {
+        let channel = channel("retired");
+        channel
+            .provider()
+            .set_state(2, PatchState::Retired)
+            .unwrap();
+        assert!(!served(channel, None, 2));
+    }
+
+    #[test]
+    fn quarantined_versions_are_never_served() {
+        let channel = channel("quarantined");
+        channel.provider().quarantine(Quarantined {
+            directory: "2".to_string(),
+            version: Some(2),
+            reason: "has an invalid signature".to_string(),
+        });
+        assert!(!served(channel, None, 2));
+    }
+
+    #[test]
+    fn byte_range_parses_closed_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-499", 1000),
+            ByteRange::Partial(0, 499)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=500-500", 1000),
+            ByteRange::Partial(500, 500)
+        );
+        assert_eq!(
+            ByteRange::parse(" bytes=1 - 2 ", 1000),
+            ByteRange::Partial(1, 2)
+        );
+    }
+
+    #[test]
+    fn byte_range_clamps_the_end_to_the_file() {
+        assert_eq!(
+            ByteRange::parse("bytes=900-2000", 1000),
+            ByteRange::Partial(900, 999)
+        );
+    }
+
+    #[test]
+    fn byte_range_parses_open_ended_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=400-", 1000),
+            ByteRange::Partial(400, 999)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=999-", 1000),
+            ByteRange::Partial(999, 999)
+        );
+    }
+
+    #[test]
+    fn byte_range_parses_suffix_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=-100", 1000),
+            ByteRange::Partial(900, 999)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-5000", 1000),
+            ByteRange::Partial(0, 999)
+        );
+    }
+
+    #[test]
+    fn byte_range_answers_multiple_and_overlapping_ranges_in_full() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-99,200-299", 1000),
+            ByteRange::Full
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=0-499,400-999", 1000),
+            ByteRange::Full
+        );
+        assert_eq!(ByteRange::parse("bytes=-100,0-", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn byte_range_answers_malformed_ranges_in_full() {
+        assert_eq!(ByteRange::parse("items=0-99", 1000), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=100", 1000), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=a-b", 1000), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=-", 1000), ByteRange::Full);
+        assert_eq!(ByteRange::parse("bytes=500-100", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn byte_range_refuses_unsatisfiable_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=1000-", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=1000-1999", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(ByteRange::parse("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=-100", 0), ByteRange::Unsatisfiable);
+    }
+}
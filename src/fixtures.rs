@@ -0,0 +1,182 @@
+use crate::config::{LoginRejectionConfig, Rejection};
+use crate::protocol::{
+    self, GatewayNoticeResponse, IdentityInformation, PatchError, PatchProtocol, PatchResponse,
+    PatchResult, ShardListResponse,
+};
+use crate::tenant::Notice;
+use crate::PatchFileserver;
+use log::{debug, info};
+use serde::Deserialize;
+use skrillax_stream::handshake::ActiveSecuritySetup;
+use skrillax_stream::stream::SilkroadTcpExt;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Canned answers for a mock server, read from `plans.json` inside the
+/// fixture directory. Allows other projects to run the server in their
+/// integration tests without setting up a patch directory.
+#[derive(Deserialize)]
+struct Fixtures {
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default)]
+    fileserver: PatchFileserver,
+    plans: Vec<Plan>,
+    /// Read from `notices.json` inside the fixture directory, if it exists.
+    #[serde(skip)]
+    notices: Vec<Notice>,
+}
+
+/// The answer to clients on `version`. Clients are rejected if `reject` is
+/// set, patched if `target` differs from their version and considered up to
+/// date otherwise.
+#[derive(Deserialize)]
+struct Plan {
+    version: u32,
+    target: Option<u16>,
+    #[serde(default)]
+    files: Vec<FixtureFile>,
+    reject: Option<Rejection>,
+}
+
+#[derive(Deserialize)]
+struct FixtureFile {
+    /// The path of the file inside its version, e.g. `Media/server_dep/silkroad/textdata/itemdata.txt`.
+    path: String,
+    version: u16,
+    size: u32,
+}
+
+fn default_port() -> u16 {
+    15779
+}
+
+impl Fixtures {
+    fn load(dir: &Path) -> Fixtures {
+        let plans = fs::read_to_string(dir.join("plans.json")).expect("plans.json should exist");
+        let mut fixtures: Fixtures =
+            serde_json::from_str(&plans).expect("plans.json should be valid");
+        let notices = dir.join("notices.json");
+        if notices.exists() {
+            let notices = fs::read_to_string(notices).unwrap();
+            fixtures.notices =
+                serde_json::from_str(&notices).expect("notices.json should be valid");
+        }
+        fixtures
+    }
+
+    fn answer(&self, version: u32) -> PatchResult {
+        let Some(plan) = self.plans.iter().find(|plan| plan.version == version) else {
+            return PatchResult::Problem {
+                error: PatchError::InvalidVersion,
+            };
+        };
+
+        if let Some(rejection) = plan.reject {
+            return PatchResult::Problem {
+                error: rejection.into(),
+            };
+        }
+
+        match plan.target {
+            Some(target) if u32::from(target) != version => PatchResult::Problem {
+                error: PatchError::Update {
+                    server_ip: self.fileserver.ip().to_string(),
+                    server_port: self.fileserver.port(),
+                    current_version: target.into(),
+                    patch_files: plan
+                        .files
+                        .iter()
+                        .enumerate()
+                        .map(|(index, file)| protocol::PatchFile {
+                            file_id: index as u32,
+                            filename: file.path.rsplit('/').next().unwrap().to_string(),
+                            file_path: self.fileserver.advertised_path(
+                                file.version,
+                                &file.path,
+                                None,
+                                '/',
+                            ),
+                            size: file.size,
+                            in_pk2: file.path.contains('/'),
+                        })
+                        .collect(),
+                    http_server: self.fileserver.host().to_string(),
+                },
+            },
+            _ => PatchResult::UpToDate { unknown: 0 },
+        }
+    }
+}
+
+/// Runs a mock server answering from the fixtures in the directory, until
+/// the process is stopped.
+pub async fn serve(dir: &Path) {
+    let fixtures = Arc::new(Fixtures::load(dir));
+    let listener = TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, fixtures.port)))
+        .await
+        .unwrap();
+    // The port may be 0 to bind to a free one, so log the actual port.
+    info!(
+        "Serving {} fixture plans from '{}' on port {}",
+        fixtures.plans.len(),
+        dir.display(),
+        listener.local_addr().unwrap().port()
+    );
+
+    loop {
+        let (stream, peer) = listener.accept().await.unwrap();
+        debug!("Fixture client connected from {}", peer);
+        tokio::spawn(handle_client(stream, Arc::clone(&fixtures)));
+    }
+}
+
+async fn handle_client(client: TcpStream, fixtures: Arc<Fixtures>) {
+    let (mut reader, mut writer) = client.into_silkroad_stream();
+    ActiveSecuritySetup::handle(&mut reader, &mut writer)
+        .await
+        .unwrap();
+
+    while let Ok(packet) = reader.next_packet::<PatchProtocol>().await {
+        match *packet {
+            PatchProtocol::KeepAlive(_) => {}
+            PatchProtocol::PatchRequest(request) => writer
+                .write_packet(PatchResponse {
+                    result: fixtures.answer(request.version),
+                })
+                .await
+                .unwrap(),
+            PatchProtocol::IdentityInformation(_) => writer
+                .write_packet(IdentityInformation {
+                    module_name: "GatewayServer".to_string(),
+                    locality: 0x12,
+                })
+                .await
+                .unwrap(),
+            PatchProtocol::LoginRequest(_) => writer
+                .write_packet(LoginRejectionConfig::default().to_response())
+                .await
+                .unwrap(),
+            PatchProtocol::ShardListRequest(_) => writer
+                .write_packet(ShardListResponse {
+                    farms: Vec::new(),
+                    shards: Vec::new(),
+                })
+                .await
+                .unwrap(),
+            PatchProtocol::GatewayNoticeRequest(_) => writer
+                .write_packet(GatewayNoticeResponse {
+                    notices: fixtures
+                        .notices
+                        .iter()
+                        .map(|notice| notice.to_gateway_notice())
+                        .collect(),
+                })
+                .await
+                .unwrap(),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::protocol::{GatewayNoticeRequest, IdentityInformation, KeepAlive, PatchRequest};
+use bytes::Bytes;
+use serde::Deserialize;
+use skrillax_packet::Packet;
+use skrillax_stream::handshake::PassiveSecuritySetup;
+use skrillax_stream::packet::OutgoingPacket;
+use skrillax_stream::stream::{SilkroadStreamRead, SilkroadStreamWrite, SilkroadTcpExt};
+use std::future;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// Forwards everything outside the patch protocol, such as the shard list
+/// and logins, to a real gateway server. This lets the patch server sit in
+/// front of the gateway on the same port.
+#[derive(Deserialize, Clone)]
+pub struct GatewayProxyConfig {
+    /// Where the gateway server listens, e.g. `127.0.0.1:15780`.
+    pub address: String,
+    /// Opcodes sent encrypted, in both directions. Only the login request by
+    /// default, like the official gateway.
+    #[serde(default = "default_encrypted_opcodes")]
+    pub encrypted_opcodes: Vec<u16>,
+}
+
+fn default_encrypted_opcodes() -> Vec<u16> {
+    vec![0x6102]
+}
+
+/// Packets answered by the patch server itself, even if there's a gateway.
+const ANSWERED_LOCALLY: [u16; 4] = [
+    KeepAlive::ID,
+    IdentityInformation::ID,
+    PatchRequest::ID,
+    GatewayNoticeRequest::ID,
+];
+
+struct Connection {
+    reader: SilkroadStreamRead<OwnedReadHalf>,
+    writer: SilkroadStreamWrite<OwnedWriteHalf>,
+}
+
+/// The gateway connection of a single client. It's only opened once the
+/// client sends something for the gateway, which patching clients never do.
+pub struct GatewayProxy<'a> {
+    config: &'a GatewayProxyConfig,
+    identity: Option<IdentityInformation>,
+    connection: Option<Connection>,
+}
+
+impl<'a> GatewayProxy<'a> {
+    pub fn new(config: &'a GatewayProxyConfig) -> GatewayProxy<'a> {
+        GatewayProxy {
+            config,
+            identity: None,
+            connection: None,
+        }
+    }
+
+    /// Remembers how the client identified, to identify the same way to the
+    /// gateway.
+    pub fn identify(&mut self, identity: &IdentityInformation) {
+        self.identity = Some(identity.clone());
+    }
+
+    /// Whether a packet with the opcode goes to the gateway. Keep-alives are
+    /// only passed on once the gateway is connected, so it doesn't time out
+    /// the client.
+    pub fn forwards(&self, opcode: u16) -> bool {
+        if opcode == KeepAlive::ID {
+            return self.connection.is_some();
+        }
+        !ANSWERED_LOCALLY.contains(&opcode)
+    }
+
+    /// Sends a packet of the client to the gateway, connecting first if
+    /// necessary.
+    pub async fn forward(&mut self, opcode: u16, data: Bytes) -> Result<(), String> {
+        let packet = self.packet(opcode, data);
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            connection => connection.insert(Self::connect(self.config, &self.identity).await?),
+        };
+        connection
+            .writer
+            .write(packet)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /// The next packet of the gateway for the client. Never completes until
+    /// the gateway is connected, and returns `None` once it disconnects.
+    pub async fn next(&mut self) -> Option<(u16, Bytes)> {
+        let Some(connection) = &mut self.connection else {
+            return future::pending().await;
+        };
+        loop {
+            let (opcode, data) = connection.reader.next().await.ok()?.consume();
+            // The client already got the identity of the patch server.
+            if opcode != IdentityInformation::ID {
+                return Some((opcode, data));
+            }
+        }
+    }
+
+    /// Wraps the payload of a forwarded packet, encrypted if configured.
+    pub fn packet(&self, opcode: u16, data: Bytes) -> OutgoingPacket {
+        OutgoingPacket::Simple {
+            opcode,
+            encrypted: self.config.encrypted_opcodes.contains(&opcode),
+            massive: false,
+            data,
+        }
+    }
+
+    async fn connect(
+        config: &GatewayProxyConfig,
+        identity: &Option<IdentityInformation>,
+    ) -> Result<Connection, String> {
+        let stream = TcpStream::connect(&config.address)
+            .await
+            .map_err(|err| format!("could not connect to '{}': {}", config.address, err))?;
+        let (mut reader, mut writer) = stream.into_silkroad_stream();
+        PassiveSecuritySetup::handle(&mut reader, &mut writer)
+            .await
+            .map_err(|err| format!("handshake with '{}' failed: {}", config.address, err))?;
+        if let Some(identity) = identity {
+            writer
+                .write_packet(identity.clone())
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(Connection { reader, writer })
+    }
+}
+
+/// The next packet of the gateway, if the client is proxied at all.
+pub async fn next(proxy: Option<&mut GatewayProxy<'_>>) -> Option<(u16, Bytes)> {
+    match proxy {
+        Some(proxy) => proxy.next().await,
+        None => future::pending().await,
+    }
+}
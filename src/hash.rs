@@ -0,0 +1,69 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Checksums of the files of a version, stored in the format of `sha256sum`
+/// next to the version directory, e.g. `patches/30.sha256`.
+pub struct Manifest {
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl Manifest {
+    pub fn path_for(patch_dir: &Path, version: u16) -> PathBuf {
+        patch_dir.join(format!("{}.sha256", version))
+    }
+
+    pub fn is_manifest(path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|extension| extension == "sha256")
+    }
+
+    pub fn load(path: &Path) -> io::Result<Manifest> {
+        Ok(Manifest::parse(&fs::read_to_string(path)?))
+    }
+
+    pub fn parse(content: &str) -> Manifest {
+        let hashes = content
+            .lines()
+            .filter_map(|line| line.split_once(char::is_whitespace))
+            .map(|(hash, file)| {
+                // `sha256sum` marks files hashed in binary mode with a `*`.
+                let file = file.trim_start().trim_start_matches('*');
+                let file = file.strip_prefix("./").unwrap_or(file);
+                (PathBuf::from(file), hash.to_ascii_lowercase())
+            })
+            .collect();
+        Manifest { hashes }
+    }
+
+    pub fn from_hashes(hashes: HashMap<PathBuf, String>) -> Manifest {
+        Manifest { hashes }
+    }
+
+    /// Renders the manifest in the format of `sha256sum`.
+    pub fn render(&self) -> String {
+        let mut files = self.hashes.iter().collect::<Vec<_>>();
+        files.sort();
+        files
+            .into_iter()
+            .map(|(file, hash)| format!("{}  {}\n", hash, file.display()))
+            .collect()
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.hashes.keys().map(PathBuf::as_path)
+    }
+
+    pub fn hash_of(&self, file: &Path) -> Option<&str> {
+        self.hashes.get(file).map(String::as_str)
+    }
+}
+
+/// Computes the SHA-256 checksum of the file as lowercase hex.
+pub fn sha256_of(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
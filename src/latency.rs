@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+use std::hash::{BuildHasher, RandomState};
+use std::time::Duration;
+
+/// An artificial delay before answering patch requests, to simulate slow
+/// networks. Each response is delayed by `delay` plus a random share of `jitter`.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Latency {
+    #[serde(with = "humantime_serde")]
+    pub delay: Duration,
+    #[serde(default, with = "humantime_serde")]
+    pub jitter: Duration,
+}
+
+impl Latency {
+    pub fn sample(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+
+        // Every `RandomState` is seeded differently, which is random enough here.
+        let random = RandomState::new().hash_one(0u8);
+        let jitter = random % self.jitter.as_micros().max(1) as u64;
+        self.delay + Duration::from_micros(jitter)
+    }
+}
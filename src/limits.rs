@@ -0,0 +1,84 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// The maximum number of clients connected at the same time.
+    pub max_connections: Option<usize>,
+    /// The maximum number of new connections per second.
+    pub connections_per_second: Option<u32>,
+}
+
+/// Enforces the connection limits of a tenant or listener.
+pub struct Limiter {
+    config: LimitsConfig,
+    active: AtomicUsize,
+    bucket: Mutex<TokenBucket>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Keeps a connection counted as active until dropped.
+pub struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Limiter {
+    pub fn new(config: LimitsConfig) -> Limiter {
+        Limiter {
+            config,
+            active: AtomicUsize::new(0),
+            bucket: Mutex::new(TokenBucket {
+                tokens: config.connections_per_second.unwrap_or_default() as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let active = self.active.fetch_add(1, Ordering::AcqRel);
+        let permit = Permit {
+            limiter: Arc::clone(self),
+        };
+        if let Some(max_connections) = self.config.max_connections {
+            if active >= max_connections {
+                return None;
+            }
+        }
+
+        if let Some(rate) = self.config.connections_per_second {
+            if !self.bucket.lock().unwrap().try_take(rate) {
+                return None;
+            }
+        }
+
+        Some(permit)
+    }
+}
+
+impl TokenBucket {
+    fn try_take(&mut self, rate: u32) -> bool {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * rate as f64;
+        self.tokens = (self.tokens + refilled).min(rate as f64);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,180 @@
+use crate::channel::Channel;
+use crate::config::{CompatibilityConfig, ListenerOverrides, Rejection, RequestFormat};
+use crate::limits::{Limiter, Permit};
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::tenant::{Notice, Tenant};
+use crate::{PatchFileserver, PatchProvider};
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A channel served on a listener together with the version its clients
+/// get patched to by default. Without a target, the current default target
+/// of the channel is used.
+#[derive(Clone)]
+pub struct Route {
+    pub channel: Arc<Channel>,
+    pub target: Option<u16>,
+    pub version_ranges: Vec<VersionRange>,
+}
+
+/// Handles clients whose current version lies within `min..=max` differently
+/// from the rest of the route.
+#[derive(Clone)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+    pub action: RangeAction,
+}
+
+#[derive(Clone)]
+pub enum RangeAction {
+    Route(Box<Route>),
+    Reject(Rejection),
+}
+
+impl Route {
+    pub fn new(channel: Arc<Channel>, target: Option<u16>) -> Route {
+        Route {
+            channel,
+            target,
+            version_ranges: Vec::new(),
+        }
+    }
+
+    pub fn target(&self) -> Option<u16> {
+        self.target.or_else(|| self.channel.default_target())
+    }
+
+    /// Finds the route to use for a client currently on the given version.
+    pub fn resolve(&self, version: u32) -> Result<&Route, Rejection> {
+        let range = self
+            .version_ranges
+            .iter()
+            .find(|range| range.min <= version && version <= range.max);
+        match range.map(|range| &range.action) {
+            Some(RangeAction::Route(route)) => Ok(route),
+            Some(RangeAction::Reject(rejection)) => Err(*rejection),
+            None => Ok(self),
+        }
+    }
+}
+
+/// A port clients connect to, serving one or more channels of a tenant.
+pub struct Listener {
+    pub port: u16,
+    pub tenant: Arc<Tenant>,
+    pub routes: Vec<Route>,
+    pub overrides: ListenerOverrides,
+    pub limiter: Arc<Limiter>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Listener {
+    pub fn new(
+        port: u16,
+        tenant: Arc<Tenant>,
+        routes: Vec<Route>,
+        overrides: ListenerOverrides,
+    ) -> Listener {
+        Listener {
+            port,
+            limiter: Arc::new(Limiter::new(overrides.limits)),
+            metrics: tenant.metrics_for(port),
+            tenant,
+            routes,
+            overrides,
+        }
+    }
+
+    /// Checks the connection limits of both the tenant and this listener.
+    /// The connection counts as active until the permits are dropped.
+    pub fn try_admit(&self) -> Option<(Permit, Permit)> {
+        let tenant_permit = self.tenant.limiter().try_acquire()?;
+        let listener_permit = self.limiter.try_acquire()?;
+        Some((tenant_permit, listener_permit))
+    }
+    pub fn address(&self) -> SocketAddr {
+        let ip = self.overrides.bind.unwrap_or_else(|| self.tenant.bind());
+        SocketAddr::new(ip, self.port)
+    }
+
+    pub fn is_offline(&self, maintenance: &Maintenance) -> bool {
+        self.overrides
+            .maintenance
+            .unwrap_or_else(|| maintenance.current().is_some())
+    }
+
+    pub fn notices(&self) -> Vec<Notice> {
+        self.overrides
+            .notices
+            .clone()
+            .unwrap_or_else(|| self.tenant.notices())
+    }
+
+    pub fn module_name<'a>(&'a self, compatibility: &'a CompatibilityConfig) -> &'a str {
+        self.overrides
+            .module_name
+            .as_deref()
+            .unwrap_or(&compatibility.module_name)
+    }
+
+    pub fn locality(&self, compatibility: &CompatibilityConfig) -> u8 {
+        self.overrides.locality.unwrap_or(compatibility.locality)
+    }
+
+    pub fn request_format(&self, compatibility: &CompatibilityConfig) -> RequestFormat {
+        self.overrides
+            .request_format
+            .unwrap_or(compatibility.request_format)
+    }
+
+    /// The fileservers that can be advertised for a plan with files of the
+    /// given versions, most preferred first. Mirrors of the channel are
+    /// preferred once they received all of the versions, followed by the
+    /// fileserver of the provider the plan was made with.
+    pub fn fileservers_for(
+        &self,
+        channel: &Channel,
+        provider: &PatchProvider,
+        versions: &BTreeSet<u16>,
+    ) -> Vec<PatchFileserver> {
+        if let Some(fileserver) = &self.overrides.fileserver {
+            return vec![fileserver.clone()];
+        }
+
+        let mut fileservers = channel.mirrors_for(versions);
+        fileservers.push(provider.fileserver());
+        fileservers
+    }
+
+    /// Picks the route for a client identifying itself with the given module
+    /// names and asking for the given content part. Channels naming the
+    /// module are preferred over those that don't restrict their modules,
+    /// followed by channels naming the content part over those serving all.
+    /// Without a match, the first channel serving all content parts is used,
+    /// and clients asking for a content part no channel serves are rejected.
+    pub fn route(&self, modules: &[&str], content: u8) -> Result<&Route, Rejection> {
+        self.routes
+            .iter()
+            .filter(|route| route.channel.accepts_content(content))
+            .filter_map(|route| {
+                let channel = &route.channel;
+                let names_module = modules.iter().any(|module| channel.accepts_module(module));
+                if !names_module && !channel.modules().is_empty() {
+                    return None;
+                }
+                Some(((names_module, !channel.contents().is_empty()), route))
+            })
+            .min_by_key(|(rank, _)| Reverse(*rank))
+            .map(|(_, route)| route)
+            .or_else(|| {
+                self.routes
+                    .iter()
+                    .find(|route| route.channel.contents().is_empty())
+            })
+            .ok_or(Rejection::InvalidClient)
+    }
+}
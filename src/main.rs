@@ -1,26 +1,156 @@
+mod admin;
+mod alert;
+mod bandwidth;
+mod blob_store;
+mod canary;
+mod changelog;
+mod channel;
+mod check;
+mod cli;
+mod client;
+mod client_diff;
+mod cold_storage;
+mod config;
+mod delta;
+mod deployment;
+mod download_filter;
+mod dump;
+mod early_access;
+mod env_file;
+mod events;
+mod experiment;
+mod file_cache;
+mod file_hosting;
+mod fixtures;
+mod gateway_proxy;
+mod hash;
+mod latency;
+mod limits;
+mod listener;
+mod maintenance;
+mod metrics;
+mod mirror;
+mod opcode_map;
+mod patch_archive;
+mod patch_chain;
+mod patch_manifest;
+mod patch_signing;
+mod pk2;
+#[cfg(feature = "profiling")]
+mod profiling;
+mod promotion;
 mod protocol;
+mod reference;
+mod region;
+mod replication;
+mod retention;
+mod rollout;
+mod safe_path;
+mod schedule;
+mod server;
+mod signing;
+mod tenant;
+mod transcript;
+#[cfg(feature = "tui")]
+mod tui;
+mod upstream;
+mod url_check;
 
+use crate::alert::Alerts;
+use crate::changelog::Changelog;
+use crate::channel::{Channel, ClientInfo, TargetReason};
+use crate::cli::{Cli, Command};
+use crate::client::PatchClient;
+use crate::config::{
+    CompatibilityConfig, Config, LoginRejectionConfig, NoticeFormat, NoticeTimeConfig, Rejection,
+    ResponseSizeConfig, ShardListConfig, UnknownOpcodes,
+};
+use crate::delta::DeltaConfig;
+use crate::dump::{Annotate, Direction};
+use crate::events::{Event, Events};
+use crate::gateway_proxy::{GatewayProxy, GatewayProxyConfig};
+use crate::hash::Manifest;
+use crate::listener::Listener;
+use crate::maintenance::Maintenance;
+use crate::metrics::{Outcome, Transfer};
+use crate::opcode_map::OpcodeMap;
+use crate::patch_manifest::PatchManifest;
+use crate::patch_signing::PublicKey;
+use crate::promotion::{PatchState, PatchStates};
 use crate::protocol::{
-    GatewayNoticeResponse, IdentityInformation, PatchError, PatchProtocol, PatchResponse,
-    PatchResult,
+    GatewayNotice, GatewayNoticeResponse, IdentityInformation, LegacyGatewayNoticeResponse,
+    PatchError, PatchProtocol, PatchRequest, PatchResponse, PatchResult,
 };
-use skrillax_stream::handshake::{ActiveSecuritySetup, PassiveSecuritySetup};
-use skrillax_stream::stream::SilkroadTcpExt;
-use std::collections::HashSet;
+use crate::region::GeoIp;
+use crate::safe_path::Root;
+use crate::server::Server;
+use crate::signing::UrlSigner;
+use crate::tenant::{UPDATE_SIZE_PLACEHOLDER, VERSION_PLACEHOLDER};
+use crate::transcript::Transcript;
+use crate::url_check::UrlCheck;
+use chrono::Utc;
+use clap::{CommandFactory, Parser};
+use env_logger::{Env, DEFAULT_WRITE_STYLE_ENV};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use skrillax_packet::{OutgoingPacket, Packet};
+use skrillax_serde::ByteSize;
+use skrillax_stream::handshake::ActiveSecuritySetup;
+use skrillax_stream::stream::{InStreamError, OutStreamError, SilkroadStreamWrite, SilkroadTcpExt};
+use skrillax_stream::InputProtocol;
+use socket2::{Domain, Socket, Type};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
 use std::fs;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
-use tokio::net::{TcpSocket, TcpStream};
-use tokio::signal;
+use std::time::{Duration, SystemTime};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::{signal, time};
 use tokio_util::sync::CancellationToken;
 use walkdir::WalkDir;
 
-#[derive(Clone)]
+/// Replaced in path templates by the checksum of the file.
+const HASH_PLACEHOLDER: &str = "{hash}";
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default)]
 struct PatchFileserver {
     ip: String,
     host: String,
+    /// The port advertised to clients, 80 by default.
+    port: u16,
     base_path: String,
+    /// Makes clients download from full URLs with this scheme, e.g. `https`,
+    /// instead of paths relative to the host.
+    scheme: Option<String>,
+    /// How paths of files are built, `{base}/{version}/{path}` by default.
+    /// `{filename}` is the name of the file without its directories, and
+    /// `{hash}` its checksum from the manifest of its version.
+    path_template: Option<String>,
+    /// Signs paths for the built-in fileserver.
+    #[serde(skip)]
+    signer: Option<UrlSigner>,
+}
+
+impl Default for PatchFileserver {
+    fn default() -> Self {
+        PatchFileserver {
+            ip: "127.0.0.1".to_string(),
+            host: "localhost".to_string(),
+            port: 80,
+            base_path: "".to_string(),
+            scheme: None,
+            path_template: None,
+            signer: None,
+        }
+    }
 }
 
 impl PatchFileserver {
@@ -32,20 +162,123 @@ impl PatchFileserver {
         &self.host
     }
 
-    pub fn base_path(&self) -> &str {
-        &self.base_path
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether paths include the checksum of the file.
+    pub fn uses_hash(&self) -> bool {
+        self.path_template
+            .as_ref()
+            .is_some_and(|template| template.contains(HASH_PLACEHOLDER))
+    }
+
+    /// The path of a file of a version, relative to the host.
+    pub fn path_of(&self, version: u16, file: &str, hash: Option<&str>) -> String {
+        let path = match &self.path_template {
+            Some(template) => template
+                .replace("{base}", &self.base_path)
+                .replace("{version}", &version.to_string())
+                .replace("{path}", file)
+                .replace("{filename}", file.rsplit('/').next().unwrap())
+                .replace(HASH_PLACEHOLDER, hash.unwrap_or_default()),
+            None => format!("{}/{}/{}", self.base_path, version, file),
+        };
+        match &self.signer {
+            Some(signer) => format!("{}{}", path, signer.sign(&path)),
+            None => path,
+        }
+    }
+
+    /// The full URL of a path relative to the host. The port is left out if
+    /// it's the default of the scheme.
+    pub fn url_of(&self, path: &str) -> String {
+        let scheme = self.scheme.as_deref().unwrap_or("http");
+        let default_port = if scheme == "https" { 443 } else { 80 };
+        let authority = if self.port == default_port {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        format!(
+            "{}://{}/{}",
+            scheme,
+            authority,
+            path.trim_start_matches('/')
+        )
+    }
+
+    /// The path of a file as sent to clients, with directories separated by
+    /// `separator`, or its full URL if a scheme is configured.
+    pub fn advertised_path(
+        &self,
+        version: u16,
+        file: &str,
+        hash: Option<&str>,
+        separator: char,
+    ) -> String {
+        let path = self.path_of(version, file, hash);
+        match self.scheme {
+            Some(_) => self.url_of(&path),
+            None => path.replace('/', &separator.to_string()),
+        }
     }
 }
 
 struct Patch {
     version: u16,
     files: Box<[PathBuf]>,
+    /// Files removed by this version, which clients updating past it no
+    /// longer get from earlier versions.
+    deleted: Box<[PathBuf]>,
+    /// The `manifest.toml` the files were listed in, if any.
+    manifest: Option<Arc<PatchManifest>>,
+    /// The `changelog.toml` describing the version, if any.
+    changelog: Option<Arc<Changelog>>,
 }
 
 struct PatchProvider {
-    patches: RwLock<Vec<Patch>>, // lets assume/ensure this is sorted according to the patch version ascending
+    patches: RwLock<Vec<Patch>>, // sorted according to the patch version ascending
     patch_dir: PathBuf,
-    server: PatchFileserver,
+    /// Where manifests are read from, the patch directory by default.
+    manifest_dir: RwLock<PathBuf>,
+    /// Where files with a checksum in the `manifest.toml` of their version
+    /// are stored, see [blob_store].
+    blob_dir: RwLock<Option<PathBuf>>,
+    /// The key versions have to be signed with, see [patch_signing].
+    public_key: RwLock<Option<PublicKey>>,
+    /// Where deltas between versions of files are stored, see [delta].
+    deltas: RwLock<Option<DeltaConfig>>,
+    server: RwLock<PatchFileserver>,
+    skipped: RwLock<BTreeSet<u16>>,
+    /// Whether versions are staged, live or retired, see [promotion].
+    states: RwLock<PatchStates>,
+    quarantined: RwLock<Vec<Quarantined>>,
+    metadata: RwLock<HashMap<(u16, PathBuf), FileMetadata>>,
+    manifests: RwLock<HashMap<u16, Arc<Manifest>>>,
+    /// Files whose checksum matched their manifest, as they were on disk at the time.
+    verified: RwLock<HashMap<(u16, PathBuf), FileMetadata>>,
+    /// Checksums computed from the files on disk, with the file as it was at the time.
+    checksums: RwLock<HashMap<(u16, PathBuf), (FileMetadata, String)>>,
+    /// Position of the next file to be checked by [PatchProvider::scrub].
+    scrub_cursor: AtomicUsize,
+}
+
+/// What is known about a file on disk, used to notice changes to it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct FileMetadata {
+    size: u32,
+    modified: Option<SystemTime>,
+}
+
+impl FileMetadata {
+    fn read(path: &Path) -> io::Result<FileMetadata> {
+        let metadata = fs::File::open(path)?.metadata()?;
+        Ok(FileMetadata {
+            size: metadata.len() as u32,
+            modified: metadata.modified().ok(),
+        })
+    }
 }
 
 struct PatchFile {
@@ -53,36 +286,800 @@ struct PatchFile {
     patch: u16,
 }
 
+/// A directory inside the patch directory that can't be served from.
+#[derive(Serialize, Clone)]
+struct Quarantined {
+    directory: String,
+    version: Option<u16>,
+    reason: String,
+}
+
+/// A file of a patch that can't be served.
+struct FileProblem {
+    version: u16,
+    file: PathBuf,
+    error: io::Error,
+}
+
+/// The result of comparing the loaded patches with the patch directory.
+struct Scan {
+    checked: usize,
+    /// Versions whose files were added, removed or modified since the last scan.
+    changed: Vec<u16>,
+    problems: Vec<FileProblem>,
+}
+
 impl PatchProvider {
     pub fn new(patch_dir: PathBuf, fileserver: PatchFileserver) -> PatchProvider {
         PatchProvider {
+            manifest_dir: RwLock::new(patch_dir.clone()),
+            blob_dir: RwLock::new(None),
+            public_key: RwLock::new(None),
+            deltas: RwLock::new(None),
             patch_dir,
             patches: RwLock::new(Vec::new()),
-            server: fileserver,
+            server: RwLock::new(fileserver),
+            skipped: RwLock::new(BTreeSet::new()),
+            states: RwLock::new(PatchStates::default()),
+            quarantined: RwLock::new(Vec::new()),
+            metadata: RwLock::new(HashMap::new()),
+            manifests: RwLock::new(HashMap::new()),
+            verified: RwLock::new(HashMap::new()),
+            checksums: RwLock::new(HashMap::new()),
+            scrub_cursor: AtomicUsize::new(0),
         }
     }
 
-    pub fn fileserver(&self) -> &PatchFileserver {
-        &self.server
+    pub fn fileserver(&self) -> PatchFileserver {
+        self.server.read().unwrap().clone()
     }
 
-    pub fn add_patch(&self, version: u16, files: Box<[PathBuf]>) {
-        self.patches.write().unwrap().push(Patch { version, files })
+    /// Replaces the fileserver advertised for these patches, e.g. after the
+    /// config was reloaded.
+    pub fn set_fileserver(&self, fileserver: PatchFileserver) {
+        *self.server.write().unwrap() = fileserver;
+    }
+
+    /// Adds the patch, keeping the patches sorted. Returns false if there
+    /// already is a patch for the version.
+    pub fn add_patch(&self, patch: Patch) -> bool {
+        let mut patches = self.patches.write().unwrap();
+        match patches.binary_search_by_key(&patch.version, |known| known.version) {
+            Ok(_) => false,
+            Err(index) => {
+                patches.insert(index, patch);
+                true
+            }
+        }
+    }
+
+    /// Starts serving a version that was added to the patch directory while
+    /// running, e.g. by an import, storing whether it's staged or live.
+    /// Returns the number of its files.
+    pub fn load_version(&self, version: u16) -> Result<usize, String> {
+        let version_dir = self.patch_dir.join(version.to_string());
+        let patch = read_patch(version, &version_dir).map_err(|err| err.to_string())?;
+        let files = patch.files.len();
+        if !self.add_patch(patch) {
+            return Err(format!("version {} is already served", version));
+        }
+        self.set_state(version, self.state_of(version))?;
+        self.quarantine_unsigned(version)?;
+        Ok(files)
     }
 
     pub fn patch_dir(&self) -> &Path {
         &self.patch_dir
     }
 
-    pub fn collect_necessary_files(&self, current: u16, target: u16) -> Vec<PatchFile> {
+    /// The files of the version, relative to its directory.
+    pub fn files_of(&self, version: u16) -> Vec<PathBuf> {
+        let patches = self.patches.read().unwrap();
+        match patches.binary_search_by_key(&version, |patch| patch.version) {
+            Ok(index) => patches[index].files.to_vec(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// The files removed by the version.
+    pub fn deleted_of(&self, version: u16) -> Vec<PathBuf> {
+        let patches = self.patches.read().unwrap();
+        match patches.binary_search_by_key(&version, |patch| patch.version) {
+            Ok(index) => patches[index].deleted.to_vec(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn has_file(&self, version: u16, file: &Path) -> bool {
+        let patches = self.patches.read().unwrap();
+        match patches.binary_search_by_key(&version, |patch| patch.version) {
+            Ok(index) => patches[index].files.iter().any(|known| known == file),
+            Err(_) => false,
+        }
+    }
+
+    pub fn manifest_dir(&self) -> PathBuf {
+        self.manifest_dir.read().unwrap().clone()
+    }
+
+    pub fn set_manifest_dir(&self, manifest_dir: PathBuf) {
+        *self.manifest_dir.write().unwrap() = manifest_dir;
+        self.manifests.write().unwrap().clear();
+    }
+
+    pub fn blob_dir(&self) -> Option<PathBuf> {
+        self.blob_dir.read().unwrap().clone()
+    }
+
+    pub fn set_blob_dir(&self, blob_dir: Option<PathBuf>) {
+        *self.blob_dir.write().unwrap() = blob_dir;
+    }
+
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.public_key.read().unwrap().clone()
+    }
+
+    pub fn set_public_key(&self, public_key: Option<PublicKey>) {
+        *self.public_key.write().unwrap() = public_key;
+    }
+
+    pub fn deltas(&self) -> Option<DeltaConfig> {
+        self.deltas.read().unwrap().clone()
+    }
+
+    pub fn set_deltas(&self, deltas: Option<DeltaConfig>) {
+        *self.deltas.write().unwrap() = deltas;
+    }
+
+    /// The delta a client on `current` can update the file with, as the
+    /// version it was made from and its path inside the delta directory.
+    pub fn delta_for(&self, file: &PatchFile, current: u16) -> Option<(u16, PathBuf)> {
+        let deltas = self.deltas()?;
+        let base = self.version_up_to(&file.file, current)?;
+        let path = deltas.path_of(base, file.patch, &file.file);
+        deltas.dir.join(&path).is_file().then_some((base, path))
+    }
+
+    /// Where the file is stored on disk.
+    pub fn local_path(&self, file: &PatchFile) -> PathBuf {
+        let (dir, relative) = self.locate(file);
+        dir.join(relative)
+    }
+
+    /// Like [Self::local_path], but fails if the file or a symlink along
+    /// the way leads outside of its patch directory or blob store.
+    pub fn resolve(&self, file: &PatchFile) -> io::Result<PathBuf> {
+        let (dir, relative) = self.locate(file);
+        Root::new(&dir)?.resolve(&relative)
+    }
+
+    fn locate(&self, file: &PatchFile) -> (PathBuf, PathBuf) {
+        let blob_dir = self.blob_dir();
+        let locate = |manifest: Option<&PatchManifest>| {
+            blob_store::locate(
+                &self.patch_dir,
+                blob_dir.as_deref(),
+                file.patch,
+                manifest,
+                &file.file,
+            )
+        };
+        self.listed(file.patch, |manifest| Some(locate(Some(manifest))))
+            .unwrap_or_else(|| locate(None))
+    }
+
+    /// Treats the version as if it was never published. Its files are
+    /// served from the neighbouring versions instead.
+    pub fn skip(&self, version: u16) {
+        self.skipped.write().unwrap().insert(version);
+    }
+
+    pub fn skipped(&self) -> Vec<u16> {
+        self.skipped.read().unwrap().iter().copied().collect()
+    }
+
+    /// Reads the states of the versions from the manifest directory, staging
+    /// versions without one if `stage_new` is set.
+    pub fn load_states(&self, stage_new: bool) -> Result<(), String> {
+        let versions = self
+            .patches
+            .read()
+            .unwrap()
+            .iter()
+            .map(|patch| patch.version)
+            .collect::<Vec<_>>();
+        let states = PatchStates::load(&self.manifest_dir(), stage_new, &versions)
+            .map_err(|err| err.to_string())?;
+        *self.states.write().unwrap() = states;
+        Ok(())
+    }
+
+    pub fn states(&self) -> PatchStates {
+        self.states.read().unwrap().clone()
+    }
+
+    pub fn set_states(&self, states: PatchStates) {
+        *self.states.write().unwrap() = states;
+    }
+
+    pub fn state_of(&self, version: u16) -> PatchState {
+        self.states.read().unwrap().state_of(version)
+    }
+
+    /// Versions of the patch directory with the state.
+    pub fn versions_in(&self, state: PatchState) -> Vec<u16> {
+        let versions = self.loaded_versions();
+        self.states.read().unwrap().filter(&versions, state)
+    }
+
+    /// Every version of the patch directory, including skipped, retired and
+    /// quarantined ones.
+    pub fn loaded_versions(&self) -> Vec<u16> {
+        self.patches
+            .read()
+            .unwrap()
+            .iter()
+            .map(|patch| patch.version)
+            .collect()
+    }
+
+    /// Removes the version from the patch directory, along with its
+    /// checksums. Its files inside the blob store are kept.
+    pub fn delete_version(&self, version: u16) -> io::Result<()> {
+        self.patches
+            .write()
+            .unwrap()
+            .retain(|patch| patch.version != version);
+        fs::remove_dir_all(self.patch_dir.join(version.to_string()))?;
+        match fs::remove_file(Manifest::path_for(&self.manifest_dir(), version)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// The changelog of the version, if it has one.
+    pub fn changelog_of(&self, version: u16) -> Option<Arc<Changelog>> {
+        let patches = self.patches.read().unwrap();
+        let index = patches
+            .binary_search_by_key(&version, |patch| patch.version)
+            .ok()?;
+        patches[index].changelog.clone()
+    }
+
+    /// Replaces the changelog of the version, in its directory as well.
+    pub fn set_changelog(&self, version: u16, mut changelog: Changelog) -> Result<(), String> {
+        changelog.published.get_or_insert_with(Utc::now);
+        let mut patches = self.patches.write().unwrap();
+        let Ok(index) = patches.binary_search_by_key(&version, |patch| patch.version) else {
+            return Err(format!("version {} doesn't exist", version));
+        };
+        changelog
+            .write(&self.patch_dir.join(version.to_string()))
+            .map_err(|err| format!("could not store the changelog: {}", err))?;
+        patches[index].changelog = Some(Arc::new(changelog));
+        Ok(())
+    }
+
+    /// Whether the files of the version were moved to cold storage.
+    pub fn is_evicted(&self, version: u16) -> bool {
+        self.listed(version, |manifest| Some(manifest.evicted()))
+            .unwrap_or(false)
+    }
+
+    /// Marks the files of the version as moved to or back from cold storage,
+    /// reading the listing of the version again.
+    pub fn set_evicted(&self, version: u16, evicted: bool) -> Result<(), String> {
+        let version_dir = self.patch_dir.join(version.to_string());
+        PatchManifest::set_evicted(&version_dir, evicted).map_err(|err| err.to_string())?;
+        let patch = read_patch(version, &version_dir).map_err(|err| err.to_string())?;
+        let mut patches = self.patches.write().unwrap();
+        match patches.binary_search_by_key(&version, |known| known.version) {
+            Ok(index) => patches[index] = patch,
+            Err(_) => return Err(format!("version {} doesn't exist", version)),
+        }
+        Ok(())
+    }
+
+    /// Changes the state of the version, e.g. to promote a staged version.
+    pub fn set_state(&self, version: u16, state: PatchState) -> Result<(), String> {
+        let exists = self
+            .patches
+            .read()
+            .unwrap()
+            .iter()
+            .any(|patch| patch.version == version);
+        if !exists {
+            return Err(format!("version {} doesn't exist", version));
+        }
+
+        self.states
+            .write()
+            .unwrap()
+            .set(version, state)
+            .map_err(|err| format!("could not store the state: {}", err))
+    }
+
+    /// Stops serving anything from the directory. A quarantined version is
+    /// treated like a skipped one.
+    pub fn quarantine(&self, quarantined: Quarantined) {
+        self.quarantined.write().unwrap().push(quarantined);
+    }
+
+    pub fn quarantined(&self) -> Vec<Quarantined> {
+        self.quarantined.read().unwrap().clone()
+    }
+
+    /// Reads the quarantined versions again, e.g. after their files were
+    /// fixed, and serves those again whose files are all available, match
+    /// their manifest and whose signature is valid. Returns the versions
+    /// released from the quarantine.
+    pub fn recheck_quarantined(&self) -> Vec<u16> {
+        let versions = self
+            .quarantined
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|quarantined| quarantined.version)
+            .collect::<BTreeSet<_>>();
+        let mut released = Vec::new();
+        for version in versions {
+            let Ok(patch) = read_patch(version, &self.patch_dir.join(version.to_string())) else {
+                continue;
+            };
+
+            // Nothing known about the broken files may be trusted anymore.
+            self.manifests.write().unwrap().remove(&version);
+            for known in [&self.metadata, &self.verified] {
+                known
+                    .write()
+                    .unwrap()
+                    .retain(|(known, _), _| *known != version);
+            }
+            self.checksums
+                .write()
+                .unwrap()
+                .retain(|(known, _), _| *known != version);
+            {
+                let mut patches = self.patches.write().unwrap();
+                match patches.binary_search_by_key(&version, |known| known.version) {
+                    Ok(index) => patches[index] = patch,
+                    Err(index) => patches.insert(index, patch),
+                }
+            }
+
+            if self.check_version(version).is_ok() {
+                self.quarantined
+                    .write()
+                    .unwrap()
+                    .retain(|quarantined| quarantined.version != Some(version));
+                released.push(version);
+            }
+        }
+        released
+    }
+
+    /// Fails unless every file of the version is available and matches its
+    /// manifest, if listed there, and the signature of the version is valid.
+    fn check_version(&self, version: u16) -> Result<(), String> {
+        if !self.is_evicted(version) {
+            let manifest = self.manifest(version).ok();
+            for file in self.files_of(version) {
+                let file = PatchFile {
+                    file,
+                    patch: version,
+                };
+                FileMetadata::read(&self.local_path(&file)).map_err(|err| err.to_string())?;
+                if manifest
+                    .as_ref()
+                    .is_some_and(|manifest| manifest.hash_of(&file.file).is_some())
+                {
+                    self.verify_hash(&file)?;
+                }
+            }
+        }
+        self.verify_signature(version)
+    }
+
+    fn excluded(&self) -> BTreeSet<u16> {
+        let mut excluded = self.skipped.read().unwrap().clone();
+        excluded.extend(self.versions_in(PatchState::Retired));
+        excluded.extend(
+            self.quarantined
+                .read()
+                .unwrap()
+                .iter()
+                .filter_map(|quarantined| quarantined.version),
+        );
+        excluded
+    }
+
+    /// All versions that can be patched to, excluding skipped, retired and
+    /// quarantined ones.
+    pub fn versions(&self) -> Vec<u16> {
+        let excluded = self.excluded();
+        self.loaded_versions()
+            .into_iter()
+            .filter(|version| !excluded.contains(version))
+            .collect()
+    }
+
+    pub fn latest_version(&self) -> Option<u16> {
+        self.versions().into_iter().max()
+    }
+
+    /// Compares the served versions with the patch directory, checking that
+    /// every file can be opened and picking up files that were added,
+    /// removed or modified on disk since the last scan.
+    pub fn scan(&self) -> Scan {
+        let excluded = self.excluded();
+        // Manifests may have been changed as well.
+        self.manifests.write().unwrap().clear();
+        let mut patches = self.patches.write().unwrap();
+        let mut metadata = self.metadata.write().unwrap();
+        let mut scan = Scan {
+            checked: 0,
+            changed: Vec::new(),
+            problems: Vec::new(),
+        };
+        for patch in patches
+            .iter_mut()
+            .filter(|patch| !excluded.contains(&patch.version))
+        {
+            let version_dir = self.patch_dir.join(patch.version.to_string());
+            let (files, deleted, manifest, changelog) =
+                match read_patch(patch.version, &version_dir) {
+                    Ok(read) => (read.files, read.deleted, read.manifest, read.changelog),
+                    Err(error) => {
+                        scan.problems.push(FileProblem {
+                            version: patch.version,
+                            file: PathBuf::new(),
+                            error,
+                        });
+                        continue;
+                    }
+                };
+
+            patch.manifest = manifest;
+            patch.changelog = changelog;
+            let mut changed = files.len() != patch.files.len() || deleted != patch.deleted;
+            patch.deleted = deleted;
+            let blob_dir = self.blob_dir();
+            // The files of evicted versions are in cold storage instead.
+            let evicted = patch
+                .manifest
+                .as_ref()
+                .is_some_and(|manifest| manifest.evicted());
+            for file in files.iter().filter(|_| !evicted) {
+                scan.checked += 1;
+                let (dir, relative) = blob_store::locate(
+                    &self.patch_dir,
+                    blob_dir.as_deref(),
+                    patch.version,
+                    patch.manifest.as_deref(),
+                    file,
+                );
+                match FileMetadata::read(&dir.join(relative)) {
+                    Ok(current) => {
+                        let previous = metadata.insert((patch.version, file.clone()), current);
+                        changed |= previous != Some(current);
+                    }
+                    Err(error) => scan.problems.push(FileProblem {
+                        version: patch.version,
+                        file: file.clone(),
+                        error,
+                    }),
+                }
+            }
+
+            if changed {
+                metadata
+                    .retain(|(version, file), _| *version != patch.version || files.contains(file));
+                self.checksums
+                    .write()
+                    .unwrap()
+                    .retain(|(version, file), _| *version != patch.version || files.contains(file));
+                patch.files = files;
+                scan.changed.push(patch.version);
+            }
+        }
+        scan
+    }
+
+    /// The checksum of the file in the manifest of its version.
+    pub fn hash_of(&self, file: &PatchFile) -> Option<String> {
+        let manifest = self.manifest(file.patch).ok()?;
+        manifest.hash_of(&file.file).map(str::to_string)
+    }
+
+    /// Compares the checksum of the file with the one stored in the manifest
+    /// of its version. Files are only hashed again once they changed on disk.
+    pub fn verify_hash(&self, file: &PatchFile) -> Result<(), String> {
+        if self.is_evicted(file.patch) {
+            return Ok(());
+        }
+
+        let key = (file.patch, file.file.clone());
+        let path = self.local_path(file);
+        let metadata = FileMetadata::read(&path).map_err(|err| err.to_string())?;
+        if self.verified.read().unwrap().get(&key) == Some(&metadata) {
+            return Ok(());
+        }
+
+        let manifest = self
+            .manifest(file.patch)
+            .map_err(|err| format!("manifest is unavailable: {}", err))?;
+        let expected = manifest
+            .hash_of(&file.file)
+            .ok_or_else(|| "file is missing from the manifest".to_string())?;
+        let actual = hash::sha256_of(&path).map_err(|err| err.to_string())?;
+        if actual != expected {
+            return Err(format!(
+                "checksum {} doesn't match {} of the manifest",
+                actual, expected
+            ));
+        }
+
+        self.verified.write().unwrap().insert(key, metadata);
+        Ok(())
+    }
+
+    /// The SHA-256 checksums of the files of the version, computed from the
+    /// files on disk. Files are only hashed again once they changed.
+    pub fn checksums(&self, version: u16) -> io::Result<BTreeMap<PathBuf, String>> {
+        self.files_of(version)
+            .into_iter()
+            .map(|file| {
+                let file = PatchFile {
+                    file,
+                    patch: version,
+                };
+                let checksum = self.checksum_of(&file)?;
+                Ok((file.file, checksum))
+            })
+            .collect()
+    }
+
+    fn checksum_of(&self, file: &PatchFile) -> io::Result<String> {
+        if self.is_evicted(file.patch) {
+            return self
+                .listed(file.patch, |manifest| {
+                    manifest.get(&file.file)?.sha256.clone()
+                })
+                .ok_or_else(|| io::Error::other("file was evicted without a checksum"));
+        }
+
+        let key = (file.patch, file.file.clone());
+        let path = self.local_path(file);
+        let metadata = FileMetadata::read(&path)?;
+        if let Some((known, checksum)) = self.checksums.read().unwrap().get(&key) {
+            if *known == metadata {
+                return Ok(checksum.clone());
+            }
+        }
+
+        let checksum = hash::sha256_of(&path)?;
+        self.checksums
+            .write()
+            .unwrap()
+            .insert(key, (metadata, checksum.clone()));
+        Ok(checksum)
+    }
+
+    /// Checks the signature of the version against the public key. Every
+    /// version is accepted if there is no public key.
+    pub fn verify_signature(&self, version: u16) -> Result<(), String> {
+        let Some(public_key) = self.public_key() else {
+            return Ok(());
+        };
+
+        let checksums = self.checksums(version).map_err(|err| err.to_string())?;
+        public_key.verify(
+            &self.patch_dir.join(version.to_string()),
+            version,
+            &checksums,
+            &self.deleted_of(version),
+        )
+    }
+
+    /// Checks the signatures of the versions, see [Self::verify_signature].
+    pub fn verify_signatures(&self, versions: &[u16]) -> Vec<FileProblem> {
+        versions
+            .iter()
+            .filter_map(|&version| {
+                let error = self.verify_signature(version).err()?;
+                Some(FileProblem {
+                    version,
+                    file: PathBuf::from(patch_signing::FILE_NAME),
+                    error: io::Error::new(io::ErrorKind::InvalidData, error),
+                })
+            })
+            .collect()
+    }
+
+    /// Quarantines the version unless its signature verifies, for versions
+    /// added while running.
+    pub fn quarantine_unsigned(&self, version: u16) -> Result<(), String> {
+        self.verify_signature(version).inspect_err(|err| {
+            self.quarantine(Quarantined {
+                directory: version.to_string(),
+                version: Some(version),
+                reason: format!("has an invalid signature: {}", err),
+            })
+        })
+    }
+
+    /// Hashes the next `count` files, starting where the previous call left
+    /// off, and compares them with their manifest. Unlike [Self::scan], this
+    /// notices corruption that doesn't change the size or modification time.
+    /// Files of versions without a manifest are skipped.
+    pub fn scrub(&self, count: usize) -> Vec<FileProblem> {
+        let files = {
+            let excluded = self.excluded();
+            self.patches
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|patch| !excluded.contains(&patch.version))
+                .filter(|patch| {
+                    !patch
+                        .manifest
+                        .as_ref()
+                        .is_some_and(|manifest| manifest.evicted())
+                })
+                .flat_map(|patch| patch.files.iter().map(|file| (patch.version, file.clone())))
+                .collect::<Vec<_>>()
+        };
+        if files.is_empty() {
+            return Vec::new();
+        }
+
+        let start = self.scrub_cursor.load(Ordering::Relaxed) % files.len();
+        let window = count.min(files.len());
+        self.scrub_cursor
+            .store((start + window) % files.len(), Ordering::Relaxed);
+
+        let mut problems = Vec::new();
+        for (version, file) in files.into_iter().cycle().skip(start).take(window) {
+            let Ok(manifest) = self.manifest(version) else {
+                continue;
+            };
+            let Some(expected) = manifest.hash_of(&file) else {
+                continue;
+            };
+
+            let path = self.local_path(&PatchFile {
+                file: file.clone(),
+                patch: version,
+            });
+            let error = match hash::sha256_of(&path) {
+                Ok(actual) if actual == expected => continue,
+                Ok(actual) => io::Error::other(format!(
+                    "checksum {} doesn't match {} of the manifest",
+                    actual, expected
+                )),
+                Err(error) => error,
+            };
+            self.verified
+                .write()
+                .unwrap()
+                .remove(&(version, file.clone()));
+            problems.push(FileProblem {
+                version,
+                file,
+                error,
+            });
+        }
+        problems
+    }
+
+    fn manifest(&self, version: u16) -> io::Result<Arc<Manifest>> {
+        if let Some(manifest) = self.manifests.read().unwrap().get(&version) {
+            return Ok(Arc::clone(manifest));
+        }
+
+        let manifest = match Manifest::load(&Manifest::path_for(
+            &self.manifest_dir.read().unwrap(),
+            version,
+        )) {
+            Ok(manifest) => Arc::new(manifest),
+            // Falls back to the checksums in the `manifest.toml` of the version.
+            Err(err) => Arc::new(self.listed(version, PatchManifest::checksums).ok_or(err)?),
+        };
+        self.manifests
+            .write()
+            .unwrap()
+            .insert(version, Arc::clone(&manifest));
+        Ok(manifest)
+    }
+
+    /// The size of the file, as of the last scan or as listed in the
+    /// `manifest.toml` of its version.
+    pub fn file_size(&self, file: &PatchFile) -> u32 {
+        let metadata = self.metadata.read().unwrap();
+        match metadata.get(&(file.patch, file.file.clone())) {
+            Some(metadata) => metadata.size,
+            None => self
+                .listed(file.patch, |manifest| {
+                    manifest.get(&file.file).map(|listed| listed.size)
+                })
+                .unwrap_or_else(|| fs::metadata(self.local_path(file)).unwrap().len() as u32),
+        }
+    }
+
+    /// Whether the client stores the file in a pk2 archive, by the directory
+    /// it's in, unless the `manifest.toml` of its version says otherwise.
+    pub fn in_pk2(&self, file: &PatchFile) -> bool {
+        self.listed(file.patch, |manifest| manifest.get(&file.file)?.in_pk2)
+            .unwrap_or_else(|| pk2::archive_of(&file.file).is_some())
+    }
+
+    fn listed<T>(&self, version: u16, get: impl FnOnce(&PatchManifest) -> Option<T>) -> Option<T> {
+        let patches = self.patches.read().unwrap();
+        let index = patches
+            .binary_search_by_key(&version, |patch| patch.version)
+            .ok()?;
+        get(patches[index].manifest.as_ref()?)
+    }
+
+    /// The summed size of all files a client on `current` gets to reach `target`.
+    pub fn plan_size(&self, current: u16, target: u16) -> u64 {
+        self.collect_necessary_files(current, target)
+            .iter()
+            .map(|file| u64::from(self.file_size(file)))
+            .sum()
+    }
+
+    /// The size of the update to `target` for clients on the version before it.
+    pub fn update_size(&self, target: u16) -> u64 {
+        let previous = self
+            .versions()
+            .into_iter()
+            .filter(|version| *version < target)
+            .max()
+            .unwrap_or(0);
+        self.plan_size(previous, target)
+    }
+
+    /// The latest version up to `version` containing the file, unless it was
+    /// deleted since. Skipped and quarantined versions are left out.
+    pub fn version_up_to(&self, file: &Path, version: u16) -> Option<u16> {
+        let excluded = self.excluded();
         let patches = self.patches.read().unwrap();
+        let patches = patches
+            .iter()
+            .filter(|patch| !excluded.contains(&patch.version))
+            .collect::<Vec<&Patch>>();
+        get_latest_version_in_up_to(file, &patches, version)
+    }
+
+    pub fn collect_necessary_files(&self, current: u16, target: u16) -> Vec<PatchFile> {
+        self.collect_files_hiding(current, target, &BTreeSet::new())
+    }
+
+    /// Collects the necessary files as if the `hidden` versions didn't exist.
+    pub fn collect_files_hiding(
+        &self,
+        current: u16,
+        target: u16,
+        hidden: &BTreeSet<u16>,
+    ) -> Vec<PatchFile> {
+        let mut excluded = self.excluded();
+        excluded.extend(hidden);
+        let all_patches = self.patches.read().unwrap();
         if current > target {
-            let files_to_revert = patches
+            // Files of excluded versions still need to be reverted, as the
+            // client may have received them before the version was excluded.
+            // Deleted files are restored from before their deletion.
+            let files_to_revert = all_patches
                 .iter()
                 .filter(|patch| patch.version > target && patch.version <= current)
-                .flat_map(|patch| patch.files.iter().cloned())
-                .collect::<HashSet<PathBuf>>();
+                .flat_map(|patch| patch.files.iter().chain(&patch.deleted).cloned())
+                .collect::<BTreeSet<PathBuf>>();
 
+            let patches = all_patches
+                .iter()
+                .filter(|patch| !excluded.contains(&patch.version))
+                .collect::<Vec<&Patch>>();
             files_to_revert
                 .into_iter()
                 .filter_map(|file| {
@@ -93,16 +1090,17 @@ impl PatchProvider {
                 })
                 .collect()
         } else {
-            let applicable_versions = patches
+            let applicable_versions = all_patches
                 .iter()
                 .filter(|patch| patch.version > current && patch.version <= target)
+                .filter(|patch| !excluded.contains(&patch.version))
                 .collect::<Vec<&Patch>>();
 
             // we need to track which files have been updated in which version (and which latest version of it)
             let all_files = applicable_versions
                 .iter()
                 .flat_map(|patch| patch.files.iter().cloned())
-                .collect::<HashSet<PathBuf>>();
+                .collect::<BTreeSet<PathBuf>>();
 
             all_files
                 .into_iter()
@@ -117,63 +1115,111 @@ impl PatchProvider {
     }
 }
 
+/// The latest version of the file, unless it was deleted since.
 fn get_latest_version_in(file: &Path, patches: &[&Patch]) -> Option<u16> {
     for patch in patches.iter().rev() {
         if patch.files.iter().any(|f| f == file) {
             return Some(patch.version);
         }
+        if patch.deleted.iter().any(|f| f == file) {
+            return None;
+        }
     }
 
     None
 }
 
-fn get_latest_version_in_up_to(file: &Path, patches: &[Patch], min_version: u16) -> Option<u16> {
-    for patch in patches.iter().rev() {
-        if patch.version <= min_version && patch.files.iter().any(|f| f.as_path() == file) {
-            return Some(patch.version);
-        }
-    }
+fn get_latest_version_in_up_to(file: &Path, patches: &[&Patch], min_version: u16) -> Option<u16> {
+    let patches = patches
+        .iter()
+        .copied()
+        .filter(|patch| patch.version <= min_version)
+        .collect::<Vec<_>>();
+    get_latest_version_in(file, &patches)
+}
 
-    None
+/// Settings shared by the connections of all listeners.
+struct ClientSettings {
+    maintenance: Arc<Maintenance>,
+    response_size: ResponseSizeConfig,
+    transcripts: Option<PathBuf>,
+    events: Events,
+    compatibility: CompatibilityConfig,
+    notice_time: NoticeTimeConfig,
+    shard_list: ShardListConfig,
+    login_rejection: LoginRejectionConfig,
+    gateway_proxy: Option<GatewayProxyConfig>,
+    unknown_opcodes: UnknownOpcodes,
+    opcodes: OpcodeMap,
 }
 
+/// The delay before restarting a failed listener, doubled on every
+/// consecutive failure.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Consecutive failures after which a listener is reported as degraded.
+const DEGRADED_AFTER_FAILURES: u32 = 3;
+
+/// A listener running this long without failing is considered recovered.
+const RECOVERED_AFTER: Duration = Duration::from_secs(300);
+
 struct SocketCoordinator {
-    patch_provider: Arc<PatchProvider>,
+    settings: Arc<ClientSettings>,
+    alerts: Arc<Alerts>,
     cancel_token: CancellationToken,
 }
 
 impl SocketCoordinator {
-    pub fn new(patch_provider: Arc<PatchProvider>) -> SocketCoordinator {
+    pub fn new(settings: ClientSettings, alerts: Arc<Alerts>) -> SocketCoordinator {
         SocketCoordinator {
-            patch_provider,
+            settings: Arc::new(settings),
+            alerts,
             cancel_token: CancellationToken::new(),
         }
     }
 
-    pub fn accept_patch(&mut self, patch: u16) {
-        let result = TcpSocket::new_v4().unwrap();
-        let port = 32000 + patch;
-        result
-            .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port))
-            .unwrap();
-        let provider = Arc::clone(&self.patch_provider);
-        let cancel_token = self.cancel_token.clone();
-        tokio::spawn(async move {
-            let listener = result.listen(5).unwrap();
-
-            // TODO: try to recreate the socket on error
-            while let Some(Ok(accepted)) = tokio::select! {
-                res = listener.accept() => Some(res),
-                _ = cancel_token.cancelled() => None,
-            } {
-                let (stream, _) = accepted;
-                let patch_provider = Arc::clone(&provider);
-                let child_token = cancel_token.child_token();
-                tokio::spawn(async move {
-                    handle_client(stream, patch, patch_provider, child_token).await;
-                });
+    pub fn accept(&self, listener: Listener, cancel_token: CancellationToken) -> io::Result<()> {
+        for route in &listener.routes {
+            match route.target {
+                Some(target) => info!(
+                    "Serving version {} of channel '{}' of tenant '{}' on port {}",
+                    target,
+                    route.channel.name(),
+                    listener.tenant.name(),
+                    listener.port
+                ),
+                None => info!(
+                    "Serving channel '{}' of tenant '{}' on port {}",
+                    route.channel.name(),
+                    listener.tenant.name(),
+                    listener.port
+                ),
             }
-        });
+        }
+        let socket = open_socket(listener.address())?;
+        let listener = Arc::new(listener);
+        if let Some(config) = self.alerts.outcomes() {
+            tokio::spawn(alert::watch_outcomes(
+                Arc::clone(&self.alerts),
+                Arc::clone(&listener),
+                config,
+                cancel_token.child_token(),
+            ));
+        }
+
+        tokio::spawn(supervise(
+            socket,
+            listener,
+            Arc::clone(&self.settings),
+            Arc::clone(&self.alerts),
+            cancel_token,
+        ));
+        Ok(())
+    }
+
+    pub fn child_token(&self) -> CancellationToken {
+        self.cancel_token.child_token()
     }
 
     pub fn shutdown(&self) {
@@ -181,144 +1227,1492 @@ impl SocketCoordinator {
     }
 }
 
+/// Accepts connections on the listener's socket, restarting it with a
+/// growing delay if it stops. A listener only stops on its own if something
+/// went wrong, which would otherwise go unnoticed until clients can't connect
+/// anymore.
+async fn supervise(
+    socket: TcpListener,
+    listener: Arc<Listener>,
+    settings: Arc<ClientSettings>,
+    alerts: Arc<Alerts>,
+    cancel_token: CancellationToken,
+) {
+    let mut socket = Some(socket);
+    let mut failures = 0;
+    loop {
+        let reason = match socket
+            .take()
+            .map_or_else(|| open_socket(listener.address()), Ok)
+        {
+            Ok(socket) => {
+                let mut accept_loop = tokio::spawn(accept_connections(
+                    socket,
+                    Arc::clone(&listener),
+                    Arc::clone(&settings),
+                    cancel_token.clone(),
+                ));
+                let result = tokio::select! {
+                    result = &mut accept_loop => result,
+                    _ = time::sleep(RECOVERED_AFTER) => {
+                        failures = 0;
+                        listener.metrics.set_degraded(false);
+                        accept_loop.await
+                    }
+                };
+                match result {
+                    Ok(Ok(())) => return,
+                    Ok(Err(err)) => format!("could not accept connection: {}", err),
+                    Err(err) => format!("accept loop panicked: {}", err),
+                }
+            }
+            Err(err) => format!("could not listen again: {}", err),
+        };
+
+        failures += 1;
+        listener.metrics.listener_failed();
+        if failures >= DEGRADED_AFTER_FAILURES {
+            listener.metrics.set_degraded(true);
+        }
+        alerts
+            .listener_failed(listener.tenant.name(), listener.port, &reason)
+            .await;
+
+        let backoff = RESTART_BACKOFF
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_RESTART_BACKOFF);
+        info!(
+            "Restarting listener on port {} of tenant '{}' in {:?}",
+            listener.port,
+            listener.tenant.name(),
+            backoff
+        );
+        tokio::select! {
+            _ = time::sleep(backoff) => {},
+            _ = cancel_token.cancelled() => return,
+        }
+        listener.metrics.listener_restarted();
+    }
+}
+
+async fn accept_connections(
+    socket: TcpListener,
+    listener: Arc<Listener>,
+    settings: Arc<ClientSettings>,
+    cancel_token: CancellationToken,
+) -> io::Result<()> {
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = socket.accept() => res?,
+            _ = cancel_token.cancelled() => return Ok(()),
+        };
+        // Dual-stack sockets see IPv4 clients as mapped IPv6 addresses, which
+        // networks in the config wouldn't match.
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+        let Some(permits) = listener.try_admit() else {
+            debug!(
+                "Dropping connection from {} on port {} due to connection limits",
+                peer, listener.port
+            );
+            listener.metrics.connection_limited();
+            continue;
+        };
+
+        let listener = Arc::clone(&listener);
+        let settings = Arc::clone(&settings);
+        let child_token = cancel_token.child_token();
+        tokio::spawn(async move {
+            listener.metrics.connection_opened();
+            let transfer =
+                handle_client(stream, peer, Arc::clone(&listener), settings, child_token).await;
+            listener.metrics.connection_closed(&transfer);
+            drop(permits);
+        });
+    }
+}
+
+/// Opens a listening socket. The unspecified IPv6 address accepts IPv4
+/// clients as well, independent of the default of the system.
+fn open_socket(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(5)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// The sending side of a client connection.
+struct Connection<'a> {
+    peer: SocketAddr,
+    listener: &'a Listener,
+    settings: &'a ClientSettings,
+    writer: SilkroadStreamWrite<OwnedWriteHalf>,
+    transcript: Transcript,
+    transfer: Transfer,
+}
+
+impl Connection<'_> {
+    /// Sends the response to the client, recording it in the dump, the
+    /// transcript, the transfer and the events. Fails if the client is gone.
+    async fn send<P>(&mut self, response: P, outcome: Option<Outcome>) -> Result<(), OutStreamError>
+    where
+        P: Packet + skrillax_serde::Serialize + ByteSize + Annotate + Debug + Into<OutgoingPacket>,
+    {
+        dump::dump(self.peer, Direction::Outbound, &response);
+        self.transcript.record(Direction::Outbound, &response);
+        self.transfer.sent(response.byte_size());
+        let packet = self.settings.opcodes.outbound(response);
+        if let Err(err) = self.writer.write(packet).await {
+            debug!("Could not send {} to {}: {}", P::NAME, self.peer, err);
+            return Err(err);
+        }
+        self.settings.events.emit(
+            self.listener,
+            self.peer,
+            Event::ResponseSent {
+                packet: P::NAME,
+                outcome,
+            },
+        );
+        Ok(())
+    }
+}
+
 async fn handle_client(
     client: TcpStream,
-    target_version: u16,
-    patch_provider: Arc<PatchProvider>,
+    peer: SocketAddr,
+    listener: Arc<Listener>,
+    settings: Arc<ClientSettings>,
     child_token: CancellationToken,
-) {
+) -> Transfer {
+    let mut identity: Option<String> = None;
+    // The channel and version the client was last sent to, whose changelog
+    // it is shown.
+    let mut patched: Option<(Arc<Channel>, u16)> = None;
+    let transcript = Transcript::open(settings.transcripts.as_deref(), peer);
+    let events = &settings.events;
     let (mut reader, mut writer) = client.into_silkroad_stream();
-    ActiveSecuritySetup::handle(&mut reader, &mut writer)
-        .await
-        .unwrap();
+    // Clients dropping the connection mid-handshake end up here as well.
+    if let Err(err) = ActiveSecuritySetup::handle(&mut reader, &mut writer).await {
+        debug!(
+            "Handshake with {} on port {} failed: {}",
+            peer, listener.port, err
+        );
+        return Transfer::start();
+    }
+    events.emit(&listener, peer, Event::HandshakeCompleted);
+    let mut connection = Connection {
+        peer,
+        listener: &listener,
+        settings: &settings,
+        writer,
+        transcript,
+        transfer: Transfer::start(),
+    };
+    let mut proxy = settings.gateway_proxy.as_ref().map(GatewayProxy::new);
 
-    while let Some(Ok(packet)) = tokio::select! {
-        p = reader.next_packet::<PatchProtocol>() => Some(p),
-        _ = child_token.cancelled() => None
-    } {
+    loop {
+        let packet = tokio::select! {
+            p = reader.next() => p,
+            forwarded = gateway_proxy::next(proxy.as_mut()) => {
+                // The gateway closing the connection ends it for the client.
+                let Some((opcode, data)) = forwarded else { break };
+                dump::dump_forwarded(peer, Direction::Outbound, opcode, data.len());
+                connection.transfer.sent(data.len());
+                let packet = proxy.as_ref().unwrap().packet(opcode, data);
+                if let Err(err) = connection.writer.write(packet).await {
+                    debug!("Could not forward packet of the gateway to {}: {}", peer, err);
+                    break;
+                }
+                continue;
+            }
+            _ = child_token.cancelled() => break,
+        };
+        let Ok(packet) = packet else { break };
+        let (client_opcode, data) = packet.consume();
+        let opcode = settings.opcodes.inbound(client_opcode);
+        if let Some(proxy) = proxy.as_mut().filter(|proxy| proxy.forwards(opcode)) {
+            dump::dump_forwarded(peer, Direction::Inbound, client_opcode, data.len());
+            connection.transfer.received();
+            // The gateway is meant for the same clients, so it gets their
+            // opcodes as they are.
+            if let Err(err) = proxy.forward(client_opcode, data).await {
+                warn!(
+                    "Could not forward packet of {} to the gateway: {}",
+                    peer, err
+                );
+                break;
+            }
+            continue;
+        }
+        let packet = if opcode == PatchRequest::ID {
+            let format = listener.request_format(&settings.compatibility);
+            PatchRequest::decode(&data, format)
+                .map(|request| Box::new(PatchProtocol::PatchRequest(request)))
+        } else {
+            PatchProtocol::create_from(opcode, &data).map(|(_, packet)| packet)
+        };
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(InStreamError::UnmatchedOpcode(_)) => {
+                warn!(
+                    "Unknown opcode 0x{:04X} from {} on port {}: {}",
+                    client_opcode,
+                    peer,
+                    listener.port,
+                    dump::hex(&data)
+                );
+                listener.metrics.unknown_packet();
+                connection.transfer.received();
+                match settings.unknown_opcodes {
+                    UnknownOpcodes::Disconnect => break,
+                    UnknownOpcodes::Ignore => continue,
+                }
+            }
+            Err(_) => break,
+        };
+        dump::dump_inbound(peer, &packet);
+        connection.transcript.record_inbound(&packet);
+        connection.transfer.received();
         match *packet {
             PatchProtocol::KeepAlive(_) => {}
             PatchProtocol::PatchRequest(request) => {
                 let current_version = request.version;
-                let result = if current_version == target_version.into() {
-                    PatchResult::UpToDate { unknown: 0 }
+                events.emit(
+                    &listener,
+                    peer,
+                    Event::RequestReceived {
+                        module: &request.module,
+                        version: current_version,
+                    },
+                );
+                let mut modules = vec![request.module.as_str()];
+                modules.extend(identity.as_deref());
+                let resolved = listener
+                    .route(&modules, request.content)
+                    .and_then(|route| route.resolve(current_version))
+                    .and_then(|route| {
+                        // Only happens if all versions of the channel got skipped.
+                        let target = route.target().ok_or(Rejection::Offline)?;
+                        Ok((route, target))
+                    });
+                let (route, default_target) = match resolved {
+                    Ok(resolved) => resolved,
+                    Err(rejection) => {
+                        listener
+                            .metrics
+                            .record(peer, current_version, Outcome::Rejected);
+                        let response = PatchResponse {
+                            result: PatchResult::Problem {
+                                error: rejection.into(),
+                            },
+                        };
+                        if connection
+                            .send(response, Some(Outcome::Rejected))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                let channel = &route.channel;
+                let patch_provider = channel.provider();
+                let client = ClientInfo {
+                    address: peer.ip(),
+                    port: listener.port,
+                    module: &request.module,
+                };
+                let target = channel.target_for(&client, default_target);
+                let target_version = target.version;
+                patched = Some((Arc::clone(channel), target_version));
+                let result = if listener.is_offline(&settings.maintenance) {
+                    PatchResult::Problem {
+                        error: PatchError::Offline,
+                    }
+                } else if current_version == u32::from(target_version) {
+                    PatchResult::UpToDate {
+                        unknown: settings.compatibility.up_to_date_byte,
+                    }
+                } else if !channel.is_downgrade_permitted(current_version as u16, target_version) {
+                    let downgrades = channel.downgrades();
+                    PatchResult::Problem {
+                        error: if downgrades.enabled {
+                            PatchError::InvalidVersion
+                        } else {
+                            downgrades.rejection.into()
+                        },
+                    }
                 } else {
-                    let patches = patch_provider
-                        .collect_necessary_files(current_version as u16, target_version);
+                    let patches = patch_provider.collect_files_hiding(
+                        current_version as u16,
+                        target_version,
+                        &channel.hidden_versions(&client),
+                    );
+                    if let Some(cold_storage) = channel
+                        .cold_storage()
+                        .filter(|cold_storage| cold_storage.rehydrates())
+                    {
+                        let evicted = patches
+                            .iter()
+                            .map(|file| file.patch)
+                            .filter(|version| patch_provider.is_evicted(*version))
+                            .collect::<BTreeSet<_>>();
+                        for version in evicted {
+                            info!(
+                                "Rehydrating version {} of channel '{}' from cold storage",
+                                version,
+                                channel.name()
+                            );
+                            if let Err(err) = cold_storage.rehydrate(&patch_provider, version).await
+                            {
+                                error!(
+                                    "Could not rehydrate version {} of channel '{}': {}",
+                                    version,
+                                    channel.name(),
+                                    err
+                                );
+                            }
+                        }
+                    }
+                    let corrupted = channel.verifies_hashes()
+                        && !patches.iter().all(|file| {
+                            let Err(problem) = patch_provider.verify_hash(file) else {
+                                return true;
+                            };
+                            error!(
+                                "Not serving file '{}' of version {} in channel '{}': {}",
+                                file.file.display(),
+                                file.patch,
+                                channel.name(),
+                                problem
+                            );
+                            false
+                        });
 
-                    let fileserver = patch_provider.fileserver();
+                    if corrupted {
+                        PatchResult::Problem {
+                            error: PatchError::Offline,
+                        }
+                    } else {
+                        let versions = patches.iter().map(|file| file.patch).collect();
+                        let fileservers =
+                            listener.fileservers_for(channel, &patch_provider, &versions);
+                        let fileserver = match (channel.url_check(), patches.first()) {
+                            (Some(url_check), Some(file)) => {
+                                pick_fileserver(url_check, &fileservers, &patch_provider, file)
+                                    .await
+                            }
+                            _ => &fileservers[0],
+                        };
+                        // Only one fileserver can be advertised, so files of
+                        // versions hosted elsewhere are sent as full URLs,
+                        // unless all files are hosted there.
+                        let hosts = patches
+                            .iter()
+                            .map(|file| channel.fileserver_of_version(file.patch))
+                            .collect::<Vec<_>>();
+                        let shared_host = hosts
+                            .first()
+                            .copied()
+                            .flatten()
+                            .filter(|first| hosts.iter().all(|host| *host == Some(*first)));
+                        let fileserver = shared_host.unwrap_or(fileserver);
+                        let file_ids = assign_file_ids(&patches);
+                        let patch_files = patches
+                            .into_iter()
+                            .zip(file_ids)
+                            .zip(hosts)
+                            .map(|((file, file_id), host)| {
+                                let in_pk2 = patch_provider.in_pk2(&file);
+                                let filename = PathBuf::from(&file.file);
+                                let filename =
+                                    filename.file_name().unwrap().to_str().unwrap().to_string();
+                                let size = patch_provider.file_size(&file);
+                                let host = host.filter(|_| shared_host.is_none());
+                                let hash =
+                                    hash_for(host.unwrap_or(fileserver), &patch_provider, &file);
+                                let path = file.file.to_str().unwrap();
+                                let file_path = match host {
+                                    Some(host) => host.url_of(&host.path_of(
+                                        file.patch,
+                                        path,
+                                        hash.as_deref(),
+                                    )),
+                                    None => fileserver.advertised_path(
+                                        file.patch,
+                                        path,
+                                        hash.as_deref(),
+                                        settings.compatibility.path_separator,
+                                    ),
+                                };
+                                protocol::PatchFile {
+                                    file_id,
+                                    filename,
+                                    file_path,
+                                    size,
+                                    in_pk2,
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        let size = patch_files.iter().map(|file| u64::from(file.size)).sum();
+                        debug!(
+                            "Planned {} files ({}) from version {} to {} in channel '{}' for {}",
+                            patch_files.len(),
+                            format_size(size),
+                            current_version,
+                            channel.display_version(target_version),
+                            channel.name(),
+                            peer
+                        );
+                        listener.metrics.plan_computed(size);
+                        events.emit(
+                            &listener,
+                            peer,
+                            Event::PlanComputed {
+                                channel: channel.name(),
+                                version: current_version,
+                                target: target_version,
+                                files: patch_files.len(),
+                                size,
+                            },
+                        );
 
-                    PatchResult::Problem {
-                        error: PatchError::Update {
-                            server_ip: fileserver.ip().to_string(),
-                            server_port: 80,
-                            current_version: target_version.into(),
-                            patch_files: patches
-                                .into_iter()
-                                .enumerate()
-                                .map(|(index, file)| {
-                                    let in_pk2 = file.file.parent().is_some();
-                                    let filename = PathBuf::from(&file.file);
-                                    let filename =
-                                        filename.file_name().unwrap().to_str().unwrap().to_string();
-                                    let size = get_filesize_of(patch_provider.patch_dir(), &file);
-                                    protocol::PatchFile {
-                                        file_id: index as u32,
-                                        filename,
-                                        file_path: format!(
-                                            "{}/{}/{}",
-                                            fileserver.base_path(),
-                                            file.patch,
-                                            file.file.to_str().unwrap()
-                                        ),
-                                        size,
-                                        in_pk2,
-                                    }
-                                })
-                                .collect(),
-                            http_server: fileserver.host().to_string(),
-                        },
+                        PatchResult::Problem {
+                            error: PatchError::Update {
+                                server_ip: fileserver.ip().to_string(),
+                                server_port: fileserver.port(),
+                                current_version: target_version.into(),
+                                patch_files,
+                                http_server: fileserver.host().to_string(),
+                            },
+                        }
                     }
                 };
 
-                writer.write_packet(PatchResponse { result }).await.unwrap()
+                let result = limit_response_size(result, settings.response_size, peer);
+                let outcome = Outcome::of(&result);
+                listener.metrics.record(peer, current_version, outcome);
+                if let TargetReason::Experiment(variant) = target.reason {
+                    if let Some(experiment) = channel.experiment() {
+                        experiment.record(variant, outcome);
+                    }
+                }
+
+                if let Some(latency) = listener.tenant.latency() {
+                    time::sleep(latency.sample()).await;
+                }
+                let response = PatchResponse { result };
+                if connection.send(response, Some(outcome)).await.is_err() {
+                    break;
+                }
+            }
+            PatchProtocol::IdentityInformation(information) => {
+                if let Some(proxy) = &mut proxy {
+                    proxy.identify(&information);
+                }
+                identity = Some(information.module_name);
+                let response = IdentityInformation {
+                    module_name: listener.module_name(&settings.compatibility).to_string(),
+                    locality: listener.locality(&settings.compatibility),
+                };
+                if connection.send(response, None).await.is_err() {
+                    break;
+                }
+            }
+            PatchProtocol::LoginRequest(_) => {
+                debug!("Rejecting login of {} on port {}", peer, listener.port);
+                let response = settings.login_rejection.to_response();
+                if connection.send(response, None).await.is_err() {
+                    break;
+                }
+            }
+            PatchProtocol::ShardListRequest(_) => {
+                let response = settings.shard_list.to_response();
+                if connection.send(response, None).await.is_err() {
+                    break;
+                }
             }
-            PatchProtocol::IdentityInformation(_) => writer
-                .write_packet(IdentityInformation {
-                    module_name: "GatewayServer".to_string(),
-                    locality: 0x12,
-                })
-                .await
-                .unwrap(),
             PatchProtocol::GatewayNoticeRequest(_) => {
-                writer
-                    .write_packet(GatewayNoticeResponse { notices: vec![] })
-                    .await
-                    .unwrap();
+                let notices = settings
+                    .maintenance
+                    .current()
+                    .filter(|_| listener.overrides.maintenance.is_none())
+                    .and_then(|active| {
+                        active.window.notice.as_ref().map(|notice| GatewayNotice {
+                            subject: notice.subject.clone(),
+                            article: notice.article.clone(),
+                            published: active.since,
+                        })
+                    })
+                    .into_iter()
+                    .chain(changelog_notices(
+                        &listener,
+                        patched.as_ref(),
+                        identity.as_deref(),
+                    ))
+                    .chain(
+                        listener
+                            .notices()
+                            .into_iter()
+                            .map(|notice| notice.to_gateway_notice()),
+                    )
+                    .map(|notice| fill_placeholders(notice, &listener))
+                    .map(|mut notice| {
+                        notice.published = settings.notice_time.localize(notice.published);
+                        notice
+                    })
+                    .collect::<Vec<_>>();
+                let sent = match settings.compatibility.notice_format {
+                    NoticeFormat::Standard => {
+                        let response = GatewayNoticeResponse { notices };
+                        connection.send(response, None).await
+                    }
+                    NoticeFormat::Legacy => {
+                        let response = LegacyGatewayNoticeResponse {
+                            notices: notices.into_iter().map(Into::into).collect(),
+                        };
+                        connection.send(response, None).await
+                    }
+                };
+                if sent.is_err() {
+                    break;
+                }
             }
         }
     }
+
+    let transfer = connection.transfer;
+    debug!(
+        "Connection from {} on port {} closed after {:.1?}, {} packets received, {} packets ({}) sent",
+        peer,
+        listener.port,
+        transfer.duration(),
+        transfer.packets_received,
+        transfer.packets_sent,
+        format_size(transfer.bytes_sent)
+    );
+    events.emit(
+        &listener,
+        peer,
+        Event::ConnectionClosed {
+            duration_ms: transfer.duration().as_millis() as u64,
+            packets_received: transfer.packets_received,
+            packets_sent: transfer.packets_sent,
+            bytes_sent: transfer.bytes_sent,
+        },
+    );
+    transfer
 }
 
-fn get_filesize_of(patch_dir: &Path, file: &PatchFile) -> u32 {
-    let absolute_file = patch_dir.join(file.patch.to_string()).join(&file.file);
-    fs::metadata(absolute_file).unwrap().len() as u32
+/// The changelog of the version the client was sent to. Clients that didn't
+/// ask for a patch yet get those of every channel meant for them.
+fn changelog_notices(
+    listener: &Listener,
+    patched: Option<&(Arc<Channel>, u16)>,
+    identity: Option<&str>,
+) -> Vec<GatewayNotice> {
+    if let Some((channel, version)) = patched {
+        return channel.changelog_notice(*version).into_iter().collect();
+    }
+
+    listener
+        .routes
+        .iter()
+        .filter(|route| {
+            let channel = &route.channel;
+            channel.modules().is_empty()
+                || identity.is_some_and(|module| channel.accepts_module(module))
+        })
+        .filter_map(|route| route.channel.changelog_notice(route.target()?))
+        .collect()
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-    let local_patch_dir = "./patches";
-    let local_patch_dir = PathBuf::from(local_patch_dir);
-    let patches = load_patches(&local_patch_dir);
-    let patch_versions = patches.iter().map(|p| p.version).collect::<Vec<u16>>();
-    let patch_provider = PatchProvider::new(
-        local_patch_dir,
-        PatchFileserver {
-            ip: "127.0.0.1".to_string(),
-            host: "localhost".to_string(),
-            base_path: "".to_string(),
-        },
+/// Picks the first fileserver the file is available on. If it's available
+/// on none of them, the most preferred one is used regardless.
+async fn pick_fileserver<'a>(
+    url_check: &UrlCheck,
+    fileservers: &'a [PatchFileserver],
+    provider: &PatchProvider,
+    file: &PatchFile,
+) -> &'a PatchFileserver {
+    for fileserver in fileservers {
+        if url_check
+            .is_available(&file_url(fileserver, provider, file))
+            .await
+        {
+            return fileserver;
+        }
+    }
+    error!(
+        "File '{}' of version {} is unavailable on all fileservers",
+        file.file.display(),
+        file.patch
     );
-    for patch in patches {
-        patch_provider.add_patch(patch.version, patch.files);
+    &fileservers[0]
+}
+
+/// The URL clients download the file from.
+fn file_url(fileserver: &PatchFileserver, provider: &PatchProvider, file: &PatchFile) -> String {
+    let hash = hash_for(fileserver, provider, file);
+    fileserver.url_of(&fileserver.path_of(file.patch, file.file.to_str().unwrap(), hash.as_deref()))
+}
+
+/// The checksum of the file, if the fileserver's paths include it.
+fn hash_for(
+    fileserver: &PatchFileserver,
+    provider: &PatchProvider,
+    file: &PatchFile,
+) -> Option<String> {
+    if !fileserver.uses_hash() {
+        return None;
     }
-    let patch_provider = Arc::new(patch_provider);
-    let mut coordinator = SocketCoordinator::new(patch_provider);
-    for patch in patch_versions {
-        coordinator.accept_patch(patch);
+
+    let hash = provider.hash_of(file);
+    if hash.is_none() {
+        warn!(
+            "File '{}' of version {} has no checksum for its path",
+            file.file.display(),
+            file.patch
+        );
+    }
+    hash
+}
+
+/// Replaces the placeholders in the article of a notice with the details of
+/// the listener's first channel.
+fn fill_placeholders(mut notice: GatewayNotice, listener: &Listener) -> GatewayNotice {
+    let Some(route) = listener.routes.first() else {
+        return notice;
+    };
+
+    if notice.article.contains(UPDATE_SIZE_PLACEHOLDER) {
+        let size = route.channel.update_size().unwrap_or(0);
+        notice.article = notice
+            .article
+            .replace(UPDATE_SIZE_PLACEHOLDER, &format_size(size));
+    }
+    if notice.article.contains(VERSION_PLACEHOLDER) {
+        let version = route
+            .target()
+            .map(|target| match route.channel.version_name(target) {
+                Some(name) => name.to_string(),
+                None => target.to_string(),
+            })
+            .unwrap_or_default();
+        notice.article = notice.article.replace(VERSION_PLACEHOLDER, &version);
+    }
+    notice
+}
+
+/// Formats a size in bytes for humans, e.g. `1.5 GB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Checks the serialized size of the response, as some clients silently fail
+/// to handle overly large responses. Oversized responses are logged and
+/// replaced by the configured rejection, if any.
+fn limit_response_size(
+    result: PatchResult,
+    config: ResponseSizeConfig,
+    peer: SocketAddr,
+) -> PatchResult {
+    let Some(max_size) = config.max_size else {
+        return result;
+    };
+
+    let response = PatchResponse { result };
+    let size = response.byte_size();
+    if size <= max_size {
+        return response.result;
+    }
+
+    let files = match &response.result {
+        PatchResult::Problem {
+            error: PatchError::Update { patch_files, .. },
+        } => patch_files.len(),
+        _ => 0,
+    };
+    warn!(
+        "Response to {} with {} files is {} bytes large, exceeding the limit of {} bytes",
+        peer, files, size, max_size
+    );
+    match config.rejection {
+        Some(rejection) => PatchResult::Problem {
+            error: rejection.into(),
+        },
+        None => response.result,
+    }
+}
+
+/// Derives the id of each file from its path, as the first four bytes of the
+/// SHA-256 of the path with `/` as separator, so clients get the same id for
+/// a file across requests, restarts and builds of the server. Ids of
+/// colliding paths are incremented until they are unique, in the order of the
+/// paths.
+fn assign_file_ids(files: &[PatchFile]) -> Vec<u32> {
+    let paths = files
+        .iter()
+        .map(|file| {
+            file.file
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect::<Vec<_>>();
+    let mut order = (0..files.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| paths[*a].cmp(&paths[*b]));
+
+    let mut used = HashSet::new();
+    let mut ids = vec![0; files.len()];
+    for index in order {
+        let digest = Sha256::digest(&paths[index]);
+        let mut id = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        while !used.insert(id) {
+            id = id.wrapping_add(1);
+        }
+        ids[index] = id;
+    }
+    ids
+}
+
+/// Logs what `RUST_LOG` asks for, unless `--log-level` replaces it.
+fn log_builder(log_level: Option<&str>) -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    match log_level {
+        Some(log_level) => builder
+            .parse_filters(log_level)
+            .parse_env(Env::new().write_style(DEFAULT_WRITE_STYLE_ENV)),
+        None => builder.parse_env(Env::default()),
+    };
+    builder
+}
+
+fn main() {
+    // Loaded first, so the file can also configure logging, and before the
+    // runtime starts any threads.
+    if let Err(err) = env_file::load(Path::new("./.env")) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Should be able to start the runtime")
+        .block_on(run());
+}
+
+async fn run() {
+    let cli = Cli::parse();
+    let mut log_builder = log_builder(cli.log_level.as_deref());
+    // The dashboard occupies the terminal, so it shows the logs itself.
+    #[cfg(feature = "tui")]
+    let log_tail = cli.tui.then(|| tui::init_logging(&mut log_builder));
+    #[cfg(not(feature = "tui"))]
+    if cli.tui {
+        eprintln!("The dashboard requires building with the 'tui' feature");
+        process::exit(1);
+    }
+    if !cli.tui {
+        log_builder.init();
+    }
+    match cli.command {
+        Some(Command::Completions { shell }) => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+            return;
+        }
+        Some(Command::Man) => {
+            clap_mangen::Man::new(Cli::command())
+                .render(&mut io::stdout())
+                .expect("Should be able to write the man page");
+            return;
+        }
+        _ => {}
+    }
+    // Fixtures are served without any config.
+    if let Some(fixtures) = &cli.fixtures {
+        fixtures::serve(fixtures).await;
+        return;
+    }
+    let command_line = cli.settings();
+    if let Some(Command::CheckConfig) = cli.command {
+        let report = check::check(&cli.config, command_line);
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        process::exit(if report.valid { 0 } else { 1 });
+    }
+    let mut config =
+        Config::resolve(&cli.config, command_line.clone()).expect("Config should be valid");
+
+    match cli.command {
+        Some(Command::CompareReference { reference }) => {
+            let matches = reference::compare(&config, &reference);
+            process::exit(if matches { 0 } else { 1 });
+        }
+        Some(Command::Probe { address, version }) => {
+            let mut client = PatchClient::connect(address.as_str())
+                .await
+                .expect("Should be able to connect to the server");
+            let identity = client.identify("SR_Client").await.unwrap();
+            println!("Connected to '{}'", identity.module_name);
+            println!("{:#?}", client.notices().await.unwrap());
+            println!(
+                "{:#?}",
+                client.request_patch("SR_Client", version).await.unwrap()
+            );
+            return;
+        }
+        Some(Command::Replicate {
+            channel,
+            version,
+            url,
+            token,
+        }) => {
+            let (patch_dir, blob_dir) = channel_dirs(&config, &channel);
+            match replication::push(
+                &patch_dir,
+                blob_dir.as_deref(),
+                &channel,
+                version,
+                &url,
+                &token,
+            )
+            .await
+            {
+                Ok(()) => info!("Replicated version {} of channel '{}'", version, channel),
+                Err(err) => {
+                    error!("Could not replicate version {}: {}", version, err);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Ingest) => {
+            let channel_configs = config
+                .channels
+                .iter()
+                .chain(config.tenants.iter().flat_map(|tenant| &tenant.channels));
+            for channel_config in channel_configs {
+                let Some(blob_dir) = &channel_config.blob_dir else {
+                    continue;
+                };
+                match blob_store::ingest(&channel_config.patch_dir, blob_dir) {
+                    Ok(ingested) => info!(
+                        "Ingested {} versions of channel '{}', moving {} files and saving {}",
+                        ingested.versions,
+                        channel_config.name,
+                        ingested.files,
+                        format_size(ingested.saved)
+                    ),
+                    Err(err) => {
+                        error!(
+                            "Could not ingest channel '{}': {}",
+                            channel_config.name, err
+                        );
+                        process::exit(1);
+                    }
+                }
+            }
+            return;
+        }
+        Some(Command::Deltas) => {
+            let channel_configs = config
+                .channels
+                .iter()
+                .chain(config.tenants.iter().flat_map(|tenant| &tenant.channels));
+            for channel_config in channel_configs {
+                let Some(deltas) = &channel_config.deltas else {
+                    continue;
+                };
+                let provider = channel::load_provider(
+                    &channel_config.name,
+                    channel_config.patch_dir.clone(),
+                    PatchFileserver::default(),
+                );
+                provider.set_blob_dir(channel_config.blob_dir.clone());
+                for version in &channel_config.skipped_versions {
+                    provider.skip(*version);
+                }
+                match delta::generate(&provider, deltas) {
+                    Ok(generated) => info!(
+                        "Made {} deltas for channel '{}'",
+                        generated, channel_config.name
+                    ),
+                    Err(err) => {
+                        error!(
+                            "Could not make the deltas of channel '{}': {}",
+                            channel_config.name, err
+                        );
+                        process::exit(1);
+                    }
+                }
+            }
+            return;
+        }
+        Some(Command::Evict) => {
+            let channel_configs = config
+                .channels
+                .iter()
+                .chain(config.tenants.iter().flat_map(|tenant| &tenant.channels));
+            for channel_config in channel_configs {
+                let Some(cold_storage) = &channel_config.cold_storage else {
+                    continue;
+                };
+                let provider = channel::load_provider(
+                    &channel_config.name,
+                    channel_config.patch_dir.clone(),
+                    PatchFileserver::default(),
+                );
+                provider.set_blob_dir(channel_config.blob_dir.clone());
+                for version in &channel_config.skipped_versions {
+                    provider.skip(*version);
+                }
+                match cold_storage::evict(&provider, cold_storage).await {
+                    Ok(evicted) => info!(
+                        "Evicted {} versions of channel '{}'",
+                        evicted, channel_config.name
+                    ),
+                    Err(err) => {
+                        error!(
+                            "Could not evict old versions of channel '{}': {}",
+                            channel_config.name, err
+                        );
+                        process::exit(1);
+                    }
+                }
+            }
+            return;
+        }
+        Some(Command::Diff {
+            channel,
+            version,
+            old,
+            new,
+        }) => {
+            let (patch_dir, _) = channel_dirs(&config, &channel);
+            let version_dir = patch_dir.join(version.to_string());
+            match client_diff::diff(&old, &new, &version_dir) {
+                Ok(diff) => info!(
+                    "Wrote version {} of channel '{}' to '{}' with {} changed and {} deleted files",
+                    version,
+                    channel,
+                    version_dir.display(),
+                    diff.changed,
+                    diff.deleted
+                ),
+                Err(err) => {
+                    error!("Could not build version {}: {}", version, err);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Import {
+            channel,
+            archive,
+            version,
+            admin_url,
+            admin_token,
+        }) => {
+            let (patch_dir, blob_dir) = channel_dirs(&config, &channel);
+            let imported =
+                match patch_archive::import(&archive, &patch_dir, blob_dir.as_deref(), version) {
+                    Ok(imported) => imported,
+                    Err(err) => {
+                        error!("Could not import '{}': {}", archive.display(), err);
+                        process::exit(1);
+                    }
+                };
+            for imported in &imported {
+                info!(
+                    "Imported version {} of channel '{}' with {} files",
+                    imported.version, channel, imported.files
+                );
+            }
+            if let Some(admin_url) = admin_url {
+                for imported in &imported {
+                    if let Err(err) = patch_archive::register(
+                        &admin_url,
+                        admin_token.as_deref(),
+                        &channel,
+                        imported.version,
+                    )
+                    .await
+                    {
+                        error!("{}", err);
+                        process::exit(1);
+                    }
+                }
+                info!("The running server serves the imported versions");
+            }
+            return;
+        }
+        Some(Command::Export {
+            channel,
+            archive,
+            from,
+            to,
+        }) => {
+            let (patch_dir, blob_dir) = channel_dirs(&config, &channel);
+            let provider = channel::load_provider(&channel, patch_dir, PatchFileserver::default());
+            provider.set_blob_dir(blob_dir);
+            let versions = provider
+                .versions()
+                .into_iter()
+                .filter(|version| from.is_none_or(|from| *version >= from))
+                .filter(|version| to.is_none_or(|to| *version <= to))
+                .collect::<Vec<_>>();
+            if versions.is_empty() {
+                error!("Channel '{}' has no versions to export", channel);
+                process::exit(1);
+            }
+            match patch_archive::export(&provider, &versions, &archive) {
+                Ok(files) => info!(
+                    "Exported {} versions of channel '{}' with {} files to '{}'",
+                    versions.len(),
+                    channel,
+                    files,
+                    archive.display()
+                ),
+                Err(err) => {
+                    error!("Could not export channel '{}': {}", channel, err);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::GenerateKey { key }) => {
+            match patch_signing::generate_key(&key) {
+                Ok(public_key) => {
+                    info!("Wrote the private key to '{}'", key.display());
+                    println!("{}", public_key);
+                }
+                Err(err) => {
+                    error!("Could not generate a key: {}", err);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Sign {
+            channel,
+            version,
+            key,
+        }) => {
+            let (patch_dir, blob_dir) = channel_dirs(&config, &channel);
+            let provider =
+                channel::load_provider(&channel, patch_dir.clone(), PatchFileserver::default());
+            provider.set_blob_dir(blob_dir);
+            if !provider.versions().contains(&version) {
+                error!(
+                    "Version {} of channel '{}' doesn't exist or can't be loaded",
+                    version, channel
+                );
+                process::exit(1);
+            }
+            let signed = provider
+                .checksums(version)
+                .map_err(|err| err.to_string())
+                .and_then(|checksums| {
+                    patch_signing::sign(
+                        &key,
+                        &patch_dir.join(version.to_string()),
+                        version,
+                        &checksums,
+                        &provider.deleted_of(version),
+                    )
+                });
+            match signed {
+                Ok(()) => info!("Signed version {} of channel '{}'", version, channel),
+                Err(err) => {
+                    error!("Could not sign version {}: {}", version, err);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::CheckConfig | Command::Completions { .. } | Command::Man) | None => {}
+    }
+
+    let tenant_configs = config.take_tenants();
+    let path_separator = config.compatibility.path_separator;
+    let maintenance = Arc::new(Maintenance::new(config.maintenance));
+    let geoip = config
+        .geoip_database
+        .as_deref()
+        .map(|path| Arc::new(GeoIp::open(path)));
+    let server = Arc::new(Server::new(
+        SocketCoordinator::new(
+            ClientSettings {
+                maintenance,
+                response_size: config.response_size,
+                transcripts: config.transcripts,
+                events: Events::open(config.events.as_deref()),
+                compatibility: config.compatibility,
+                notice_time: config.notice_time,
+                shard_list: config.shard_list,
+                login_rejection: config.login_rejection,
+                gateway_proxy: config.gateway_proxy,
+                unknown_opcodes: config.unknown_opcodes,
+                opcodes: OpcodeMap::new(&config.opcodes).expect("Opcode mapping should be valid"),
+            },
+            Arc::new(Alerts::new(config.alerts)),
+        ),
+        geoip,
+        config.downgrades,
+        config.storage,
+        config.chain_validation,
+    ));
+
+    for tenant_config in tenant_configs {
+        server
+            .add_tenant(tenant_config)
+            .expect("Tenant should be able to start");
+    }
+
+    if let Some(scan_interval) = config.scan_interval {
+        tokio::spawn(Arc::clone(&server).scan_periodically(scan_interval));
+    }
+
+    if let Some(scrub) = config.scrub {
+        tokio::spawn(Arc::clone(&server).scrub_periodically(scrub));
+    }
+
+    if let Some(mirror_sync_interval) = config.mirror_sync_interval {
+        tokio::spawn(Arc::clone(&server).sync_mirrors_periodically(mirror_sync_interval));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(Arc::clone(&server).reload_on_hangup(cli.config, command_line));
+
+    if let Some(file_hosting) = config.file_hosting {
+        tokio::spawn(file_hosting::serve(
+            file_hosting,
+            path_separator,
+            Arc::clone(&server),
+            server.child_token(),
+        ));
+    }
+
+    if let Some(admin) = config.admin {
+        tokio::spawn(admin::serve(
+            admin.bind,
+            admin.token,
+            admin.replication_token,
+            admin.patch_root,
+            Arc::clone(&server),
+            server.child_token(),
+        ));
+    }
+
+    #[cfg(feature = "tui")]
+    if let Some(log_tail) = log_tail {
+        let dashboard_server = Arc::clone(&server);
+        tokio::task::spawn_blocking(move || tui::run(dashboard_server, log_tail))
+            .await
+            .unwrap()
+            .expect("Should be able to render the dashboard");
+        server.shutdown();
+        return;
     }
 
     signal::ctrl_c()
         .await
         .expect("Should be able to listen for ctrl-c");
 
-    coordinator.shutdown();
+    server.shutdown();
 }
 
-fn load_patches(local_path: &Path) -> Vec<Patch> {
-    local_path
-        .read_dir()
-        .unwrap()
-        .filter_map(Result::ok)
-        .map(|entry| {
-            let patch: u16 = entry.file_name().into_string().unwrap().parse().unwrap();
-            let patch_files = collect_files_recursively(&entry.path());
+/// The patch directory and blob store of the channel in the config.
+fn channel_dirs(config: &Config, channel: &str) -> (PathBuf, Option<PathBuf>) {
+    let channel_config = config
+        .channels
+        .iter()
+        .chain(config.tenants.iter().flat_map(|tenant| &tenant.channels))
+        .find(|channel_config| channel_config.name == channel);
+    let patch_dir = channel_config
+        .map(|channel_config| channel_config.patch_dir.clone())
+        .or_else(|| config.patch_dir.clone())
+        .unwrap_or_else(config::default_patch_dir);
+    let blob_dir = channel_config.and_then(|channel_config| channel_config.blob_dir.clone());
+    (patch_dir, blob_dir)
+}
+
+/// Loads all versions inside the directory. Directories that aren't named
+/// after a version or can't be read are quarantined instead.
+fn load_patches(local_path: &Path) -> (Vec<Patch>, Vec<Quarantined>) {
+    let mut patches = Vec::new();
+    let mut quarantined = Vec::new();
+    for entry in local_path.read_dir().unwrap().filter_map(Result::ok) {
+        if Manifest::is_manifest(&entry.path()) || promotion::is_state_file(&entry.file_name()) {
+            continue;
+        }
+
+        let directory = entry.file_name().to_string_lossy().to_string();
+        let Ok(version) = directory.parse::<u16>() else {
+            quarantined.push(Quarantined {
+                directory,
+                version: None,
+                reason: "not named after a version".to_string(),
+            });
+            continue;
+        };
+
+        // Paths on the fileserver are built from the version, so e.g. `07`
+        // could never be served, and would clash with `7`.
+        if directory != version.to_string() {
+            quarantined.push(Quarantined {
+                reason: format!("ambiguous name, should be named '{}'", version),
+                directory,
+                version: None,
+            });
+            continue;
+        }
+
+        match read_patch(version, &entry.path()) {
+            Ok(patch) => patches.push(patch),
+            Err(err) => quarantined.push(Quarantined {
+                directory,
+                version: Some(version),
+                reason: err.to_string(),
+            }),
+        }
+    }
+
+    patches.sort_by_key(|patch| patch.version);
+    (patches, quarantined)
+}
 
-            Patch {
-                version: patch,
-                files: patch_files.into_boxed_slice(),
+/// Reads the files of a version from its `manifest.toml`, or from its
+/// directory if it has none.
+fn read_patch(version: u16, version_dir: &Path) -> io::Result<Patch> {
+    let mut deleted = patch_manifest::load_deletions(version_dir)?;
+    let (files, manifest) = match PatchManifest::load(version_dir)? {
+        Some(manifest) => {
+            deleted.extend_from_slice(manifest.deleted());
+            (manifest.files(), Some(Arc::new(manifest)))
+        }
+        None => (collect_files_recursively(version_dir)?, None),
+    };
+    deleted.sort();
+    deleted.dedup();
+    Ok(Patch {
+        version,
+        files: files.into_boxed_slice(),
+        deleted: deleted.into_boxed_slice(),
+        manifest,
+        // A broken changelog shouldn't keep the version from being served.
+        changelog: match Changelog::load(version_dir) {
+            Ok(changelog) => changelog.map(Arc::new),
+            Err(err) => {
+                warn!("Serving version {} without its changelog: {}", version, err);
+                None
             }
-        })
-        .collect()
+        },
+    })
 }
 
-fn collect_files_recursively(path: &Path) -> Vec<PathBuf> {
-    WalkDir::new(path)
-        .same_file_system(true)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|dir| dir.metadata().unwrap().is_file())
-        .map(|file| file.path().strip_prefix(path).unwrap().to_path_buf())
-        .collect()
+/// Lists the files of a version directory. Symlinks are followed as long as
+/// they stay inside the patch directory, e.g. to share files between
+/// versions, and skipped otherwise.
+fn collect_files_recursively(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let patch_dir = path.parent().unwrap_or(path);
+    let root = Root::new(patch_dir)?;
+    let mut files = Vec::new();
+    for entry in WalkDir::new(path).follow_links(true).same_file_system(true) {
+        let entry = entry?;
+        if !entry.metadata()?.is_file()
+            || (entry.depth() == 1 && patch_manifest::is_listing(entry.file_name()))
+        {
+            continue;
+        }
+
+        if let Err(err) = root.resolve(entry.path().strip_prefix(patch_dir).unwrap()) {
+            warn!("Skipping '{}': {}", entry.path().display(), err);
+            continue;
+        }
+        files.push(entry.path().strip_prefix(path).unwrap().to_path_buf());
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// An empty patch directory for a single test.
+    fn patch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("patch-provider-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn patch(version: u16, files: &[&str], deleted: &[&str]) -> Patch {
+        Patch {
+            version,
+            files: files.iter().map(PathBuf::from).collect(),
+            deleted: deleted.iter().map(PathBuf::from).collect(),
+            manifest: None,
+            changelog: None,
+        }
+    }
+
+    /// The files planned for the update, with the version each is taken from.
+    fn planned(provider: &PatchProvider, current: u16, target: u16) -> Vec<(String, u16)> {
+        provider
+            .collect_necessary_files(current, target)
+            .into_iter()
+            .map(|file| (file.file.to_string_lossy().into_owned(), file.patch))
+            .collect()
+    }
+
+    fn planned_file(file: &str, patch: u16) -> PatchFile {
+        PatchFile {
+            file: PathBuf::from(file),
+            patch,
+        }
+    }
+
+    fn quarantine(provider: &PatchProvider, version: u16) {
+        provider.quarantine(Quarantined {
+            directory: version.to_string(),
+            version: Some(version),
+            reason: "contains unavailable files".to_string(),
+        });
+    }
+
+    #[test]
+    fn deleted_files_are_only_planned_before_their_deletion() {
+        let provider = PatchProvider::new(PathBuf::from("patches"), PatchFileserver::default());
+        provider.add_patch(patch(1, &["Media.pk2", "Music.pk2"], &[]));
+        provider.add_patch(patch(2, &["Media.pk2"], &["Music.pk2"]));
+        provider.add_patch(patch(3, &["Map.pk2", "Music.pk2"], &[]));
+
+        let file = |file: &str, version| (file.to_string(), version);
+        assert_eq!(planned(&provider, 0, 2), [file("Media.pk2", 2)]);
+        assert_eq!(
+            planned(&provider, 0, 3),
+            [
+                file("Map.pk2", 3),
+                file("Media.pk2", 2),
+                file("Music.pk2", 3)
+            ]
+        );
+        assert_eq!(provider.version_up_to(Path::new("Music.pk2"), 2), None);
+        // Downgrades restore deleted files from before their deletion.
+        assert_eq!(
+            planned(&provider, 2, 1),
+            [file("Media.pk2", 1), file("Music.pk2", 1)]
+        );
+    }
+
+    #[test]
+    fn skipped_and_retired_versions_are_left_out_of_plans() {
+        let provider = PatchProvider::new(PathBuf::from("patches"), PatchFileserver::default());
+        provider.add_patch(patch(1, &["Media.pk2"], &[]));
+        provider.add_patch(patch(2, &["Media.pk2", "Music.pk2"], &[]));
+        provider.add_patch(patch(3, &["Media.pk2"], &[]));
+
+        let file = |file: &str, version| (file.to_string(), version);
+        provider.skip(2);
+        assert_eq!(planned(&provider, 1, 3), [file("Media.pk2", 3)]);
+        provider.set_state(3, PatchState::Retired).unwrap();
+        assert!(planned(&provider, 1, 3).is_empty());
+        assert_eq!(provider.versions(), [1]);
+        // Clients that already got the files of excluded versions are reverted.
+        assert_eq!(planned(&provider, 3, 1), [file("Media.pk2", 1)]);
+    }
+
+    #[test]
+    fn file_ids_depend_only_on_the_path() {
+        let ids = assign_file_ids(&[planned_file("Media.pk2", 1), planned_file("Music.pk2", 2)]);
+        let reordered = assign_file_ids(&[
+            planned_file("Map.pk2", 3),
+            planned_file("Music.pk2", 5),
+            planned_file("Media.pk2", 4),
+        ]);
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(reordered[2], ids[0]);
+        assert_eq!(reordered[1], ids[1]);
+
+        let nested = assign_file_ids(&[planned_file("Data/Media.pk2", 1)]);
+        let digest = Sha256::digest("Data/Media.pk2");
+        assert_eq!(
+            nested[0],
+            u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+        );
+    }
+
+    #[test]
+    fn fixed_versions_are_released_from_quarantine() {
+        let dir = patch_dir("fixed");
+        let provider = channel::load_provider("stable", dir.clone(), PatchFileserver::default());
+        quarantine(&provider, 1);
+        assert!(provider.recheck_quarantined().is_empty());
+
+        fs::create_dir_all(dir.join("1")).unwrap();
+        fs::write(dir.join("1").join("Media.pk2"), "media").unwrap();
+        assert_eq!(provider.recheck_quarantined(), [1]);
+        assert!(provider.quarantined().is_empty());
+        assert_eq!(provider.versions(), [1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn versions_with_a_broken_changelog_are_served_without_it() {
+        let dir = patch_dir("changelog");
+        let version_dir = dir.join("1");
+        fs::create_dir_all(&version_dir).unwrap();
+        fs::write(version_dir.join("Media.pk2"), "media").unwrap();
+        fs::write(version_dir.join(changelog::FILE_NAME), "title = ").unwrap();
+        let provider = channel::load_provider("stable", dir.clone(), PatchFileserver::default());
+        assert_eq!(provider.versions(), [1]);
+        assert!(provider.quarantined().is_empty());
+        assert!(provider.changelog_of(1).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_versions_stay_quarantined() {
+        let dir = patch_dir("corrupted");
+        let version_dir = dir.join("1");
+        fs::create_dir_all(&version_dir).unwrap();
+        fs::write(
+            version_dir.join(patch_manifest::FILE_NAME),
+            format!(
+                "[[files]]\npath = \"Media.pk2\"\nsize = 5\nsha256 = \"{:x}\"\n",
+                Sha256::digest("media")
+            ),
+        )
+        .unwrap();
+        fs::write(version_dir.join("Media.pk2"), "mediA").unwrap();
+        let provider = channel::load_provider("stable", dir.clone(), PatchFileserver::default());
+        quarantine(&provider, 1);
+        assert!(provider.recheck_quarantined().is_empty());
+        assert!(provider.versions().is_empty());
+
+        fs::write(version_dir.join("Media.pk2"), "media").unwrap();
+        assert_eq!(provider.recheck_quarantined(), [1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,76 @@
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// A recurring period during which the server reports itself as offline.
+#[derive(Deserialize, Clone)]
+pub struct MaintenanceWindow {
+    /// Cron expression (including seconds) for the start of the window,
+    /// e.g. `0 0 4 * * Thu` for every thursday at 4am UTC.
+    #[serde(deserialize_with = "deserialize_schedule")]
+    pub schedule: Schedule,
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    /// Notice shown to clients while the maintenance is ongoing.
+    #[serde(default)]
+    pub notice: Option<MaintenanceNotice>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MaintenanceNotice {
+    pub subject: String,
+    pub article: String,
+}
+
+impl MaintenanceWindow {
+    /// Returns the start of the currently ongoing occurrence of this window, if any.
+    pub fn active_since(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let duration = chrono::Duration::from_std(self.duration).ok()?;
+        self.schedule
+            .after(&(now - duration))
+            .next()
+            .filter(|start| *start <= now)
+    }
+}
+
+pub struct ActiveMaintenance {
+    pub window: MaintenanceWindow,
+    pub since: DateTime<Utc>,
+}
+
+pub struct Maintenance {
+    windows: RwLock<Vec<MaintenanceWindow>>,
+}
+
+impl Maintenance {
+    pub fn new(windows: Vec<MaintenanceWindow>) -> Maintenance {
+        Maintenance {
+            windows: RwLock::new(windows),
+        }
+    }
+
+    pub fn set_windows(&self, windows: Vec<MaintenanceWindow>) {
+        *self.windows.write().unwrap() = windows;
+    }
+
+    pub fn current(&self) -> Option<ActiveMaintenance> {
+        let now = Utc::now();
+        self.windows.read().unwrap().iter().find_map(|window| {
+            window.active_since(now).map(|since| ActiveMaintenance {
+                window: window.clone(),
+                since,
+            })
+        })
+    }
+}
+
+fn deserialize_schedule<'de, D>(deserializer: D) -> Result<Schedule, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let expression = String::deserialize(deserializer)?;
+    Schedule::from_str(&expression).map_err(serde::de::Error::custom)
+}
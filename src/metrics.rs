@@ -0,0 +1,289 @@
+use crate::protocol::PatchResult;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The number of recently answered clients kept per listener.
+const RECENT_CLIENTS: usize = 20;
+
+/// How a patch request was answered.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    UpToDate,
+    Updated,
+    Rejected,
+}
+
+impl Outcome {
+    pub fn of(result: &PatchResult) -> Outcome {
+        match result {
+            PatchResult::UpToDate { .. } => Outcome::UpToDate,
+            PatchResult::Problem { error } if error.is_update() => Outcome::Updated,
+            PatchResult::Problem { .. } => Outcome::Rejected,
+        }
+    }
+}
+
+/// Counters of a single listener.
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    limited_connections: AtomicU64,
+    up_to_date: AtomicU64,
+    updated: AtomicU64,
+    rejected: AtomicU64,
+    listener_failures: AtomicU64,
+    listener_restarts: AtomicU64,
+    degraded: AtomicBool,
+    planned_bytes: AtomicU64,
+    largest_plan: AtomicU64,
+    silent_connections: AtomicU64,
+    connection_milliseconds: AtomicU64,
+    packets_received: AtomicU64,
+    unknown_packets: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    recent_clients: Mutex<VecDeque<RecentClient>>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct RecentClient {
+    pub time: DateTime<Utc>,
+    pub address: SocketAddr,
+    pub version: u32,
+    pub outcome: Outcome,
+}
+
+/// What was exchanged over a single connection, not counting the handshake.
+pub struct Transfer {
+    started: Instant,
+    pub packets_received: u64,
+    pub packets_sent: u64,
+    /// The size of the sent packets without headers.
+    pub bytes_sent: u64,
+}
+
+impl Transfer {
+    pub fn start() -> Transfer {
+        Transfer {
+            started: Instant::now(),
+            packets_received: 0,
+            packets_sent: 0,
+            bytes_sent: 0,
+        }
+    }
+
+    pub fn received(&mut self) {
+        self.packets_received += 1;
+    }
+
+    pub fn sent(&mut self, size: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += size as u64;
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// The current values of a listener's counters, e.g. to compute rates.
+#[derive(Clone, Copy)]
+pub struct Snapshot {
+    #[cfg(feature = "tui")]
+    pub active_connections: u64,
+    pub up_to_date: u64,
+    pub updated: u64,
+    pub rejected: u64,
+}
+
+impl Snapshot {
+    pub fn requests(&self) -> u64 {
+        self.up_to_date + self.updated + self.rejected
+    }
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self, transfer: &Transfer) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        if transfer.packets_received == 0 {
+            self.silent_connections.fetch_add(1, Ordering::Relaxed);
+        }
+        self.connection_milliseconds
+            .fetch_add(transfer.duration().as_millis() as u64, Ordering::Relaxed);
+        self.packets_received
+            .fetch_add(transfer.packets_received, Ordering::Relaxed);
+        self.packets_sent
+            .fetch_add(transfer.packets_sent, Ordering::Relaxed);
+        self.bytes_sent
+            .fetch_add(transfer.bytes_sent, Ordering::Relaxed);
+    }
+
+    pub fn connection_limited(&self) {
+        self.limited_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unknown_packet(&self) {
+        self.unknown_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn listener_failed(&self) {
+        self.listener_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn listener_restarted(&self) {
+        self.listener_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks the listener as failing repeatedly, or as recovered.
+    pub fn set_degraded(&self, degraded: bool) {
+        self.degraded.store(degraded, Ordering::Relaxed);
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    pub fn plan_computed(&self, size: u64) {
+        self.planned_bytes.fetch_add(size, Ordering::Relaxed);
+        self.largest_plan.fetch_max(size, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, address: SocketAddr, version: u32, outcome: Outcome) {
+        let counter = match outcome {
+            Outcome::UpToDate => &self.up_to_date,
+            Outcome::Updated => &self.updated,
+            Outcome::Rejected => &self.rejected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut recent_clients = self.recent_clients.lock().unwrap();
+        if recent_clients.len() == RECENT_CLIENTS {
+            recent_clients.pop_front();
+        }
+        recent_clients.push_back(RecentClient {
+            time: Utc::now(),
+            address,
+            version,
+            outcome,
+        });
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            #[cfg(feature = "tui")]
+            active_connections: load(&self.active_connections),
+            up_to_date: load(&self.up_to_date),
+            updated: load(&self.updated),
+            rejected: load(&self.rejected),
+        }
+    }
+
+    /// The most recently answered clients, oldest first.
+    pub fn recent_clients(&self) -> Vec<RecentClient> {
+        self.recent_clients
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    fn values(&self) -> [(&'static str, &'static str, u64); 17] {
+        [
+            ("connections_total", "counter", load(&self.connections)),
+            (
+                "active_connections",
+                "gauge",
+                load(&self.active_connections),
+            ),
+            (
+                "limited_connections_total",
+                "counter",
+                load(&self.limited_connections),
+            ),
+            ("up_to_date_total", "counter", load(&self.up_to_date)),
+            ("updated_total", "counter", load(&self.updated)),
+            ("rejected_total", "counter", load(&self.rejected)),
+            (
+                "listener_failures_total",
+                "counter",
+                load(&self.listener_failures),
+            ),
+            (
+                "listener_restarts_total",
+                "counter",
+                load(&self.listener_restarts),
+            ),
+            ("listener_degraded", "gauge", u64::from(self.is_degraded())),
+            ("planned_bytes_total", "counter", load(&self.planned_bytes)),
+            ("largest_plan_bytes", "gauge", load(&self.largest_plan)),
+            (
+                "silent_connections_total",
+                "counter",
+                load(&self.silent_connections),
+            ),
+            (
+                "connection_milliseconds_total",
+                "counter",
+                load(&self.connection_milliseconds),
+            ),
+            (
+                "packets_received_total",
+                "counter",
+                load(&self.packets_received),
+            ),
+            (
+                "unknown_packets_total",
+                "counter",
+                load(&self.unknown_packets),
+            ),
+            ("packets_sent_total", "counter", load(&self.packets_sent)),
+            ("sent_bytes_total", "counter", load(&self.bytes_sent)),
+        ]
+    }
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Renders the metrics of all listeners in the prometheus text format,
+/// labelled with their tenant and port.
+pub fn render<'a>(listeners: impl IntoIterator<Item = (&'a str, u16, &'a Metrics)>) -> String {
+    let mut samples = Vec::new();
+    for (tenant, port, metrics) in listeners {
+        for (index, (name, kind, value)) in metrics.values().into_iter().enumerate() {
+            samples.push((index, name, kind, tenant, port, value));
+        }
+    }
+    samples.sort_by_key(|(index, ..)| *index);
+
+    let mut output = String::new();
+    let mut previous = None;
+    for (_, name, kind, tenant, port, value) in samples {
+        if previous != Some(name) {
+            writeln!(output, "# TYPE patch_server_{} {}", name, kind).unwrap();
+            previous = Some(name);
+        }
+        writeln!(
+            output,
+            "patch_server_{}{{tenant=\"{}\",port=\"{}\"}} {}",
+            name, tenant, port, value
+        )
+        .unwrap();
+    }
+    output
+}
@@ -0,0 +1,118 @@
+use crate::{PatchFile, PatchFileserver, PatchProvider};
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// Another fileserver the patches of a channel are uploaded to via HTTP PUT.
+#[derive(Deserialize, Clone)]
+pub struct MirrorConfig {
+    pub name: String,
+    /// The fileserver advertised to clients once the mirror is in sync.
+    pub fileserver: PatchFileserver,
+    /// Files are uploaded to `<upload_url>/<version>/<file>`.
+    pub upload_url: String,
+}
+
+/// A mirror of a channel, which is only advertised for plans whose versions
+/// it completely received, so clients never run into missing files.
+pub struct Mirror {
+    config: MirrorConfig,
+    synced: RwLock<BTreeSet<u16>>,
+}
+
+impl Mirror {
+    pub fn new(config: MirrorConfig) -> Mirror {
+        Mirror {
+            config,
+            synced: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn fileserver(&self) -> &PatchFileserver {
+        &self.config.fileserver
+    }
+
+    pub fn has_synced(&self, versions: &BTreeSet<u16>) -> bool {
+        versions.is_subset(&self.synced.read().unwrap())
+    }
+
+    pub fn synced(&self) -> BTreeSet<u16> {
+        self.synced.read().unwrap().clone()
+    }
+
+    /// Uploads all versions the mirror didn't receive yet. Files that already
+    /// exist on the mirror with the same size are not uploaded again.
+    pub async fn sync(&self, client: &reqwest::Client, provider: &PatchProvider) {
+        for version in provider.versions() {
+            if self.synced.read().unwrap().contains(&version) {
+                continue;
+            }
+
+            let mut complete = true;
+            for file in provider.files_of(version) {
+                if let Err(err) = self.upload(client, provider, version, &file).await {
+                    warn!(
+                        "Could not upload '{}' of version {} to mirror '{}': {}",
+                        file.display(),
+                        version,
+                        self.name(),
+                        err
+                    );
+                    complete = false;
+                    break;
+                }
+            }
+            if complete {
+                info!("Mirror '{}' received version {}", self.name(), version);
+                self.synced.write().unwrap().insert(version);
+            }
+        }
+    }
+
+    pub async fn upload(
+        &self,
+        client: &reqwest::Client,
+        provider: &PatchProvider,
+        version: u16,
+        file: &Path,
+    ) -> Result<(), String> {
+        let path = provider.local_path(&PatchFile {
+            file: file.to_path_buf(),
+            patch: version,
+        });
+        let url = format!(
+            "{}/{}/{}",
+            self.config.upload_url.trim_end_matches('/'),
+            version,
+            file.to_str().unwrap().replace('\\', "/")
+        );
+        let size = tokio::fs::metadata(&path)
+            .await
+            .map_err(|err| err.to_string())?
+            .len();
+        let existing = client.head(&url).send().await;
+        if existing.is_ok_and(|response| {
+            response.status().is_success() && response.content_length() == Some(size)
+        }) {
+            return Ok(());
+        }
+
+        let content = tokio::fs::read(&path)
+            .await
+            .map_err(|err| err.to_string())?;
+        client
+            .put(&url)
+            .body(content)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+}
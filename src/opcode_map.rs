@@ -0,0 +1,70 @@
+use serde::Deserialize;
+use skrillax_stream::packet::OutgoingPacket;
+use std::collections::HashMap;
+
+/// Replaces an opcode of the protocol in both directions, for clients built
+/// with their opcodes shuffled.
+#[derive(Deserialize, Clone, Copy)]
+pub struct OpcodeMapping {
+    /// The opcode as used by official clients, e.g. `0x6100` for patch
+    /// requests.
+    pub standard: u16,
+    /// The opcode the customized client uses instead.
+    pub client: u16,
+}
+
+/// Translates between the opcodes of a customized client and the standard
+/// ones. Opcodes without a mapping are left as they are.
+#[derive(Default)]
+pub struct OpcodeMap {
+    inbound: HashMap<u16, u16>,
+    outbound: HashMap<u16, u16>,
+}
+
+impl OpcodeMap {
+    pub fn new(mappings: &[OpcodeMapping]) -> Result<OpcodeMap, String> {
+        let mut map = OpcodeMap::default();
+        for mapping in mappings {
+            if map
+                .outbound
+                .insert(mapping.standard, mapping.client)
+                .is_some()
+            {
+                return Err(format!(
+                    "Opcode 0x{:04X} is mapped more than once",
+                    mapping.standard
+                ));
+            }
+            if map
+                .inbound
+                .insert(mapping.client, mapping.standard)
+                .is_some()
+            {
+                return Err(format!(
+                    "Opcode 0x{:04X} of the client is mapped more than once",
+                    mapping.client
+                ));
+            }
+        }
+        Ok(map)
+    }
+
+    /// The standard opcode of a packet sent by the client.
+    pub fn inbound(&self, opcode: u16) -> u16 {
+        self.inbound.get(&opcode).copied().unwrap_or(opcode)
+    }
+
+    /// Turns the packet into the one to send, with the opcode the client
+    /// expects.
+    pub fn outbound(&self, packet: impl Into<OutgoingPacket>) -> OutgoingPacket {
+        let mut packet = packet.into();
+        match &mut packet {
+            OutgoingPacket::Simple { opcode, .. } | OutgoingPacket::Massive { opcode, .. } => {
+                if let Some(client) = self.outbound.get(opcode) {
+                    *opcode = *client;
+                }
+            }
+        }
+        packet
+    }
+}
@@ -0,0 +1,371 @@
+use crate::blob_store;
+use crate::changelog;
+use crate::hash::{self, Manifest};
+use crate::patch_manifest::{self, ListedFile, PatchManifest};
+use crate::patch_signing;
+use crate::replication::AUTHORIZATION_PREFIX;
+use crate::{read_patch, PatchFile, PatchProvider};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Where archives are unpacked to before their versions are moved into place.
+const STAGING_DIR_NAME: &str = ".import.partial";
+
+/// Files describing a version that are bundled with its files.
+const LISTINGS: [&str; 4] = [
+    patch_manifest::FILE_NAME,
+    patch_manifest::DELETIONS_FILE_NAME,
+    patch_signing::FILE_NAME,
+    changelog::FILE_NAME,
+];
+
+/// A version installed from an archive.
+pub struct Imported {
+    pub version: u16,
+    pub files: usize,
+}
+
+/// Bundles the versions into a tar archive, gzipped if its name ends with
+/// `.tar.gz` or `.tgz`, in the layout [import] expects: a directory per
+/// version, with files from the blob store in place, and its checksums next
+/// to it. Unpacked, the bundle is a patch directory that can be served or
+/// mirrored as it is. Returns the number of files bundled.
+pub fn export(provider: &PatchProvider, versions: &[u16], archive: &Path) -> Result<usize, String> {
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let gzipped = name.ends_with(".tar.gz") || name.ends_with(".tgz");
+    if !gzipped && !name.ends_with(".tar") {
+        return Err(format!(
+            "'{}' has to end with '.tar', '.tar.gz' or '.tgz'",
+            archive.display()
+        ));
+    }
+
+    // Written next to it first, so a failed export never leaves a bundle
+    // behind that looks complete.
+    let mut partial = archive.as_os_str().to_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let file = File::create(&partial).map_err(|err| err.to_string())?;
+    let bundled = if gzipped {
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        let bundled = bundle(&mut builder, provider, versions);
+        bundled.and_then(|bundled| {
+            builder.into_inner()?.finish()?;
+            Ok(bundled)
+        })
+    } else {
+        let mut builder = tar::Builder::new(file);
+        let bundled = bundle(&mut builder, provider, versions);
+        bundled.and_then(|bundled| {
+            builder.into_inner()?;
+            Ok(bundled)
+        })
+    };
+    match bundled {
+        Ok(bundled) => {
+            fs::rename(&partial, archive).map_err(|err| err.to_string())?;
+            Ok(bundled)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            Err(err.to_string())
+        }
+    }
+}
+
+fn bundle<W: Write>(
+    builder: &mut tar::Builder<W>,
+    provider: &PatchProvider,
+    versions: &[u16],
+) -> io::Result<usize> {
+    let mut bundled = 0;
+    for &version in versions {
+        let version_dir = PathBuf::from(version.to_string());
+        for file in provider.files_of(version) {
+            let path = provider.resolve(&PatchFile {
+                file: file.clone(),
+                patch: version,
+            })?;
+            builder.append_path_with_name(path, version_dir.join(&file))?;
+            bundled += 1;
+        }
+        for listing in LISTINGS {
+            let path = provider.patch_dir().join(&version_dir).join(listing);
+            if path.is_file() {
+                builder.append_path_with_name(path, version_dir.join(listing))?;
+            }
+        }
+
+        let checksums = provider.checksums(version)?;
+        let checksums = Manifest::from_hashes(checksums.into_iter().collect::<HashMap<_, _>>());
+        let content = checksums.render();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(
+            &mut header,
+            Manifest::path_for(Path::new(""), version),
+            content.as_bytes(),
+        )?;
+    }
+    Ok(bundled)
+}
+
+/// Installs the versions inside a zip or tar archive, optionally gzipped,
+/// into the patch directory. The archive either contains a directory per
+/// version, named after it, or the files of a single version, which is
+/// given or else follows the latest version inside the patch directory.
+/// Checksums next to a version directory (`<version>.sha256`) have to match
+/// its files. Versions are only moved into place once all of them are valid.
+pub fn import(
+    archive: &Path,
+    patch_dir: &Path,
+    blob_dir: Option<&Path>,
+    version: Option<u16>,
+) -> Result<Vec<Imported>, String> {
+    let staging_dir = patch_dir.join(STAGING_DIR_NAME);
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir).map_err(|err| err.to_string())?;
+    }
+    fs::create_dir_all(&staging_dir).map_err(|err| err.to_string())?;
+    let result = unpack(archive, &staging_dir)
+        .and_then(|()| install(&staging_dir, patch_dir, blob_dir, version));
+    let _ = fs::remove_dir_all(&staging_dir);
+    result
+}
+
+fn unpack(archive: &Path, dir: &Path) -> Result<(), String> {
+    let name = archive
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let file = File::open(archive).map_err(|err| format!("'{}': {}", archive.display(), err))?;
+    // Entries that would leave the directory are skipped by both formats.
+    if name.ends_with(".zip") {
+        zip::ZipArchive::new(file)
+            .and_then(|mut zip| zip.extract(dir))
+            .map_err(|err| err.to_string())
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        tar::Archive::new(GzDecoder::new(file))
+            .unpack(dir)
+            .map_err(|err| err.to_string())
+    } else if name.ends_with(".tar") {
+        tar::Archive::new(file)
+            .unpack(dir)
+            .map_err(|err| err.to_string())
+    } else {
+        Err(format!(
+            "'{}' is neither a zip nor a tar archive",
+            archive.display()
+        ))
+    }
+}
+
+fn install(
+    staging_dir: &Path,
+    patch_dir: &Path,
+    blob_dir: Option<&Path>,
+    version: Option<u16>,
+) -> Result<Vec<Imported>, String> {
+    let mut root = staging_dir.to_path_buf();
+    // Archives of a release often wrap everything in a single directory.
+    if let [entry] = entries(&root)?.as_slice() {
+        if entry.is_dir() && parse_version(entry).is_none() {
+            root = entry.clone();
+        }
+    }
+
+    let entries = entries(&root)?;
+    let version_dirs = entries
+        .iter()
+        .filter(|entry| entry.is_dir())
+        .filter_map(|entry| {
+            let version = parse_version(entry)?;
+            let checksums = Manifest::path_for(&root, version);
+            Some((
+                version,
+                entry.clone(),
+                checksums.is_file().then_some(checksums),
+            ))
+        })
+        .collect::<Vec<_>>();
+    let only_versions = entries.iter().all(|entry| {
+        (entry.is_dir() && parse_version(entry).is_some())
+            || (entry.is_file() && Manifest::is_manifest(entry))
+    });
+    let version_dirs = if only_versions && !version_dirs.is_empty() {
+        if let Some(version) = version {
+            if version_dirs.iter().any(|(found, ..)| *found != version) {
+                return Err(format!(
+                    "the archive contains other versions than {}",
+                    version
+                ));
+            }
+        }
+        version_dirs
+    } else {
+        let version = match version {
+            Some(version) => version,
+            None => next_version(patch_dir)?,
+        };
+        vec![(version, root.clone(), None)]
+    };
+
+    for (version, dir, checksums) in &version_dirs {
+        validate(*version, dir, checksums.as_deref(), patch_dir, blob_dir)?;
+    }
+
+    let mut imported = Vec::new();
+    for (version, dir, _) in version_dirs {
+        let version_dir = patch_dir.join(version.to_string());
+        fs::rename(&dir, &version_dir).map_err(|err| err.to_string())?;
+        let files = read_patch(version, &version_dir)
+            .map_err(|err| err.to_string())?
+            .files
+            .len();
+        if let Some(blob_dir) = blob_dir {
+            blob_store::ingest_one(&version_dir, version, blob_dir)
+                .map_err(|err| err.to_string())?;
+        }
+        imported.push(Imported { version, files });
+    }
+    Ok(imported)
+}
+
+/// Checks that the version can be loaded and that every listed file exists,
+/// inside the archive or the blob store, and matches the checksums, if there
+/// are any. Versions without a `manifest.toml` get one listing the checksums.
+fn validate(
+    version: u16,
+    dir: &Path,
+    checksums: Option<&Path>,
+    patch_dir: &Path,
+    blob_dir: Option<&Path>,
+) -> Result<(), String> {
+    let version_dir = patch_dir.join(version.to_string());
+    if version_dir.exists() {
+        return Err(format!("version {} already exists", version));
+    }
+
+    let patch = read_patch(version, dir).map_err(|err| format!("version {}: {}", version, err))?;
+    if patch.files.is_empty() && patch.deleted.is_empty() {
+        return Err(format!("version {} contains no files", version));
+    }
+    for file in patch.files.iter() {
+        let hash = patch
+            .manifest
+            .as_ref()
+            .and_then(|manifest| manifest.get(file)?.sha256.as_deref());
+        let stored = blob_dir
+            .zip(hash)
+            .is_some_and(|(blob_dir, hash)| blob_dir.join(hash).is_file());
+        if !stored && !dir.join(file).is_file() {
+            return Err(format!(
+                "file '{}' of version {} is missing",
+                file.display(),
+                version
+            ));
+        }
+    }
+
+    let Some(checksums) = checksums else {
+        return Ok(());
+    };
+    let checksums = Manifest::load(checksums).map_err(|err| err.to_string())?;
+    let mut listed = Vec::new();
+    for file in patch.files.iter() {
+        let expected = checksums.hash_of(file).ok_or_else(|| {
+            format!(
+                "file '{}' of version {} has no checksum",
+                file.display(),
+                version
+            )
+        })?;
+        let path = dir.join(file);
+        if !path.is_file() {
+            continue;
+        }
+        let actual = hash::sha256_of(&path).map_err(|err| err.to_string())?;
+        if actual != expected {
+            return Err(format!(
+                "checksum of '{}' of version {} doesn't match",
+                file.display(),
+                version
+            ));
+        }
+        listed.push(ListedFile {
+            path: file.clone(),
+            size: fs::metadata(&path).map_err(|err| err.to_string())?.len() as u32,
+            sha256: Some(actual),
+            in_pk2: None,
+        });
+    }
+    if patch.manifest.is_none() {
+        PatchManifest::write(dir, listed, patch.deleted.to_vec()).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+fn entries(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|err| err.to_string())?;
+    Ok(entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .collect())
+}
+
+/// The version a directory is named after, if it is, without ambiguous
+/// names like `07`.
+fn parse_version(dir: &Path) -> Option<u16> {
+    let name = dir.file_name()?.to_str()?;
+    let version = name.parse::<u16>().ok()?;
+    (version.to_string() == name).then_some(version)
+}
+
+/// The version after the latest one inside the patch directory.
+fn next_version(patch_dir: &Path) -> Result<u16, String> {
+    let latest = entries(patch_dir)?
+        .iter()
+        .filter(|entry| entry.is_dir())
+        .filter_map(|entry| parse_version(entry))
+        .max();
+    match latest {
+        Some(latest) => latest
+            .checked_add(1)
+            .ok_or_else(|| "there is no version after the latest one".to_string()),
+        None => Ok(1),
+    }
+}
+
+/// Tells the running server, whose admin API is reachable at `url`
+/// (including `/tenants/<tenant>` for other tenants), to serve an imported
+/// version.
+pub async fn register(
+    url: &str,
+    token: Option<&str>,
+    channel: &str,
+    version: u16,
+) -> Result<(), String> {
+    let mut request =
+        reqwest::Client::new().post(format!("{}/channels/{}/versions/{}", url, channel, version));
+    if let Some(token) = token {
+        request = request.header(
+            "Authorization",
+            format!("{}{}", AUTHORIZATION_PREFIX, token),
+        );
+    }
+    request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("could not register version {}: {}", version, err))?;
+    Ok(())
+}
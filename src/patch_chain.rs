@@ -0,0 +1,65 @@
+use crate::PatchProvider;
+use serde::Deserialize;
+
+/// What happens on startup if the versions of a channel don't form a proper
+/// chain, see [validate].
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChainValidation {
+    /// Logs every problem and serves the channel anyway.
+    #[default]
+    Warn,
+    /// Refuses to start the tenant of the channel.
+    Refuse,
+}
+
+/// Finds problems with the versions of the patch directory that would
+/// otherwise only change which files clients get: gaps between versions,
+/// version directories without any files and directories naming a version
+/// that already exists. Skipped versions don't count as gaps, as they're not
+/// meant to be served.
+pub fn validate(provider: &PatchProvider) -> Vec<String> {
+    let versions = provider.loaded_versions();
+    let quarantined = provider.quarantined();
+    // Versions that failed to load are reported when they're quarantined.
+    let mut known = provider.skipped();
+    known.extend(
+        quarantined
+            .iter()
+            .filter_map(|quarantined| quarantined.version),
+    );
+    let mut problems = Vec::new();
+    for pair in versions.windows(2) {
+        let missing = (pair[0] + 1..pair[1])
+            .filter(|version| !known.contains(version))
+            .collect::<Vec<_>>();
+        match missing.as_slice() {
+            [] => {}
+            [version] => problems.push(format!("Version {} is missing", version)),
+            [first, .., last] => {
+                problems.push(format!("Versions {} to {} are missing", first, last))
+            }
+        }
+    }
+
+    for version in &versions {
+        if provider.files_of(*version).is_empty() && provider.deleted_of(*version).is_empty() {
+            problems.push(format!("Version {} contains no files", version));
+        }
+    }
+
+    for quarantined in quarantined {
+        let duplicate = quarantined
+            .directory
+            .parse::<u16>()
+            .ok()
+            .filter(|version| quarantined.version.is_none() && versions.contains(version));
+        if let Some(version) = duplicate {
+            problems.push(format!(
+                "'{}' duplicates version {}",
+                quarantined.directory, version
+            ));
+        }
+    }
+    problems
+}
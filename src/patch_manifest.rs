@@ -0,0 +1,210 @@
+use crate::changelog;
+use crate::hash::Manifest;
+use crate::patch_signing;
+use crate::safe_path;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the listing inside a version directory.
+pub const FILE_NAME: &str = "manifest.toml";
+
+/// Lists files removed by a version, one per line, inside its directory.
+pub const DELETIONS_FILE_NAME: &str = "deleted.txt";
+
+/// Where a new listing is written to before replacing the old one.
+const PARTIAL_FILE_NAME: &str = "manifest.toml.partial";
+
+/// Whether the file at the top of a version directory describes the
+/// version, instead of being one of its files.
+pub fn is_listing(name: &OsStr) -> bool {
+    [
+        FILE_NAME,
+        DELETIONS_FILE_NAME,
+        PARTIAL_FILE_NAME,
+        patch_signing::FILE_NAME,
+        changelog::FILE_NAME,
+        changelog::PARTIAL_FILE_NAME,
+    ]
+    .map(OsStr::new)
+    .contains(&name)
+}
+
+/// The files of a version as listed in its `manifest.toml`, which is used
+/// instead of walking the version directory. This keeps large versions
+/// from being scanned on startup and makes their content deterministic.
+pub struct PatchManifest {
+    files: HashMap<PathBuf, ListedFile>,
+    deleted: Vec<PathBuf>,
+    evicted: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ManifestFile {
+    #[serde(default)]
+    files: Vec<ListedFile>,
+    /// Files removed by the version.
+    #[serde(default)]
+    deleted: Vec<PathBuf>,
+    /// Whether the files were moved to cold storage, see [crate::cold_storage].
+    #[serde(default, skip_serializing_if = "is_false")]
+    evicted: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ListedFile {
+    /// Relative to the version directory.
+    pub path: PathBuf,
+    pub size: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Whether the client stores the file in a pk2 archive, overriding
+    /// the default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_pk2: Option<bool>,
+}
+
+impl PatchManifest {
+    /// Reads the listing of the version directory, if it has one.
+    pub fn load(version_dir: &Path) -> io::Result<Option<PatchManifest>> {
+        let content = match fs::read_to_string(version_dir.join(FILE_NAME)) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let listing = toml::from_str::<ManifestFile>(&content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid {}: {}", FILE_NAME, err),
+            )
+        })?;
+
+        let mut files = HashMap::new();
+        for mut file in listing.files {
+            check_relative(&file.path, FILE_NAME)?;
+            // Checksums name files in blob stores, so they can't be anything else.
+            if let Some(hash) = &mut file.sha256 {
+                if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("'{}' has an invalid checksum", file.path.display()),
+                    ));
+                }
+                hash.make_ascii_lowercase();
+            }
+            files.insert(file.path.clone(), file);
+        }
+        for deleted in &listing.deleted {
+            check_relative(deleted, FILE_NAME)?;
+        }
+        Ok(Some(PatchManifest {
+            files,
+            deleted: listing.deleted,
+            evicted: listing.evicted,
+        }))
+    }
+
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.files.keys().cloned().collect()
+    }
+
+    pub fn deleted(&self) -> &[PathBuf] {
+        &self.deleted
+    }
+
+    pub fn get(&self, file: &Path) -> Option<&ListedFile> {
+        self.files.get(file)
+    }
+
+    /// Whether the files of the version are in cold storage instead of its
+    /// directory.
+    pub fn evicted(&self) -> bool {
+        self.evicted
+    }
+
+    /// Replaces the listing of the version directory.
+    pub fn write(
+        version_dir: &Path,
+        files: Vec<ListedFile>,
+        deleted: Vec<PathBuf>,
+    ) -> io::Result<()> {
+        write_listing(
+            version_dir,
+            ManifestFile {
+                files,
+                deleted,
+                evicted: false,
+            },
+        )
+    }
+
+    /// Marks the files of the version as moved to or back from cold storage.
+    pub fn set_evicted(version_dir: &Path, evicted: bool) -> io::Result<()> {
+        let content = fs::read_to_string(version_dir.join(FILE_NAME))?;
+        let mut listing = toml::from_str::<ManifestFile>(&content)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        listing.evicted = evicted;
+        write_listing(version_dir, listing)
+    }
+
+    /// The listed checksums, if there are any.
+    pub fn checksums(&self) -> Option<Manifest> {
+        let hashes = self
+            .files
+            .values()
+            .filter_map(|file| Some((file.path.clone(), file.sha256.clone()?)))
+            .collect::<HashMap<_, _>>();
+        (!hashes.is_empty()).then(|| Manifest::from_hashes(hashes))
+    }
+}
+
+fn write_listing(version_dir: &Path, mut listing: ManifestFile) -> io::Result<()> {
+    listing.files.sort_by(|a, b| a.path.cmp(&b.path));
+    let content = toml::to_string(&listing).map_err(io::Error::other)?;
+    // Written next to it first, so a crash never leaves half a listing.
+    let partial = version_dir.join(PARTIAL_FILE_NAME);
+    fs::write(&partial, content)?;
+    fs::rename(partial, version_dir.join(FILE_NAME))
+}
+
+/// Reads the files removed by the version from its `deleted.txt`, if it has
+/// one. Empty lines and lines starting with `#` are ignored.
+pub fn load_deletions(version_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let content = match fs::read_to_string(version_dir.join(DELETIONS_FILE_NAME)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let deleted = PathBuf::from(line.strip_prefix("./").unwrap_or(line));
+            check_relative(&deleted, DELETIONS_FILE_NAME)?;
+            Ok(deleted)
+        })
+        .collect()
+}
+
+fn check_relative(path: &Path, listed_in: &str) -> io::Result<()> {
+    if safe_path::is_relative(path) {
+        return Ok(());
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "'{}' in {} is not a relative path",
+            path.display(),
+            listed_in
+        ),
+    ))
+}
@@ -0,0 +1,99 @@
+use crate::hash::Manifest;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The signature of a version, as hex inside its directory.
+pub const FILE_NAME: &str = "manifest.sig";
+
+/// A public Ed25519 key that versions have to be signed with. Versions with
+/// a missing or invalid signature are quarantined, so files swapped on the
+/// server are never served.
+#[derive(Clone)]
+pub struct PublicKey {
+    key: Vec<u8>,
+}
+
+impl PublicKey {
+    /// Parses the key from hex, as printed by `generate-key`.
+    pub fn parse(key: &str) -> Result<PublicKey, String> {
+        let key = from_hex(key.trim()).ok_or_else(|| "the public key is not hex".to_string())?;
+        if key.len() != 32 {
+            return Err(format!(
+                "the public key has {} instead of 32 bytes",
+                key.len()
+            ));
+        }
+        Ok(PublicKey { key })
+    }
+
+    pub fn verify(
+        &self,
+        version_dir: &Path,
+        version: u16,
+        checksums: &BTreeMap<PathBuf, String>,
+        deleted: &[PathBuf],
+    ) -> Result<(), String> {
+        let signature = fs::read_to_string(version_dir.join(FILE_NAME))
+            .map_err(|err| format!("the signature is unavailable: {}", err))?;
+        let signature =
+            from_hex(signature.trim()).ok_or_else(|| "the signature is not hex".to_string())?;
+        UnparsedPublicKey::new(&ED25519, &self.key)
+            .verify(message(version, checksums, deleted).as_bytes(), &signature)
+            .map_err(|_| "the signature doesn't match its files".to_string())
+    }
+}
+
+/// Writes a new private key to the file, returning its public key as hex.
+pub fn generate_key(path: &Path) -> Result<String, String> {
+    let pkcs8 =
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|err| err.to_string())?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|err| err.to_string())?;
+    fs::write(path, pkcs8.as_ref()).map_err(|err| err.to_string())?;
+    Ok(to_hex(key_pair.public_key().as_ref()))
+}
+
+/// Signs the files of the version with the private key inside the file.
+pub fn sign(
+    key: &Path,
+    version_dir: &Path,
+    version: u16,
+    checksums: &BTreeMap<PathBuf, String>,
+    deleted: &[PathBuf],
+) -> Result<(), String> {
+    let pkcs8 = fs::read(key).map_err(|err| format!("'{}': {}", key.display(), err))?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+        .map_err(|err| format!("'{}': {}", key.display(), err))?;
+    let signature = key_pair.sign(message(version, checksums, deleted).as_bytes());
+    fs::write(version_dir.join(FILE_NAME), to_hex(signature.as_ref()))
+        .map_err(|err| err.to_string())
+}
+
+/// What is signed: the version, the checksums of its files in the format of
+/// `sha256sum` and the files it deletes, so neither files nor whole versions
+/// can be swapped.
+fn message(version: u16, checksums: &BTreeMap<PathBuf, String>, deleted: &[PathBuf]) -> String {
+    let checksums = Manifest::from_hashes(checksums.clone().into_iter().collect());
+    let mut message = format!("version {}\n{}", version, checksums.render());
+    for file in deleted {
+        message.push_str(&format!("deleted {}\n", file.display()));
+    }
+    message
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect()
+}
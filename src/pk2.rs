@@ -0,0 +1,173 @@
+use crate::safe_path;
+use blowfish::cipher::generic_array::GenericArray;
+use blowfish::cipher::{BlockDecrypt, KeyInit};
+use blowfish::BlowfishLE;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// The archives of the client that files inside them are patched into, by
+/// the first directory of their path, e.g. `Media/itemdata.txt` into
+/// `Media.pk2`. Files in other directories are stored as they are.
+pub const ARCHIVES: [&str; 5] = ["Data", "Map", "Media", "Music", "Particles"];
+
+/// The key every client archive is encrypted with, before being salted.
+const KEY: &[u8] = b"169841";
+const SALT: [u8; 10] = [0x03, 0xF8, 0xE4, 0x44, 0x88, 0x99, 0x3F, 0x64, 0xFE, 0x35];
+
+const SIGNATURE: &[u8] = b"JoyMax File Manager!\n";
+const HEADER_SIZE: u64 = 256;
+const ENTRY_SIZE: usize = 128;
+const ENTRIES_PER_BLOCK: usize = 20;
+
+const ENTRY_DIRECTORY: u8 = 1;
+const ENTRY_FILE: u8 = 2;
+
+/// The archive a file of a patch is stored in, if any. Archive names are
+/// matched case-insensitively, like the client does.
+pub fn archive_of(file: &Path) -> Option<&'static str> {
+    let mut components = file.components();
+    let Some(Component::Normal(first)) = components.next() else {
+        return None;
+    };
+    components.next()?;
+    let first = first.to_str()?;
+    ARCHIVES
+        .into_iter()
+        .find(|archive| archive.eq_ignore_ascii_case(first))
+}
+
+/// A file inside an archive.
+pub struct Pk2File {
+    /// Relative to the root of the archive.
+    pub path: PathBuf,
+    pub size: u32,
+    offset: u64,
+}
+
+/// A pk2 archive of the client, opened to read its files. The directory
+/// entries of the archive are stored in blocks of 20, encrypted with
+/// Blowfish, while the content of its files is stored as it is.
+pub struct Pk2Archive {
+    file: File,
+    cipher: Option<BlowfishLE>,
+    files: Vec<Pk2File>,
+}
+
+impl Pk2Archive {
+    pub fn open(path: &Path) -> io::Result<Pk2Archive> {
+        let mut file = File::open(path)?;
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact(&mut header)?;
+        if !header.starts_with(SIGNATURE) {
+            return Err(invalid(format!(
+                "'{}' is not a pk2 archive",
+                path.display()
+            )));
+        }
+
+        let encrypted = header[34] != 0;
+        let cipher = encrypted.then(|| {
+            let key = KEY
+                .iter()
+                .zip(SALT)
+                .map(|(byte, salt)| byte ^ salt)
+                .collect::<Vec<_>>();
+            BlowfishLE::new_from_slice(&key).unwrap()
+        });
+        let mut archive = Pk2Archive {
+            file,
+            cipher,
+            files: Vec::new(),
+        };
+        let mut visited = HashSet::new();
+        archive.read_directory(HEADER_SIZE, PathBuf::new(), &mut visited)?;
+        Ok(archive)
+    }
+
+    pub fn files(&self) -> &[Pk2File] {
+        &self.files
+    }
+
+    /// Reads the content of the file at the index of [Self::files].
+    pub fn read(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let file = &self.files[index];
+        let mut content = vec![0; file.size as usize];
+        self.file.seek(SeekFrom::Start(file.offset))?;
+        self.file.read_exact(&mut content)?;
+        Ok(content)
+    }
+
+    /// Collects the files of the directory whose entries start at the
+    /// offset, and of its subdirectories.
+    fn read_directory(
+        &mut self,
+        mut offset: u64,
+        directory: PathBuf,
+        visited: &mut HashSet<u64>,
+    ) -> io::Result<()> {
+        let mut subdirectories = Vec::new();
+        loop {
+            // Corrupted archives could otherwise point back at a block that
+            // was already read.
+            if !visited.insert(offset) {
+                return Err(invalid(format!("block at {} is referenced twice", offset)));
+            }
+
+            let block = self.read_block(offset)?;
+            for entry in block.chunks_exact(ENTRY_SIZE) {
+                let name = &entry[1..82];
+                let name = &name[..name.iter().position(|byte| *byte == 0).unwrap_or(81)];
+                let name = String::from_utf8_lossy(name).to_string();
+                let position = u64::from_le_bytes(entry[106..114].try_into().unwrap());
+                if entry[0] == ENTRY_DIRECTORY && (name == "." || name == "..") {
+                    continue;
+                }
+                // Paths inside the archive are written to disk, so names may
+                // not leave their directory.
+                let valid = safe_path::is_relative(Path::new(&name)) && !name.contains(['/', '\\']);
+                if matches!(entry[0], ENTRY_DIRECTORY | ENTRY_FILE) && !valid {
+                    return Err(invalid(format!("'{}' is not a valid name", name)));
+                }
+                match entry[0] {
+                    ENTRY_DIRECTORY => subdirectories.push((directory.join(name), position)),
+                    ENTRY_FILE => self.files.push(Pk2File {
+                        path: directory.join(name),
+                        size: u32::from_le_bytes(entry[114..118].try_into().unwrap()),
+                        offset: position,
+                    }),
+                    _ => {}
+                }
+            }
+
+            // Only the last entry of a block points to the next one.
+            let last = &block[(ENTRIES_PER_BLOCK - 1) * ENTRY_SIZE..];
+            match u64::from_le_bytes(last[118..126].try_into().unwrap()) {
+                0 => break,
+                next => offset = next,
+            }
+        }
+
+        for (subdirectory, position) in subdirectories {
+            self.read_directory(position, subdirectory, visited)?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&mut self, offset: u64) -> io::Result<Vec<u8>> {
+        let mut block = vec![0; ENTRY_SIZE * ENTRIES_PER_BLOCK];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut block)?;
+        if let Some(cipher) = &self.cipher {
+            for chunk in block.chunks_exact_mut(8) {
+                cipher.decrypt_block(GenericArray::from_mut_slice(chunk));
+            }
+        }
+        Ok(block)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,53 @@
+use axum::extract::Query;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use std::thread;
+use std::time::Duration;
+
+/// Samples per second, slightly off from 100 to not run in lockstep with
+/// periodic work.
+const SAMPLE_FREQUENCY: i32 = 99;
+
+/// Keeps a forgotten request from profiling the server for hours.
+const MAX_SECONDS: u64 = 120;
+
+#[derive(Deserialize)]
+pub struct ProfileParameters {
+    #[serde(default = "default_seconds")]
+    seconds: u64,
+}
+
+fn default_seconds() -> u64 {
+    10
+}
+
+/// Samples the CPU of the whole server for the requested number of seconds
+/// and answers with a flamegraph of it.
+pub async fn cpu_profile(
+    Query(parameters): Query<ProfileParameters>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let duration = Duration::from_secs(parameters.seconds.clamp(1, MAX_SECONDS));
+    let flamegraph = tokio::task::spawn_blocking(move || {
+        // Only one profile can be taken at a time.
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(SAMPLE_FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|err| (StatusCode::CONFLICT, err.to_string()))?;
+        thread::sleep(duration);
+        let report = guard
+            .report()
+            .build()
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let mut flamegraph = Vec::new();
+        report
+            .flamegraph(&mut flamegraph)
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        Ok::<_, (StatusCode, String)>(flamegraph)
+    })
+    .await
+    .unwrap()?;
+    Ok(([(CONTENT_TYPE, "image/svg+xml")], flamegraph))
+}
@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the states of the versions of a channel are kept, next to its
+/// manifests.
+pub const FILE_NAME: &str = "states.toml";
+
+/// Where new states are written to before replacing the old ones.
+const PARTIAL_FILE_NAME: &str = "states.toml.partial";
+
+/// Whether the file inside the patch directory holds the states, which is
+/// where they're kept without a state directory.
+pub fn is_state_file(name: &OsStr) -> bool {
+    name == FILE_NAME || name == PARTIAL_FILE_NAME
+}
+
+/// Whether a version is served. New versions of channels with
+/// `stage_new_versions` start out staged and are only served to canaries,
+/// until they're promoted through the admin API.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PatchState {
+    Staged,
+    Live,
+    /// No longer served, like a skipped version.
+    Retired,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct StateFile {
+    #[serde(default)]
+    live: BTreeSet<u16>,
+    #[serde(default)]
+    staged: BTreeSet<u16>,
+    #[serde(default)]
+    retired: BTreeSet<u16>,
+}
+
+/// The states of the versions of a channel.
+#[derive(Clone, Default)]
+pub struct PatchStates {
+    /// Where changes are written to, if anywhere.
+    path: Option<PathBuf>,
+    /// Whether versions without a state are staged instead of live.
+    stage_new: bool,
+    states: BTreeMap<u16, PatchState>,
+}
+
+impl PatchStates {
+    /// Reads the states from the directory. Without a state file, the given
+    /// versions are recorded as live if new versions are staged, so only
+    /// versions added afterwards are staged.
+    pub fn load(dir: &Path, stage_new: bool, versions: &[u16]) -> io::Result<PatchStates> {
+        let path = dir.join(FILE_NAME);
+        let mut states = PatchStates {
+            path: Some(path.clone()),
+            stage_new,
+            states: BTreeMap::new(),
+        };
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                let file = toml::from_str::<StateFile>(&content)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                let listed = [
+                    (file.live, PatchState::Live),
+                    (file.staged, PatchState::Staged),
+                    (file.retired, PatchState::Retired),
+                ];
+                for (versions, state) in listed {
+                    states
+                        .states
+                        .extend(versions.into_iter().map(|version| (version, state)));
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound && stage_new => {
+                states
+                    .states
+                    .extend(versions.iter().map(|version| (*version, PatchState::Live)));
+                states.save()?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(states)
+    }
+
+    pub fn state_of(&self, version: u16) -> PatchState {
+        match self.states.get(&version) {
+            Some(state) => *state,
+            None if self.stage_new => PatchState::Staged,
+            None => PatchState::Live,
+        }
+    }
+
+    /// The versions with the state, out of those given.
+    pub fn filter(&self, versions: &[u16], state: PatchState) -> Vec<u16> {
+        versions
+            .iter()
+            .copied()
+            .filter(|version| self.state_of(*version) == state)
+            .collect()
+    }
+
+    pub fn set(&mut self, version: u16, state: PatchState) -> io::Result<()> {
+        self.states.insert(version, state);
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut file = StateFile::default();
+        for (version, state) in &self.states {
+            let versions = match state {
+                PatchState::Live => &mut file.live,
+                PatchState::Staged => &mut file.staged,
+                PatchState::Retired => &mut file.retired,
+            };
+            versions.insert(*version);
+        }
+        let content = toml::to_string(&file).map_err(io::Error::other)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Written next to it first, so a crash never leaves half a file.
+        let partial = path.with_file_name(PARTIAL_FILE_NAME);
+        fs::write(&partial, content)?;
+        fs::rename(partial, path)
+    }
+}
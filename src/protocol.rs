@@ -1,7 +1,10 @@
+use crate::config::RequestFormat;
 use chrono::{DateTime, Utc};
 use skrillax_packet::Packet;
 use skrillax_protocol::define_inbound_protocol;
 use skrillax_serde::{ByteSize, Deserialize, Serialize};
+use skrillax_stream::stream::InStreamError;
+use skrillax_stream::InputProtocol;
 
 #[derive(Clone, Copy, Serialize, Deserialize, ByteSize, Packet, Debug)]
 #[packet(opcode = 0x2002)]
@@ -36,6 +39,110 @@ pub struct GatewayNotice {
 
 type NormalDateTime = DateTime<Utc>;
 
+/// The notice layout of older clients, which lack the published date.
+#[derive(Clone, Serialize, Deserialize, ByteSize, Packet, Debug)]
+#[packet(opcode = 0xA104, massive = true)]
+pub struct LegacyGatewayNoticeResponse {
+    #[silkroad(list_type = "length")]
+    pub notices: Vec<LegacyGatewayNotice>,
+}
+
+#[derive(Clone, Deserialize, Serialize, ByteSize, Debug)]
+pub struct LegacyGatewayNotice {
+    pub subject: String,
+    pub article: String,
+}
+
+impl From<GatewayNotice> for LegacyGatewayNotice {
+    fn from(notice: GatewayNotice) -> Self {
+        LegacyGatewayNotice {
+            subject: notice.subject,
+            article: notice.article,
+        }
+    }
+}
+
+/// Sent by launchers probing for the servers behind the gateway.
+#[derive(Clone, Copy, Serialize, Deserialize, ByteSize, Packet, Debug)]
+#[packet(opcode = 0x6101)]
+pub struct ShardListRequest;
+
+#[derive(Clone, Serialize, Deserialize, ByteSize, Packet, Debug)]
+#[packet(opcode = 0xA101)]
+pub struct ShardListResponse {
+    #[silkroad(list_type = "has-more")]
+    pub farms: Vec<Farm>,
+    #[silkroad(list_type = "has-more")]
+    pub shards: Vec<Shard>,
+}
+
+#[derive(Clone, Deserialize, Serialize, ByteSize, Debug)]
+pub struct Farm {
+    pub id: u8,
+    pub name: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, ByteSize, Debug)]
+pub struct Shard {
+    pub id: u16,
+    pub name: String,
+    pub online: u16,
+    pub capacity: u16,
+    pub operating: bool,
+    pub farm_id: u8,
+}
+
+/// Sent by clients that proceed past patching, expecting a full gateway.
+#[derive(Clone, Serialize, Deserialize, ByteSize, Packet, Debug)]
+#[packet(opcode = 0x6102)]
+pub struct LoginRequest {
+    pub locale: u8,
+    pub username: String,
+    pub password: String,
+    pub shard_id: u16,
+}
+
+impl LoginRequest {
+    /// A copy without the credentials, to keep them out of dumps and
+    /// transcripts.
+    pub fn redacted(&self) -> LoginRequest {
+        LoginRequest {
+            username: String::new(),
+            password: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, ByteSize, Packet, Debug)]
+#[packet(opcode = 0xA102)]
+pub struct LoginResponse {
+    pub result: LoginResult,
+}
+
+#[derive(Clone, Deserialize, Serialize, ByteSize, Debug)]
+pub enum LoginResult {
+    #[silkroad(value = 2)]
+    Error { error: LoginError },
+}
+
+#[derive(Clone, Deserialize, Serialize, ByteSize, Debug)]
+pub enum LoginError {
+    /// Shows the reason in a message box, which makes it the only error
+    /// with a message of our choosing.
+    #[silkroad(value = 2)]
+    Blocked { block: LoginBlock },
+}
+
+#[derive(Clone, Deserialize, Serialize, ByteSize, Debug)]
+pub enum LoginBlock {
+    #[silkroad(value = 1)]
+    Punishment {
+        reason: String,
+        until: NormalDateTime,
+    },
+}
+
 #[derive(Clone, Serialize, Deserialize, ByteSize, Packet, Debug)]
 #[packet(opcode = 0x6100)]
 pub struct PatchRequest {
@@ -44,6 +151,41 @@ pub struct PatchRequest {
     pub version: u32,
 }
 
+impl PatchRequest {
+    /// Reads a patch request sent in the given layout.
+    pub fn decode(data: &[u8], format: RequestFormat) -> Result<PatchRequest, InStreamError> {
+        match format {
+            RequestFormat::Standard => Ok(PatchRequest::create_from(Self::ID, data)?.1),
+            RequestFormat::Legacy => Ok(LegacyPatchRequest::create_from(Self::ID, data)?.1.into()),
+            // Standard requests are two bytes longer, so only legacy ones
+            // are read completely in the legacy layout.
+            RequestFormat::Auto => match LegacyPatchRequest::create_from(Self::ID, data) {
+                Ok((consumed, request)) if consumed == data.len() => Ok(request.into()),
+                _ => Self::decode(data, RequestFormat::Standard),
+            },
+        }
+    }
+}
+
+/// The patch request of older clients, which only send a 16 bit version.
+#[derive(Clone, Serialize, Deserialize, ByteSize, Packet, Debug)]
+#[packet(opcode = 0x6100)]
+pub struct LegacyPatchRequest {
+    pub content: u8,
+    pub module: String,
+    pub version: u16,
+}
+
+impl From<LegacyPatchRequest> for PatchRequest {
+    fn from(request: LegacyPatchRequest) -> Self {
+        PatchRequest {
+            content: request.content,
+            module: request.module,
+            version: u32::from(request.version),
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, ByteSize, Packet, Debug)]
 #[packet(opcode = 0xA100, massive = true)]
 pub struct PatchResponse {
@@ -79,6 +221,12 @@ pub enum PatchError {
     PatchDisabled,
 }
 
+impl PatchError {
+    pub fn is_update(&self) -> bool {
+        matches!(self, PatchError::Update { .. })
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize, ByteSize, Debug)]
 pub struct PatchFile {
     pub file_id: u32,
@@ -92,5 +240,15 @@ define_inbound_protocol! { PatchProtocol =>
     KeepAlive,
     PatchRequest,
     IdentityInformation,
-    GatewayNoticeRequest
+    GatewayNoticeRequest,
+    ShardListRequest,
+    LoginRequest
+}
+
+// Packets a client receives from the server, see `client::PatchClient`.
+define_inbound_protocol! { PatchServerProtocol =>
+    KeepAlive,
+    IdentityInformation,
+    PatchResponse,
+    GatewayNoticeResponse
 }
@@ -0,0 +1,92 @@
+use crate::channel::Channel;
+use crate::config::{self, Config};
+use crate::hash::{self, Manifest};
+use crate::PatchFileserver;
+use std::path::{Path, PathBuf};
+
+/// How a served file differs from the reference client.
+enum Deviation {
+    Modified,
+    Unknown,
+    Unreadable(String),
+}
+
+/// Compares the files every channel serves at its target with the checksums
+/// of a known-good client, in the format written by `sha256sum`. Returns
+/// whether all served files match the reference.
+pub fn compare(config: &Config, reference: &Path) -> bool {
+    let reference = Manifest::load(reference).expect("Reference hashes should be readable");
+    let mut channels = Vec::new();
+    for channel_config in config
+        .channels
+        .iter()
+        .chain(config.tenants.iter().flat_map(|tenant| &tenant.channels))
+    {
+        let channel = Channel::load(
+            channel_config.name.clone(),
+            channel_config.patch_dir.clone(),
+            PatchFileserver::default(),
+        )
+        .with_target(channel_config.target)
+        .with_blob_dir(channel_config.blob_dir.clone());
+        for version in &channel_config.skipped_versions {
+            channel.provider().skip(*version);
+        }
+        channels.push(channel);
+    }
+    if channels.is_empty() {
+        channels.push(Channel::load(
+            "default".to_string(),
+            config::default_patch_dir(),
+            PatchFileserver::default(),
+        ));
+    }
+
+    let mut matches = true;
+    for channel in channels {
+        let Some(target) = channel.default_target() else {
+            println!("Channel '{}' contains no patches", channel.name());
+            continue;
+        };
+
+        let provider = channel.provider();
+        let files = provider.collect_necessary_files(0, target);
+        let mut deviations = files
+            .iter()
+            .filter_map(|file| {
+                let path = provider.local_path(file);
+                let deviation = match (reference.hash_of(&file.file), hash::sha256_of(&path)) {
+                    (_, Err(err)) => Deviation::Unreadable(err.to_string()),
+                    (None, Ok(_)) => Deviation::Unknown,
+                    (Some(expected), Ok(actual)) if expected != actual => Deviation::Modified,
+                    (Some(_), Ok(_)) => return None,
+                };
+                Some((&file.file, file.patch, deviation))
+            })
+            .collect::<Vec<(&PathBuf, u16, Deviation)>>();
+        deviations.sort_by_key(|(file, ..)| *file);
+
+        println!(
+            "Channel '{}' (version {}): {} of {} files deviate from the reference",
+            channel.name(),
+            target,
+            deviations.len(),
+            files.len()
+        );
+        for (file, version, deviation) in &deviations {
+            let description = match deviation {
+                Deviation::Modified => "checksum differs".to_string(),
+                Deviation::Unknown => "not part of the reference".to_string(),
+                Deviation::Unreadable(err) => format!("unreadable: {}", err),
+            };
+            println!(
+                "  {} (version {}): {}",
+                file.display(),
+                version,
+                description
+            );
+        }
+        matches &= deviations.is_empty();
+    }
+    matches
+}
@@ -0,0 +1,38 @@
+use maxminddb::{geoip2, Reader};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// A group of clients that is patched to its own target version. Clients
+/// belong to a region if they connect through one of its ports or, when a
+/// GeoIP database is configured, are located in one of its countries.
+#[derive(Deserialize, Clone)]
+pub struct Region {
+    pub name: String,
+    pub target: u16,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// ISO country codes, e.g. `DE` or `KR`.
+    #[serde(default)]
+    pub countries: Vec<String>,
+}
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> GeoIp {
+        GeoIp {
+            reader: Reader::open_readfile(path).expect("GeoIP database should be readable"),
+        }
+    }
+
+    pub fn country_of(&self, address: IpAddr) -> Option<String> {
+        let country: geoip2::Country = self.reader.lookup(address).ok()?;
+        country
+            .country?
+            .iso_code
+            .map(|iso_code| iso_code.to_string())
+    }
+}
@@ -0,0 +1,177 @@
+use crate::blob_store;
+use crate::changelog;
+use crate::hash::{self, Manifest};
+use crate::patch_manifest;
+use crate::patch_signing;
+use crate::safe_path::is_relative;
+use crate::{collect_files_recursively, read_patch, PatchProvider};
+use log::info;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Precedes the token secondaries require in the authorization header.
+pub const AUTHORIZATION_PREFIX: &str = "Bearer ";
+
+/// Where files pushed by a primary are kept until their version is activated.
+fn staging_dir(provider: &PatchProvider, version: u16) -> PathBuf {
+    provider.patch_dir().join(format!(".{}.replica", version))
+}
+
+/// Stores a file pushed by a primary for a version that isn't served yet.
+pub fn stage_file(
+    provider: &PatchProvider,
+    version: u16,
+    file: &Path,
+    content: &[u8],
+) -> Result<(), String> {
+    if provider.patch_dir().join(version.to_string()).exists() {
+        return Err(format!("version {} already exists", version));
+    }
+    if !is_relative(file) {
+        return Err(format!("refusing to write file '{}'", file.display()));
+    }
+
+    let path = staging_dir(provider, version).join(file);
+    fs::create_dir_all(path.parent().unwrap()).map_err(|err| err.to_string())?;
+    fs::write(path, content).map_err(|err| err.to_string())
+}
+
+/// Checks the staged files of the version against the manifest sent by the
+/// primary and, only if all of them match, moves them into place and serves
+/// the version.
+pub fn activate(provider: &PatchProvider, version: u16, manifest: &str) -> Result<usize, String> {
+    let staging_dir = staging_dir(provider, version);
+    let version_dir = provider.patch_dir().join(version.to_string());
+    if version_dir.exists() {
+        return Err(format!("version {} already exists", version));
+    }
+
+    let manifest = Manifest::parse(manifest);
+    let expected = manifest.files().collect::<BTreeSet<_>>();
+    let staged = collect_files_recursively(&staging_dir).map_err(|err| err.to_string())?;
+    let staged = staged.iter().map(PathBuf::as_path).collect::<BTreeSet<_>>();
+    if let Some(missing) = expected.difference(&staged).next() {
+        return Err(format!("file '{}' is missing", missing.display()));
+    }
+    if let Some(unexpected) = staged.difference(&expected).next() {
+        return Err(format!(
+            "file '{}' is not part of the manifest",
+            unexpected.display()
+        ));
+    }
+    for file in &expected {
+        let actual = hash::sha256_of(&staging_dir.join(file)).map_err(|err| err.to_string())?;
+        if manifest.hash_of(file) != Some(actual.as_str()) {
+            return Err(format!("checksum of '{}' doesn't match", file.display()));
+        }
+    }
+
+    fs::write(
+        Manifest::path_for(&provider.manifest_dir(), version),
+        manifest.render(),
+    )
+    .map_err(|err| err.to_string())?;
+    fs::rename(&staging_dir, &version_dir).map_err(|err| err.to_string())?;
+    if let Some(blob_dir) = provider.blob_dir() {
+        blob_store::ingest_one(&version_dir, version, &blob_dir).map_err(|err| err.to_string())?;
+    }
+    let patch = read_patch(version, &version_dir).map_err(|err| err.to_string())?;
+    let count = patch.files.len();
+    provider.add_patch(patch);
+    provider.quarantine_unsigned(version)?;
+    Ok(count)
+}
+
+/// Pushes a version of the patch directory to a secondary, whose admin API
+/// is reachable at `url` (including `/tenants/<tenant>` for other tenants).
+pub async fn push(
+    patch_dir: &Path,
+    blob_dir: Option<&Path>,
+    channel: &str,
+    version: u16,
+    url: &str,
+    token: &str,
+) -> Result<(), String> {
+    let patch =
+        read_patch(version, &patch_dir.join(version.to_string())).map_err(|err| err.to_string())?;
+    let local_path = |file: &Path| {
+        let (dir, relative) = blob_store::locate(
+            patch_dir,
+            blob_dir,
+            version,
+            patch.manifest.as_deref(),
+            file,
+        );
+        dir.join(relative)
+    };
+    let hashes = patch
+        .files
+        .iter()
+        .map(|file| Ok((file.clone(), hash::sha256_of(&local_path(file))?)))
+        .collect::<io::Result<_>>()
+        .map_err(|err| err.to_string())?;
+    let manifest = Manifest::from_hashes(hashes);
+    let base_url = format!(
+        "{}/channels/{}/replication/{}",
+        url.trim_end_matches('/'),
+        channel,
+        version
+    );
+
+    let mut pushed = patch
+        .files
+        .iter()
+        .map(|file| (file.clone(), local_path(file)))
+        .collect::<Vec<_>>();
+    // The listings describing the version, such as its deleted files and
+    // changelog. Secondaries with a public key only serve the version with
+    // its signature.
+    let listings = [
+        patch_manifest::FILE_NAME,
+        patch_manifest::DELETIONS_FILE_NAME,
+        changelog::FILE_NAME,
+        patch_signing::FILE_NAME,
+    ];
+    for listing in listings {
+        let path = patch_dir.join(version.to_string()).join(listing);
+        if path.is_file() {
+            pushed.push((PathBuf::from(listing), path));
+        }
+    }
+
+    let client = reqwest::Client::new();
+    for (file, path) in &pushed {
+        let content = tokio::fs::read(path).await.map_err(|err| err.to_string())?;
+        client
+            .put(format!(
+                "{}/files/{}",
+                base_url,
+                file.to_str().unwrap().replace('\\', "/")
+            ))
+            .header(
+                "Authorization",
+                format!("{}{}", AUTHORIZATION_PREFIX, token),
+            )
+            .body(content)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("could not push '{}': {}", file.display(), err))?;
+        info!("Pushed '{}'", file.display());
+    }
+
+    client
+        .post(&base_url)
+        .header(
+            "Authorization",
+            format!("{}{}", AUTHORIZATION_PREFIX, token),
+        )
+        .body(manifest.render())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("could not activate version {}: {}", version, err))?;
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use crate::channel::Channel;
+use crate::promotion::PatchState;
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::{task, time};
+use tokio_util::sync::CancellationToken;
+
+/// Regularly retires versions of a channel that are no longer needed,
+/// keeping the latest `keep_versions` versions and those added within
+/// `keep_for`. Only versions up to `min_supported_version` are ever pruned,
+/// as clients on it need every version after it to update.
+#[derive(Deserialize, Clone)]
+pub struct RetentionConfig {
+    pub min_supported_version: u16,
+    #[serde(default)]
+    pub keep_versions: Option<usize>,
+    /// Versions whose directory was modified more recently are kept.
+    #[serde(default, with = "humantime_serde")]
+    pub keep_for: Option<Duration>,
+    /// Deletes the directories of pruned versions instead of only retiring
+    /// them. Files in the blob store are kept, as other versions may share
+    /// them.
+    #[serde(default)]
+    pub delete: bool,
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+pub async fn prune_periodically(
+    channel: Arc<Channel>,
+    config: RetentionConfig,
+    range_targets: BTreeSet<u16>,
+    cancel_token: CancellationToken,
+) {
+    let mut interval = time::interval(config.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = cancel_token.cancelled() => return,
+        }
+
+        let pruned_channel = Arc::clone(&channel);
+        let pruned_config = config.clone();
+        let pruned_range_targets = range_targets.clone();
+        let result = task::spawn_blocking(move || {
+            prune(&pruned_channel, &pruned_config, &pruned_range_targets)
+        })
+        .await
+        .unwrap();
+        if let Err(err) = result {
+            warn!(
+                "Could not prune old versions of channel '{}': {}",
+                channel.name(),
+                err
+            );
+        }
+    }
+}
+
+/// Retires, and deletes if configured, the versions outside the retention
+/// of the channel. Versions that are targeted in any way are always kept,
+/// including those version ranges of listeners send clients to.
+fn prune(
+    channel: &Channel,
+    config: &RetentionConfig,
+    range_targets: &BTreeSet<u16>,
+) -> Result<(), String> {
+    let provider = channel.provider();
+    let versions = provider.versions();
+    let mut targeted = channel.targeted_versions();
+    targeted.extend(range_targets);
+
+    let now = SystemTime::now();
+    for (index, version) in versions.iter().copied().enumerate() {
+        if version > config.min_supported_version || targeted.contains(&version) {
+            continue;
+        }
+        if config
+            .keep_versions
+            .is_some_and(|keep| index + keep >= versions.len())
+        {
+            continue;
+        }
+        if let Some(keep_for) = config.keep_for {
+            let version_dir = provider.patch_dir().join(version.to_string());
+            let modified = fs::metadata(&version_dir)
+                .and_then(|metadata| metadata.modified())
+                .map_err(|err| format!("'{}': {}", version_dir.display(), err))?;
+            if now.duration_since(modified).unwrap_or_default() < keep_for {
+                continue;
+            }
+        }
+
+        provider.set_state(version, PatchState::Retired)?;
+        if config.delete {
+            provider
+                .delete_version(version)
+                .map_err(|err| format!("could not delete version {}: {}", version, err))?;
+        }
+        info!(
+            "{} version {} of channel '{}'",
+            if config.delete { "Deleted" } else { "Retired" },
+            version,
+            channel.name()
+        );
+    }
+    Ok(())
+}
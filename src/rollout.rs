@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+/// Serves `version` to a fixed share of the clients instead of the regular
+/// target. Clients are assigned to a bucket based on their IP, so the same
+/// client always ends up with the same version for a given percentage.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Rollout {
+    pub version: u16,
+    pub percentage: u8,
+}
+
+impl Rollout {
+    pub fn applies_to(&self, ip: IpAddr) -> bool {
+        client_bucket(ip) < self.percentage
+    }
+}
+
+/// Maps the client address onto one of 100 buckets. SHA-256 keeps the
+/// buckets stable across restarts and builds, unlike the hasher of std.
+pub fn client_bucket(ip: IpAddr) -> u8 {
+    let digest = Sha256::digest(ip.to_string());
+    let prefix = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    (prefix % 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENTS: u32 = 2000;
+
+    fn clients() -> impl Iterator<Item = IpAddr> {
+        (0..CLIENTS).map(|index| IpAddr::from(Ipv4Addr::from(0x0a00_0000 + index)))
+    }
+
+    #[test]
+    fn rollouts_reach_about_their_percentage_of_clients() {
+        for percentage in [0, 10, 50, 100] {
+            let rollout = Rollout {
+                version: 31,
+                percentage,
+            };
+            let reached = clients()
+                .filter(|client| rollout.applies_to(*client))
+                .count();
+            let expected = CLIENTS as usize * usize::from(percentage) / 100;
+            assert!(
+                reached.abs_diff(expected) <= CLIENTS as usize * 3 / 100,
+                "{}% reached {} clients",
+                percentage,
+                reached
+            );
+        }
+    }
+
+    #[test]
+    fn widening_a_rollout_keeps_the_clients_it_already_reached() {
+        let narrow = Rollout {
+            version: 31,
+            percentage: 10,
+        };
+        let wide = Rollout {
+            version: 31,
+            percentage: 40,
+        };
+        for client in clients() {
+            assert!(client_bucket(client) < 100);
+            assert!(!narrow.applies_to(client) || wide.applies_to(client));
+        }
+    }
+}
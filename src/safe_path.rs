@@ -0,0 +1,203 @@
+use std::path::{Component, Path, PathBuf};
+use std::{fs, io};
+
+/// A directory that paths, including the targets of symlinks, have to stay
+/// inside of.
+pub struct Root {
+    dir: PathBuf,
+}
+
+impl Root {
+    pub fn new(dir: &Path) -> io::Result<Root> {
+        Ok(Root {
+            dir: fs::canonicalize(dir)?,
+        })
+    }
+
+    /// Resolves the path relative to the root, failing if it or any symlink
+    /// along the way leads outside of the root.
+    pub fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        if !is_relative(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("'{}' is not a relative path", path.display()),
+            ));
+        }
+
+        let resolved = fs::canonicalize(self.dir.join(path))?;
+        if !resolved.starts_with(&self.dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "'{}' leads to '{}' outside of '{}'",
+                    path.display(),
+                    resolved.display(),
+                    self.dir.display()
+                ),
+            ));
+        }
+
+        Ok(resolved)
+    }
+}
+
+/// Whether the path only consists of names, so it can't leave the directory
+/// it's joined onto by itself.
+pub fn is_relative(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Turns a path requested by a client into a relative one, with `.` and
+/// empty segments removed. Paths that could leave the directory, through
+/// `..` or by being absolute, are refused, as are backslashes, which are
+/// separators on Windows.
+pub fn normalize(path: &str) -> Option<PathBuf> {
+    if path.contains(['\\', '\0']) {
+        return None;
+    }
+
+    let mut normalized = PathBuf::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment => normalized.push(segment),
+        }
+    }
+
+    is_relative(&normalized).then_some(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// An empty directory for a single test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("safe-path-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn normalize_removes_empty_and_current_segments() {
+        assert_eq!(
+            normalize("/./patches//1/./Media.pk2"),
+            Some(PathBuf::from("patches/1/Media.pk2"))
+        );
+    }
+
+    #[test]
+    fn normalize_refuses_parent_segments() {
+        assert_eq!(normalize(".."), None);
+        assert_eq!(normalize("1/../../secret"), None);
+        assert_eq!(normalize("1/.."), None);
+    }
+
+    #[test]
+    fn normalize_refuses_empty_paths() {
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize("/./"), None);
+    }
+
+    #[test]
+    fn normalize_makes_absolute_paths_relative() {
+        assert_eq!(normalize("/etc/passwd"), Some(PathBuf::from("etc/passwd")));
+    }
+
+    #[test]
+    fn normalize_refuses_backslashes_and_nul() {
+        assert_eq!(normalize("..\\secret"), None);
+        assert_eq!(normalize("1\\Media.pk2"), None);
+        assert_eq!(normalize("C:\\Windows"), None);
+        assert_eq!(normalize("1/Media.pk2\0"), None);
+    }
+
+    #[test]
+    fn normalize_keeps_encoded_separators_in_a_single_name() {
+        // Paths arrive decoded, so anything still encoded is part of a name.
+        assert_eq!(
+            normalize("%2e%2e%2fsecret"),
+            Some(PathBuf::from("%2e%2e%2fsecret"))
+        );
+        assert_eq!(
+            normalize("..%2F..%2Fsecret").unwrap().components().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn is_relative_refuses_anything_but_names() {
+        assert!(is_relative(Path::new("1/Media.pk2")));
+        assert!(!is_relative(Path::new("")));
+        assert!(!is_relative(Path::new("/1/Media.pk2")));
+        assert!(!is_relative(Path::new("./1/Media.pk2")));
+        assert!(!is_relative(Path::new("1/../Media.pk2")));
+    }
+
+    #[test]
+    fn root_resolves_paths_inside() {
+        let dir = temp_dir("inside");
+        fs::create_dir(dir.join("1")).unwrap();
+        fs::write(dir.join("1/Media.pk2"), b"media").unwrap();
+
+        let root = Root::new(&dir).unwrap();
+        let resolved = root.resolve(Path::new("1/Media.pk2")).unwrap();
+        assert_eq!(resolved, fs::canonicalize(dir.join("1/Media.pk2")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn root_refuses_parent_and_absolute_paths() {
+        let dir = temp_dir("escape");
+        fs::create_dir(dir.join("1")).unwrap();
+
+        let root = Root::new(&dir.join("1")).unwrap();
+        let parent = root.resolve(Path::new("..")).unwrap_err();
+        assert_eq!(parent.kind(), io::ErrorKind::PermissionDenied);
+        let absolute = root.resolve(&dir).unwrap_err();
+        assert_eq!(absolute.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn root_fails_for_missing_paths() {
+        let dir = temp_dir("missing");
+
+        let root = Root::new(&dir).unwrap();
+        let missing = root.resolve(Path::new("1")).unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn root_refuses_symlinks_leading_outside() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("symlinks");
+        fs::create_dir_all(dir.join("root/1")).unwrap();
+        fs::write(dir.join("root/1/Media.pk2"), b"media").unwrap();
+        fs::write(dir.join("secret"), b"secret").unwrap();
+        symlink(dir.join("secret"), dir.join("root/secret")).unwrap();
+        symlink(&dir, dir.join("root/outside")).unwrap();
+        symlink(dir.join("root/1"), dir.join("root/latest")).unwrap();
+
+        let root = Root::new(&dir.join("root")).unwrap();
+        let file = root.resolve(Path::new("secret")).unwrap_err();
+        assert_eq!(file.kind(), io::ErrorKind::PermissionDenied);
+        let directory = root.resolve(Path::new("outside/secret")).unwrap_err();
+        assert_eq!(directory.kind(), io::ErrorKind::PermissionDenied);
+        let inside = root.resolve(Path::new("latest/Media.pk2")).unwrap();
+        assert_eq!(
+            inside,
+            fs::canonicalize(dir.join("root/1/Media.pk2")).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,24 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Switches the target of a channel to `version` once `go_live_at` has passed.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Activation {
+    pub version: u16,
+    pub go_live_at: DateTime<Utc>,
+}
+
+impl Activation {
+    pub fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.go_live_at <= now
+    }
+}
+
+/// Returns the version of the most recent activation that already went live.
+pub fn active_version(activations: &[Activation], now: DateTime<Utc>) -> Option<u16> {
+    activations
+        .iter()
+        .filter(|activation| activation.is_live(now))
+        .max_by_key(|activation| activation.go_live_at)
+        .map(|activation| activation.version)
+}
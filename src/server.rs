@@ -0,0 +1,787 @@
+use crate::bandwidth::{Bandwidth, BandwidthConfig};
+use crate::channel::Channel;
+use crate::config::{
+    Config, DowngradeConfig, ListenerOverrides, ScrubConfig, StorageConfig, TenantConfig,
+    VersionRangeConfig,
+};
+use crate::listener::{Listener, RangeAction, Route, VersionRange};
+use crate::mirror::Mirror;
+use crate::patch_chain::{self, ChainValidation};
+use crate::patch_signing::PublicKey;
+use crate::promotion::PatchState;
+use crate::region::GeoIp;
+use crate::retention;
+use crate::tenant::Tenant;
+use crate::upstream;
+use crate::url_check::UrlCheck;
+use crate::{config, FileProblem, Quarantined, SocketCoordinator};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{task, time};
+use tokio_util::sync::CancellationToken;
+use toml::Table;
+
+/// A listener added at runtime, serving a single channel of a tenant.
+#[derive(Deserialize)]
+pub struct DynamicListenerConfig {
+    pub port: u16,
+    pub channel: String,
+    /// The version clients get patched to. Defaults to the target of the channel.
+    pub target: Option<u16>,
+    #[serde(flatten)]
+    pub overrides: ListenerOverrides,
+}
+
+/// Keeps track of the running tenants, allowing tenants and their listeners
+/// to be added and removed at runtime.
+pub struct Server {
+    coordinator: SocketCoordinator,
+    tenants: RwLock<Vec<Arc<Tenant>>>,
+    geoip: Option<Arc<GeoIp>>,
+    downgrades: DowngradeConfig,
+    storage: StorageConfig,
+    chain_validation: ChainValidation,
+    bandwidth: Bandwidth,
+}
+
+impl Server {
+    pub fn new(
+        coordinator: SocketCoordinator,
+        geoip: Option<Arc<GeoIp>>,
+        downgrades: DowngradeConfig,
+        storage: StorageConfig,
+        chain_validation: ChainValidation,
+    ) -> Server {
+        Server {
+            coordinator,
+            tenants: RwLock::new(Vec::new()),
+            geoip,
+            downgrades,
+            storage,
+            chain_validation,
+            bandwidth: Bandwidth::new(BandwidthConfig::default()),
+        }
+    }
+
+    /// Whether nothing may be written into the patch directories.
+    pub fn is_read_only(&self) -> bool {
+        self.storage.read_only
+    }
+
+    /// Whether states of versions can be stored, which read-only storage
+    /// needs a state directory for.
+    pub fn can_store_states(&self) -> bool {
+        !self.storage.read_only || self.storage.state_dir.is_some()
+    }
+
+    /// The bandwidth limits of the built-in fileserver.
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    pub fn tenants(&self) -> Vec<Arc<Tenant>> {
+        self.tenants.read().unwrap().clone()
+    }
+
+    pub fn find_tenant(&self, name: &str) -> Option<Arc<Tenant>> {
+        self.tenants
+            .read()
+            .unwrap()
+            .iter()
+            .find(|tenant| tenant.name() == name)
+            .cloned()
+    }
+
+    pub fn add_tenant(&self, config: TenantConfig) -> Result<Arc<Tenant>, String> {
+        if self.find_tenant(&config.name).is_some() {
+            return Err(format!("Tenant '{}' already exists", config.name));
+        }
+
+        let tenant = self.start_tenant(config)?;
+        self.tenants.write().unwrap().push(Arc::clone(&tenant));
+        Ok(tenant)
+    }
+
+    /// Stops all listeners and connections of the tenant and removes it.
+    pub fn remove_tenant(&self, name: &str) -> bool {
+        let mut tenants = self.tenants.write().unwrap();
+        let Some(index) = tenants.iter().position(|tenant| tenant.name() == name) else {
+            return false;
+        };
+
+        let tenant = tenants.remove(index);
+        tenant.shutdown();
+        info!("Removed tenant '{}'", name);
+        true
+    }
+
+    /// Applies the settings of the config that can change without
+    /// restarting: the fileservers and notices of the tenants, the
+    /// maintenance windows and the bandwidth limits. Connections in progress keep going and pick up
+    /// the changes with their next request.
+    pub fn reload(&self, mut config: Config) {
+        for tenant_config in config.take_tenants() {
+            let Some(tenant) = self.find_tenant(&tenant_config.name) else {
+                warn!(
+                    "Tenant '{}' is new and only started after a restart",
+                    tenant_config.name
+                );
+                continue;
+            };
+
+            tenant.set_notices(tenant_config.notices);
+            for channel in tenant.channels() {
+                channel.set_fileserver(tenant_config.fileserver.clone());
+            }
+        }
+        self.coordinator
+            .settings
+            .maintenance
+            .set_windows(config.maintenance);
+        if let Some(file_hosting) = config.file_hosting {
+            self.bandwidth.set_config(file_hosting.bandwidth);
+        }
+        info!("Reloaded the config");
+    }
+
+    /// Reloads the config every time the process receives SIGHUP. An invalid
+    /// config is reported and leaves the settings unchanged.
+    #[cfg(unix)]
+    pub async fn reload_on_hangup(self: Arc<Self>, path: PathBuf, command_line: Table) {
+        let cancel_token = self.child_token();
+        let mut hangups =
+            signal(SignalKind::hangup()).expect("Should be able to listen for SIGHUP");
+        loop {
+            tokio::select! {
+                Some(()) = hangups.recv() => {},
+                _ = cancel_token.cancelled() => return,
+            }
+
+            match Config::resolve(&path, command_line.clone()) {
+                Ok(config) => self.reload(config),
+                Err(err) => error!("Could not reload the config: {}", err),
+            }
+        }
+    }
+
+    pub fn add_listener(&self, tenant: &str, config: DynamicListenerConfig) -> Result<(), String> {
+        let tenant = self
+            .find_tenant(tenant)
+            .ok_or_else(|| format!("Unknown tenant '{}'", tenant))?;
+        let channel = tenant
+            .find_channel(&config.channel)
+            .cloned()
+            .ok_or_else(|| format!("Unknown channel '{}'", config.channel))?;
+        let target = config
+            .target
+            .or_else(|| channel.default_target())
+            .ok_or_else(|| format!("Channel '{}' has no target", channel.name()))?;
+        if !channel.provider().versions().contains(&target) {
+            return Err(format!(
+                "Version {} doesn't exist in channel '{}'",
+                target,
+                channel.name()
+            ));
+        }
+
+        self.start_listener(
+            &tenant,
+            Listener::new(
+                config.port,
+                Arc::clone(&tenant),
+                vec![Route::new(channel, config.target)],
+                config.overrides,
+            ),
+        )
+    }
+
+    /// Stops the listener and all connections made through it.
+    pub fn remove_listener(&self, tenant: &str, port: u16) -> bool {
+        let Some(tenant) = self.find_tenant(tenant) else {
+            return false;
+        };
+
+        let removed = tenant.stop_listener(port);
+        if removed {
+            info!(
+                "Removed listener on port {} of tenant '{}'",
+                port,
+                tenant.name()
+            );
+        }
+        removed
+    }
+
+    pub fn child_token(&self) -> CancellationToken {
+        self.coordinator.child_token()
+    }
+
+    /// Regularly compares the patches of all channels with their patch
+    /// directory, so files changed on disk don't get served with stale sizes.
+    pub async fn scan_periodically(self: Arc<Self>, period: Duration) {
+        let cancel_token = self.child_token();
+        let mut interval = time::interval(period);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = cancel_token.cancelled() => return,
+            }
+
+            for tenant in self.tenants() {
+                for channel in tenant.channels() {
+                    for version in channel.provider().recheck_quarantined() {
+                        info!(
+                            "Version {} of channel '{}' is no longer quarantined",
+                            version,
+                            channel.name()
+                        );
+                    }
+                    let scan = channel.provider().scan();
+                    for version in &scan.changed {
+                        info!(
+                            "Version {} of channel '{}' changed on disk, reloaded its files",
+                            version,
+                            channel.name()
+                        );
+                    }
+                    quarantine_problems(channel, scan.problems, "contains unavailable files");
+                    let problems = channel.provider().verify_signatures(&scan.changed);
+                    quarantine_problems(channel, problems, "has an invalid signature");
+                }
+            }
+        }
+    }
+
+    /// Regularly hashes a few files of every channel, to notice files that
+    /// got corrupted on disk.
+    pub async fn scrub_periodically(self: Arc<Self>, config: ScrubConfig) {
+        let cancel_token = self.child_token();
+        let mut interval = time::interval(config.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = cancel_token.cancelled() => return,
+            }
+
+            for tenant in self.tenants() {
+                for channel in tenant.channels() {
+                    let scrubbed = Arc::clone(channel);
+                    let problems =
+                        task::spawn_blocking(move || scrubbed.provider().scrub(config.files))
+                            .await
+                            .unwrap();
+                    quarantine_problems(channel, problems, "contains corrupted files");
+                }
+            }
+        }
+    }
+
+    /// Regularly uploads versions the mirrors of every channel didn't receive
+    /// yet. Mirrors are only advertised for versions they received.
+    pub async fn sync_mirrors_periodically(self: Arc<Self>, period: Duration) {
+        let cancel_token = self.child_token();
+        let client = reqwest::Client::new();
+        let mut interval = time::interval(period);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = cancel_token.cancelled() => return,
+            }
+
+            for tenant in self.tenants() {
+                for channel in tenant.channels() {
+                    for mirror in channel.mirrors() {
+                        mirror.sync(&client, &channel.provider()).await;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.coordinator.shutdown()
+    }
+
+    /// Moves the state of the channel into the state directory, if there is
+    /// one, making sure nothing would end up inside a read-only patch directory.
+    fn prepare_storage(&self, tenant: &str, channel: &Channel) -> Result<(), String> {
+        let Some(state_dir) = &self.storage.state_dir else {
+            return Ok(());
+        };
+
+        let provider = channel.provider();
+        let patch_dir = provider.patch_dir();
+        if self.storage.read_only && canonical(state_dir).starts_with(canonical(patch_dir)) {
+            return Err(format!(
+                "The state directory is inside the read-only patch directory '{}'",
+                patch_dir.display()
+            ));
+        }
+
+        provider.set_manifest_dir(state_dir.join(tenant).join(channel.name()));
+        Ok(())
+    }
+
+    /// Logs the problems with the versions of the channel, including
+    /// quarantined ones, failing if the chain has to be valid.
+    fn validate_chain(&self, channel: &Channel) -> Result<(), String> {
+        let problems = patch_chain::validate(&channel.provider());
+        for problem in &problems {
+            warn!(
+                "Invalid patch chain in channel '{}': {}",
+                channel.name(),
+                problem
+            );
+        }
+
+        let quarantined = channel.provider().quarantined().len();
+        if self.chain_validation == ChainValidation::Refuse
+            && (!problems.is_empty() || quarantined > 0)
+        {
+            return Err(format!(
+                "Channel '{}' has {} problems with its patch chain and {} quarantined directories",
+                channel.name(),
+                problems.len(),
+                quarantined
+            ));
+        }
+        Ok(())
+    }
+
+    fn start_listener(&self, tenant: &Arc<Tenant>, listener: Listener) -> Result<(), String> {
+        let port = listener.port;
+        if tenant.ports().contains(&port) {
+            return Err(format!(
+                "Tenant '{}' already listens on port {}",
+                tenant.name(),
+                port
+            ));
+        }
+
+        let cancel_token = tenant.child_token();
+        self.coordinator
+            .accept(listener, cancel_token.clone())
+            .map_err(|err| format!("Could not listen on port {}: {}", port, err))?;
+        tenant.register_listener(port, cancel_token);
+        Ok(())
+    }
+
+    fn start_tenant(&self, config: TenantConfig) -> Result<Arc<Tenant>, String> {
+        let mut channels = Vec::new();
+        let mut routes = Vec::new();
+        if config.channels.is_empty() {
+            // Without any configured channels, every version gets its own port
+            // and clients are patched to the version of the port they connected to.
+            let patch_dir = config.patch_dir.unwrap_or_else(config::default_patch_dir);
+            check_patch_dir("default", &patch_dir)?;
+            let channel = Arc::new(
+                Channel::load("default".to_string(), patch_dir, config.fileserver)
+                    .with_downgrades(self.downgrades),
+            );
+            self.prepare_storage(&config.name, &channel)?;
+            verify_channel(&channel);
+            self.validate_chain(&channel)?;
+            let base_port = config.base_port.unwrap_or(config::DEFAULT_BASE_PORT);
+            for version in channel.provider().versions() {
+                let port = base_port.checked_add(version).ok_or_else(|| {
+                    format!(
+                        "Version {} can't be served above base port {}",
+                        version, base_port
+                    )
+                })?;
+                routes.push((
+                    vec![port],
+                    Route::new(Arc::clone(&channel), Some(version)),
+                    Vec::new(),
+                ));
+            }
+            channels.push(channel);
+        } else {
+            for channel_config in config.channels {
+                check_patch_dir(&channel_config.name, &channel_config.patch_dir)?;
+                let public_key = channel_config
+                    .public_key
+                    .as_deref()
+                    .map(PublicKey::parse)
+                    .transpose()
+                    .map_err(|err| {
+                        format!(
+                            "Channel '{}' has an invalid public key: {}",
+                            channel_config.name, err
+                        )
+                    })?;
+                let channel = Arc::new(
+                    Channel::load(
+                        channel_config.name,
+                        channel_config.patch_dir,
+                        config.fileserver.clone(),
+                    )
+                    .with_target(channel_config.target)
+                    .with_canaries(channel_config.canaries)
+                    .with_regions(channel_config.regions, self.geoip.clone())
+                    .with_max_downgrade_depth(channel_config.max_downgrade_depth)
+                    .with_downgrades(self.downgrades)
+                    .with_modules(channel_config.modules)
+                    .with_contents(channel_config.contents)
+                    .with_hash_verification(channel_config.verify_hashes)
+                    .with_blob_dir(channel_config.blob_dir)
+                    .with_public_key(public_key)
+                    .with_deltas(channel_config.deltas)
+                    .with_version_names(
+                        channel_config
+                            .version_names
+                            .into_iter()
+                            .map(|name| (name.version, name.name))
+                            .collect(),
+                    )
+                    .with_changelog_notice(channel_config.changelog_notice)
+                    .with_early_access(channel_config.early_access.clone())
+                    .with_mirrors(
+                        channel_config
+                            .mirrors
+                            .into_iter()
+                            .map(Mirror::new)
+                            .collect(),
+                    )
+                    .with_upstream(channel_config.upstream)
+                    .with_retention(channel_config.retention)
+                    .with_cold_storage(channel_config.cold_storage)
+                    .with_url_check(channel_config.check_urls.map(UrlCheck::new))
+                    .with_version_fileservers(channel_config.version_fileservers),
+                );
+                if channel.upstream().is_some() && self.storage.read_only {
+                    return Err(format!(
+                        "Channel '{}' can't fetch from upstream into read-only storage",
+                        channel.name()
+                    ));
+                }
+                if channel
+                    .retention()
+                    .is_some_and(|retention| retention.delete)
+                    && self.storage.read_only
+                {
+                    return Err(format!(
+                        "Channel '{}' can't delete old versions from read-only storage",
+                        channel.name()
+                    ));
+                }
+                if channel.retention().is_some() && !self.can_store_states() {
+                    return Err(format!(
+                        "Channel '{}' can't retire old versions in read-only storage without a state directory",
+                        channel.name()
+                    ));
+                }
+                if channel
+                    .cold_storage()
+                    .is_some_and(|cold_storage| cold_storage.rehydrates())
+                    && self.storage.read_only
+                {
+                    return Err(format!(
+                        "Channel '{}' can't rehydrate versions into read-only storage",
+                        channel.name()
+                    ));
+                }
+                for early_access in &channel_config.early_access {
+                    info!(
+                        "Version {} of channel '{}' is in early access until {}",
+                        early_access.version,
+                        channel.name(),
+                        early_access.until
+                    );
+                }
+                for version in channel_config.skipped_versions {
+                    info!(
+                        "Skipping version {} of channel '{}'",
+                        version,
+                        channel.name()
+                    );
+                    channel.provider().skip(version);
+                }
+                self.prepare_storage(&config.name, &channel)?;
+                if channel_config.stage_new_versions && !self.can_store_states() {
+                    return Err(format!(
+                        "Channel '{}' can't stage new versions in read-only storage without a state directory",
+                        channel.name()
+                    ));
+                }
+                channel
+                    .provider()
+                    .load_states(channel_config.stage_new_versions)
+                    .map_err(|err| {
+                        format!(
+                            "Could not load the version states of channel '{}': {}",
+                            channel.name(),
+                            err
+                        )
+                    })?;
+                for version in channel.provider().versions_in(PatchState::Staged) {
+                    info!(
+                        "Version {} of channel '{}' is staged",
+                        version,
+                        channel.name()
+                    );
+                }
+                verify_channel(&channel);
+                self.validate_chain(&channel)?;
+                for (address, version) in channel_config.pins {
+                    channel.pin(address, version);
+                }
+                channel.set_rollout(channel_config.rollout);
+                channel.set_experiment(channel_config.experiment);
+                for activation in channel_config.schedule {
+                    info!(
+                        "Version {} of channel '{}' goes live at {}",
+                        activation.version,
+                        channel.name(),
+                        activation.go_live_at
+                    );
+                    channel.schedule(activation);
+                }
+                if let Some(expected) = channel_config.expected_version {
+                    let latest = channel.provider().latest_version();
+                    if latest != Some(expected) {
+                        let message = format!(
+                            "Expected version {} to be the latest of channel '{}', but it is {}",
+                            expected,
+                            channel.name(),
+                            latest.map_or("none".to_string(), |latest| latest.to_string())
+                        );
+                        if channel_config.fail_on_drift {
+                            return Err(message);
+                        }
+                        warn!("{}", message);
+                    }
+                }
+                let target = channel
+                    .default_target()
+                    .ok_or_else(|| format!("Channel '{}' contains no patches", channel.name()))?;
+                if !channel.provider().versions().contains(&target) {
+                    return Err(format!(
+                        "Target {} of channel '{}' is skipped, quarantined or doesn't exist",
+                        target,
+                        channel.name()
+                    ));
+                }
+                info!(
+                    "Channel '{}' patches clients to version {}{}",
+                    channel.name(),
+                    channel.display_version(target),
+                    if channel.target_override().is_some() {
+                        ""
+                    } else {
+                        " (latest version)"
+                    }
+                );
+                let mut ports = channel_config.ports;
+                for region in channel.regions() {
+                    info!(
+                        "Region '{}' of channel '{}' is patched to version {}",
+                        region.name,
+                        channel.name(),
+                        region.target
+                    );
+                    ports.extend(&region.ports);
+                }
+                ports.sort_unstable();
+                ports.dedup();
+                routes.push((
+                    ports,
+                    Route::new(Arc::clone(&channel), None),
+                    channel_config.version_ranges,
+                ));
+                channels.push(channel);
+            }
+        }
+
+        // Version ranges may refer to other channels, so they can only be
+        // resolved once all channels are loaded.
+        let plain_routes = routes
+            .iter()
+            .map(|(_, route, _)| route.clone())
+            .collect::<Vec<_>>();
+        let mut routes_by_port = BTreeMap::<u16, Vec<Route>>::new();
+        for (ports, mut route, version_ranges) in routes {
+            route.version_ranges = version_ranges
+                .into_iter()
+                .map(|range| resolve_version_range(range, &route, &plain_routes))
+                .collect::<Result<_, _>>()?;
+            for port in ports {
+                routes_by_port.entry(port).or_default().push(route.clone());
+            }
+        }
+
+        let tenant = Arc::new(Tenant::new(
+            config.name,
+            channels,
+            config.notices,
+            config.limits,
+            config.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            self.coordinator.child_token(),
+        ));
+        tenant.set_latency(config.latency);
+        for channel in tenant.channels() {
+            if let Some(upstream) = channel.upstream() {
+                tokio::spawn(upstream::fetch_periodically(
+                    Arc::clone(channel),
+                    upstream.clone(),
+                    tenant.child_token(),
+                ));
+            }
+            if let Some(retention) = channel.retention() {
+                tokio::spawn(retention::prune_periodically(
+                    Arc::clone(channel),
+                    retention.clone(),
+                    range_targets(&routes_by_port, channel),
+                    tenant.child_token(),
+                ));
+            }
+        }
+        let mut overrides_by_port = config
+            .listeners
+            .into_iter()
+            .map(|listener| (listener.port, listener.overrides))
+            .collect::<HashMap<_, _>>();
+        for (port, routes) in routes_by_port {
+            let listener = Listener::new(
+                port,
+                Arc::clone(&tenant),
+                routes,
+                overrides_by_port.remove(&port).unwrap_or_default(),
+            );
+            if let Err(err) = self.start_listener(&tenant, listener) {
+                tenant.shutdown();
+                return Err(err);
+            }
+        }
+        for port in overrides_by_port.keys() {
+            warn!(
+                "Listener settings for port {} of tenant '{}' are unused, as no channel is served on it",
+                port,
+                tenant.name()
+            );
+        }
+
+        Ok(tenant)
+    }
+}
+
+/// Resolves the path as far as possible, to compare it with other paths.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Fails instead of panicking while loading a channel from a patch directory
+/// that doesn't exist, e.g. because of a typo in the config.
+fn check_patch_dir(channel: &str, patch_dir: &Path) -> Result<(), String> {
+    if !patch_dir.is_dir() {
+        return Err(format!(
+            "The patch directory '{}' of channel '{}' doesn't exist",
+            patch_dir.display(),
+            channel
+        ));
+    }
+    Ok(())
+}
+
+/// Checks all files before accepting connections, as a missing file would
+/// otherwise only be noticed once a client needs it. Versions with
+/// unavailable files or an invalid signature are quarantined.
+fn verify_channel(channel: &Channel) {
+    let scan = channel.provider().scan();
+    info!(
+        "Checked {} files of channel '{}', {} unavailable",
+        scan.checked,
+        channel.name(),
+        scan.problems.len()
+    );
+    quarantine_problems(channel, scan.problems, "contains unavailable files");
+    let problems = channel
+        .provider()
+        .verify_signatures(&channel.provider().versions());
+    quarantine_problems(channel, problems, "has an invalid signature");
+}
+
+fn quarantine_problems(channel: &Channel, problems: Vec<FileProblem>, reason: &str) {
+    for problem in &problems {
+        error!(
+            "File '{}' of version {} in channel '{}' is unavailable: {}",
+            problem.file.display(),
+            problem.version,
+            channel.name(),
+            problem.error
+        );
+    }
+
+    let mut versions = problems
+        .iter()
+        .map(|problem| problem.version)
+        .collect::<Vec<_>>();
+    versions.sort_unstable();
+    versions.dedup();
+    for version in versions {
+        warn!(
+            "Quarantined version {} of channel '{}'",
+            version,
+            channel.name()
+        );
+        channel.provider().quarantine(Quarantined {
+            directory: version.to_string(),
+            version: Some(version),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+fn resolve_version_range(
+    range: VersionRangeConfig,
+    route: &Route,
+    routes: &[Route],
+) -> Result<VersionRange, String> {
+    let action = match range.reject {
+        Some(rejection) => RangeAction::Reject(rejection),
+        None => {
+            let route = match &range.channel {
+                Some(name) => routes
+                    .iter()
+                    .find(|route| route.channel.name() == name)
+                    .ok_or_else(|| format!("Version range refers to unknown channel '{}'", name))?,
+                None => route,
+            };
+            RangeAction::Route(Box::new(Route::new(
+                Arc::clone(&route.channel),
+                range.target.or(route.target),
+            )))
+        }
+    };
+
+    Ok(VersionRange {
+        min: range.min,
+        max: range.max,
+        action,
+    })
+}
+
+/// The versions version ranges of the routes send clients of the channel to.
+fn range_targets(
+    routes_by_port: &BTreeMap<u16, Vec<Route>>,
+    channel: &Arc<Channel>,
+) -> BTreeSet<u16> {
+    routes_by_port
+        .values()
+        .flatten()
+        .flat_map(|route| &route.version_ranges)
+        .filter_map(|range| match &range.action {
+            RangeAction::Route(route) if Arc::ptr_eq(&route.channel, channel) => route.target,
+            _ => None,
+        })
+        .collect()
+}
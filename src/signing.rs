@@ -0,0 +1,108 @@
+use crate::patch_signing::{from_hex, to_hex};
+use chrono::Utc;
+use ring::hmac;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Has the built-in fileserver only serve files to clients that were sent
+/// their paths in a patch response, instead of to anyone linking to it.
+#[derive(Deserialize)]
+pub struct SigningConfig {
+    pub secret: String,
+    /// How long advertised paths stay valid.
+    #[serde(default = "default_validity", with = "humantime_serde")]
+    pub validity: Duration,
+}
+
+fn default_validity() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+/// Signs paths with an expiry, and checks the signatures of requested paths.
+#[derive(Clone, PartialEq)]
+pub struct UrlSigner {
+    secret: Arc<str>,
+    validity: Duration,
+}
+
+impl UrlSigner {
+    pub fn new(config: &SigningConfig) -> UrlSigner {
+        UrlSigner {
+            secret: Arc::from(config.secret.as_str()),
+            validity: config.validity,
+        }
+    }
+
+    /// The query to append to the path, e.g. `?expires=..&signature=..`.
+    pub fn sign(&self, path: &str) -> String {
+        let expires = Utc::now().timestamp() as u64 + self.validity.as_secs();
+        let signature = hmac::sign(&self.key(), message(path, expires).as_bytes());
+        format!(
+            "?expires={}&signature={}",
+            expires,
+            to_hex(signature.as_ref())
+        )
+    }
+
+    pub fn verify(&self, path: &str, expires: u64, signature: &str) -> bool {
+        from_hex(signature).is_some_and(|signature| {
+            hmac::verify(&self.key(), message(path, expires).as_bytes(), &signature).is_ok()
+        }) && expires >= Utc::now().timestamp() as u64
+    }
+
+    fn key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, self.secret.as_bytes())
+    }
+}
+
+fn message(path: &str, expires: u64) -> String {
+    format!("{}\n{}", path, expires)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> UrlSigner {
+        UrlSigner::new(&SigningConfig {
+            secret: "secret".to_string(),
+            validity: default_validity(),
+        })
+    }
+
+    /// The expiry and signature of the query returned by [UrlSigner::sign].
+    fn signature(query: &str) -> (u64, String) {
+        let (expires, signature) = query
+            .strip_prefix("?expires=")
+            .and_then(|query| query.split_once("&signature="))
+            .unwrap();
+        (expires.parse().unwrap(), signature.to_string())
+    }
+
+    #[test]
+    fn signed_paths_are_verified() {
+        let signer = signer();
+        let (expires, signature) = signature(&signer.sign("/1/Media.pk2"));
+        assert!(signer.verify("/1/Media.pk2", expires, &signature));
+    }
+
+    #[test]
+    fn tampered_paths_are_rejected() {
+        let signer = signer();
+        let (expires, signature) = signature(&signer.sign("/1/Media.pk2"));
+        assert!(!signer.verify("/2/Media.pk2", expires, &signature));
+        assert!(!signer.verify("/1/Media.pk2", expires + 1, &signature));
+        assert!(!signer.verify("/1/Media.pk2", expires, &signature[2..]));
+        assert!(!signer.verify("/1/Media.pk2", expires, "signature"));
+    }
+
+    #[test]
+    fn expired_paths_are_rejected() {
+        let signer = signer();
+        let expires = Utc::now().timestamp() as u64 - 1;
+        let signature =
+            to_hex(hmac::sign(&signer.key(), message("/1/Media.pk2", expires).as_bytes()).as_ref());
+        assert!(!signer.verify("/1/Media.pk2", expires, &signature));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::channel::Channel;
+use crate::latency::Latency;
+use crate::limits::{Limiter, LimitsConfig};
+use crate::metrics::Metrics;
+use crate::protocol::GatewayNotice;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+
+/// The tenant configured at the top level of the config.
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Replaced in notice articles by the size of the latest update of the
+/// listener's channel.
+pub const UPDATE_SIZE_PLACEHOLDER: &str = "{update_size}";
+
+/// Replaced in notice articles by the name of the listener's target version.
+pub const VERSION_PLACEHOLDER: &str = "{version}";
+
+/// An independent patch service (e.g. a different game or division) with its
+/// own channels, fileserver and notices.
+pub struct Tenant {
+    name: String,
+    channels: Vec<Arc<Channel>>,
+    notices: RwLock<Vec<Notice>>,
+    limiter: Arc<Limiter>,
+    metrics: Mutex<BTreeMap<u16, Arc<Metrics>>>,
+    listeners: Mutex<BTreeMap<u16, CancellationToken>>,
+    latency: RwLock<Option<Latency>>,
+    bind: IpAddr,
+    cancel_token: CancellationToken,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Notice {
+    pub subject: String,
+    pub article: String,
+    pub published: DateTime<Utc>,
+}
+
+impl Notice {
+    pub fn to_gateway_notice(&self) -> GatewayNotice {
+        GatewayNotice {
+            subject: self.subject.clone(),
+            article: self.article.clone(),
+            published: self.published,
+        }
+    }
+}
+
+impl Tenant {
+    pub fn new(
+        name: String,
+        channels: Vec<Arc<Channel>>,
+        notices: Vec<Notice>,
+        limits: LimitsConfig,
+        bind: IpAddr,
+        cancel_token: CancellationToken,
+    ) -> Tenant {
+        Tenant {
+            name,
+            channels,
+            notices: RwLock::new(notices),
+            limiter: Arc::new(Limiter::new(limits)),
+            metrics: Mutex::new(BTreeMap::new()),
+            listeners: Mutex::new(BTreeMap::new()),
+            latency: RwLock::new(None),
+            bind,
+            cancel_token,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn channels(&self) -> &[Arc<Channel>] {
+        &self.channels
+    }
+
+    pub fn find_channel(&self, name: &str) -> Option<&Arc<Channel>> {
+        self.channels.iter().find(|channel| channel.name() == name)
+    }
+
+    pub fn notices(&self) -> Vec<Notice> {
+        self.notices.read().unwrap().clone()
+    }
+
+    pub fn set_notices(&self, notices: Vec<Notice>) {
+        *self.notices.write().unwrap() = notices;
+    }
+
+    /// The address listeners bind to unless they override it.
+    pub fn bind(&self) -> IpAddr {
+        self.bind
+    }
+
+    /// The limits shared by all listeners of this tenant.
+    pub fn limiter(&self) -> &Arc<Limiter> {
+        &self.limiter
+    }
+
+    pub fn latency(&self) -> Option<Latency> {
+        *self.latency.read().unwrap()
+    }
+
+    pub fn set_latency(&self, latency: Option<Latency>) {
+        *self.latency.write().unwrap() = latency;
+    }
+
+    pub fn metrics_for(&self, port: u16) -> Arc<Metrics> {
+        Arc::clone(self.metrics.lock().unwrap().entry(port).or_default())
+    }
+
+    pub fn ports(&self) -> Vec<u16> {
+        self.listeners.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Creates the token for a new listener, which gets cancelled together
+    /// with the tenant.
+    pub fn child_token(&self) -> CancellationToken {
+        self.cancel_token.child_token()
+    }
+
+    pub fn register_listener(&self, port: u16, cancel_token: CancellationToken) {
+        self.listeners.lock().unwrap().insert(port, cancel_token);
+    }
+
+    pub fn stop_listener(&self, port: u16) -> bool {
+        match self.listeners.lock().unwrap().remove(&port) {
+            Some(cancel_token) => {
+                cancel_token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.cancel_token.cancel();
+    }
+
+    pub fn metrics(&self) -> Vec<(u16, Arc<Metrics>)> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(port, metrics)| (*port, Arc::clone(metrics)))
+            .collect()
+    }
+}
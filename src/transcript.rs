@@ -0,0 +1,79 @@
+use crate::dump::Direction;
+use crate::protocol::PatchProtocol;
+use chrono::Utc;
+use log::warn;
+use serde_json::json;
+use skrillax_packet::Packet;
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Records the decoded packets of a single connection as JSON lines, such
+/// that users can send in what happened on their connection. Does nothing if
+/// transcripts are disabled or the file couldn't be created.
+pub struct Transcript {
+    file: Option<File>,
+}
+
+impl Transcript {
+    pub fn open(dir: Option<&Path>, peer: SocketAddr) -> Transcript {
+        let Some(dir) = dir else {
+            return Transcript { file: None };
+        };
+
+        let name = format!(
+            "{}-{}-{}.jsonl",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            peer.ip().to_string().replace(':', "_"),
+            peer.port()
+        );
+        let file = fs::create_dir_all(dir)
+            .and_then(|_| File::create(dir.join(&name)))
+            .inspect_err(|err| {
+                warn!(
+                    "Could not create transcript '{}' in '{}': {}",
+                    name,
+                    dir.display(),
+                    err
+                )
+            })
+            .ok();
+        Transcript { file }
+    }
+
+    pub fn record<P: Packet + Debug>(&mut self, direction: Direction, packet: &P) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+
+        let entry = json!({
+            "time": Utc::now().to_rfc3339(),
+            "direction": match direction {
+                Direction::Inbound => "inbound",
+                Direction::Outbound => "outbound",
+            },
+            "packet": P::NAME,
+            "opcode": P::ID,
+            "content": format!("{:?}", packet),
+        });
+        if let Err(err) = writeln!(file, "{}", entry) {
+            warn!("Could not write transcript, stopping it: {}", err);
+            self.file = None;
+        }
+    }
+
+    pub fn record_inbound(&mut self, packet: &PatchProtocol) {
+        match packet {
+            PatchProtocol::KeepAlive(packet) => self.record(Direction::Inbound, packet),
+            PatchProtocol::PatchRequest(packet) => self.record(Direction::Inbound, packet),
+            PatchProtocol::IdentityInformation(packet) => self.record(Direction::Inbound, packet),
+            PatchProtocol::GatewayNoticeRequest(packet) => self.record(Direction::Inbound, packet),
+            PatchProtocol::ShardListRequest(packet) => self.record(Direction::Inbound, packet),
+            PatchProtocol::LoginRequest(packet) => {
+                self.record(Direction::Inbound, &packet.redacted())
+            }
+        }
+    }
+}
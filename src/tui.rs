@@ -0,0 +1,215 @@
+use crate::metrics::{Outcome, Snapshot};
+use crate::server::Server;
+use log::{LevelFilter, Log, Metadata, Record};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, Paragraph, Row, Table};
+use ratatui::Frame;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The number of log lines kept for the dashboard.
+const LOG_LINES: usize = 200;
+
+/// Collects log lines for the dashboard, as it occupies the terminal that
+/// logs are written to otherwise.
+#[derive(Clone, Default)]
+pub struct LogTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+struct TailLogger {
+    filter: env_logger::Logger,
+    tail: LogTail,
+}
+
+impl Log for TailLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let mut lines = self.tail.lines.lock().unwrap();
+        if lines.len() == LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(format!(
+            "{} {:<5} {}",
+            chrono::Local::now().format("%H:%M:%S"),
+            record.level(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Sends all logs to the returned tail instead of the terminal, respecting
+/// the filter of the builder.
+pub fn init_logging(builder: &mut env_logger::Builder) -> LogTail {
+    let filter = builder.build();
+    let max_level: LevelFilter = filter.filter();
+    let tail = LogTail::default();
+    log::set_boxed_logger(Box::new(TailLogger {
+        filter,
+        tail: tail.clone(),
+    }))
+    .expect("Logging should not be initialized yet");
+    log::set_max_level(max_level);
+    tail
+}
+
+/// Renders live panels of all listeners until `q` or ctrl-c is pressed.
+pub fn run(server: Arc<Server>, log_tail: LogTail) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut previous = HashMap::<(String, u16), Snapshot>::new();
+    let mut previous_at = Instant::now();
+    let mut rates = HashMap::<(String, u16), f64>::new();
+    let result = loop {
+        let elapsed = previous_at.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let mut current = HashMap::new();
+            for tenant in server.tenants() {
+                for (port, metrics) in tenant.metrics() {
+                    current.insert((tenant.name().to_string(), port), metrics.snapshot());
+                }
+            }
+            rates = current
+                .iter()
+                .map(|(key, snapshot)| {
+                    let before = previous.get(key).map_or(0, Snapshot::requests);
+                    let requests = snapshot.requests().saturating_sub(before);
+                    (key.clone(), requests as f64 / elapsed.as_secs_f64())
+                })
+                .collect();
+            previous = current;
+            previous_at = Instant::now();
+        }
+
+        if let Err(err) = terminal.draw(|frame| draw(frame, &server, &previous, &rates, &log_tail))
+        {
+            break Err(err);
+        }
+
+        match event::poll(Duration::from_millis(250)) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(err) => break Err(err),
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.code == KeyCode::Char('q') || ctrl_c {
+                    break Ok(());
+                }
+            }
+            Ok(_) => {}
+            Err(err) => break Err(err),
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+fn draw(
+    frame: &mut Frame,
+    server: &Server,
+    snapshots: &HashMap<(String, u16), Snapshot>,
+    rates: &HashMap<(String, u16), f64>,
+    log_tail: &LogTail,
+) {
+    let [listeners_area, clients_area, logs_area] = Layout::vertical([
+        Constraint::Percentage(35),
+        Constraint::Percentage(30),
+        Constraint::Percentage(35),
+    ])
+    .areas(frame.area());
+
+    let mut keys = snapshots.keys().collect::<Vec<_>>();
+    keys.sort();
+    let rows = keys.into_iter().map(|key| {
+        let snapshot = snapshots[key];
+        Row::new(vec![
+            key.0.clone(),
+            key.1.to_string(),
+            snapshot.active_connections.to_string(),
+            format!("{:.1}", rates.get(key).copied().unwrap_or(0.0)),
+            snapshot.up_to_date.to_string(),
+            snapshot.updated.to_string(),
+            snapshot.rejected.to_string(),
+        ])
+    });
+    let listeners = Table::new(rows, [Constraint::Fill(1); 7])
+        .header(
+            Row::new(vec![
+                "Tenant",
+                "Port",
+                "Connections",
+                "Requests/s",
+                "Up to date",
+                "Updated",
+                "Rejected",
+            ])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::bordered().title(" Listeners (q to quit) "));
+    frame.render_widget(listeners, listeners_area);
+
+    let mut clients = server
+        .tenants()
+        .iter()
+        .flat_map(|tenant| {
+            tenant
+                .metrics()
+                .into_iter()
+                .flat_map(|(port, metrics)| {
+                    metrics
+                        .recent_clients()
+                        .into_iter()
+                        .map(move |client| (port, client))
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    clients.sort_by_key(|(_, client)| std::cmp::Reverse(client.time));
+    let clients = clients.into_iter().map(|(port, client)| {
+        format!(
+            "{} {:<22} port {:<5} version {:<5} {}",
+            client.time.with_timezone(&chrono::Local).format("%H:%M:%S"),
+            client.address,
+            port,
+            client.version,
+            match client.outcome {
+                Outcome::UpToDate => "up to date",
+                Outcome::Updated => "updated",
+                Outcome::Rejected => "rejected",
+            }
+        )
+    });
+    frame.render_widget(
+        List::new(clients).block(Block::bordered().title(" Recent clients ")),
+        clients_area,
+    );
+
+    // Only the newest lines fit, the border takes two.
+    let visible = usize::from(logs_area.height.saturating_sub(2));
+    let lines = log_tail.lines.lock().unwrap();
+    let logs = lines
+        .iter()
+        .skip(lines.len().saturating_sub(visible))
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+    frame.render_widget(
+        Paragraph::new(logs).block(Block::bordered().title(" Log ")),
+        logs_area,
+    );
+}
@@ -0,0 +1,270 @@
+use crate::channel::Channel;
+use crate::client::PatchClient;
+use crate::protocol::{PatchError, PatchResult};
+use crate::read_patch;
+use crate::safe_path;
+use log::{info, warn};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{fs, time};
+use tokio_util::sync::CancellationToken;
+
+/// Regularly fetches new versions from an upstream source into the patch
+/// directory of a channel, turning the channel into a mirror of it.
+#[derive(Deserialize, Clone)]
+pub struct UpstreamConfig {
+    #[serde(flatten)]
+    pub source: UpstreamSource,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum UpstreamSource {
+    /// Another patch server, which is asked for an update like a client would.
+    /// File paths are expected to be `<base_path>/<version>/<file>`, like this
+    /// server builds them.
+    Gateway {
+        address: String,
+        #[serde(default = "default_module")]
+        module: String,
+        #[serde(default)]
+        base_path: String,
+    },
+    /// A JSON document listing versions together with the URLs of their files.
+    Manifest { url: String },
+}
+
+fn default_module() -> String {
+    "SR_Client".to_string()
+}
+
+#[derive(Deserialize)]
+struct UpstreamManifest {
+    versions: Vec<UpstreamVersion>,
+}
+
+#[derive(Deserialize)]
+struct UpstreamVersion {
+    version: u16,
+    files: Vec<UpstreamFile>,
+}
+
+#[derive(Deserialize)]
+struct UpstreamFile {
+    /// The path of the file inside its version.
+    path: PathBuf,
+    url: String,
+}
+
+pub async fn fetch_periodically(
+    channel: Arc<Channel>,
+    config: UpstreamConfig,
+    cancel_token: CancellationToken,
+) {
+    let client = reqwest::Client::new();
+    let mut interval = time::interval(config.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = cancel_token.cancelled() => return,
+        }
+
+        let result = match &config.source {
+            UpstreamSource::Gateway {
+                address,
+                module,
+                base_path,
+            } => fetch_from_gateway(&client, &channel, address, module, base_path).await,
+            UpstreamSource::Manifest { url } => fetch_from_manifest(&client, &channel, url).await,
+        };
+        if let Err(err) = result {
+            warn!(
+                "Could not fetch new versions of channel '{}' from upstream: {}",
+                channel.name(),
+                err
+            );
+        }
+    }
+}
+
+async fn fetch_from_gateway(
+    client: &reqwest::Client,
+    channel: &Channel,
+    address: &str,
+    module: &str,
+    base_path: &str,
+) -> Result<(), String> {
+    let latest = latest_version(channel);
+    let mut upstream = PatchClient::connect(address)
+        .await
+        .map_err(|err| err.to_string())?;
+    upstream
+        .identify(module)
+        .await
+        .map_err(|err| err.to_string())?;
+    let result = upstream
+        .request_patch(module, latest.into())
+        .await
+        .map_err(|err| err.to_string())?;
+    let PatchResult::Problem {
+        error:
+            PatchError::Update {
+                current_version,
+                patch_files,
+                http_server,
+                ..
+            },
+    } = result
+    else {
+        return Ok(());
+    };
+
+    let version = u16::try_from(current_version)
+        .map_err(|_| format!("upstream version {} is out of range", current_version))?;
+    if version <= latest {
+        return Ok(());
+    }
+
+    let files = patch_files
+        .iter()
+        .map(|file| {
+            let file_path = file.file_path.replace('\\', "/");
+            // Drop the base path and the version the file comes from.
+            let relative = file_path
+                .strip_prefix(base_path)
+                .unwrap_or(&file_path)
+                .trim_start_matches('/');
+            let relative = relative
+                .split_once('/')
+                .filter(|(version, _)| version.parse::<u16>().is_ok())
+                .map_or(relative, |(_, relative)| relative);
+            (
+                PathBuf::from(relative),
+                format!(
+                    "http://{}/{}",
+                    http_server,
+                    file_path.trim_start_matches('/')
+                ),
+            )
+        })
+        .collect::<Vec<_>>();
+    download_version(client, channel, version, &files).await
+}
+
+async fn fetch_from_manifest(
+    client: &reqwest::Client,
+    channel: &Channel,
+    url: &str,
+) -> Result<(), String> {
+    let manifest = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| err.to_string())?
+        .json::<UpstreamManifest>()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let latest = latest_version(channel);
+    let mut versions = manifest
+        .versions
+        .into_iter()
+        .filter(|version| version.version > latest)
+        .collect::<Vec<_>>();
+    versions.sort_by_key(|version| version.version);
+    for version in versions {
+        let files = version
+            .files
+            .into_iter()
+            .map(|file| (file.path, file.url))
+            .collect::<Vec<_>>();
+        download_version(client, channel, version.version, &files).await?;
+    }
+    Ok(())
+}
+
+/// The highest version inside the patch directory, whether it is served or not.
+fn latest_version(channel: &Channel) -> u16 {
+    let provider = channel.provider();
+    let excluded = provider.skipped().into_iter().chain(
+        provider
+            .quarantined()
+            .into_iter()
+            .filter_map(|quarantined| quarantined.version),
+    );
+    provider
+        .versions()
+        .into_iter()
+        .chain(excluded)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Downloads the files into a temporary directory, which is only moved into
+/// place once complete, so clients never get served a partial version.
+async fn download_version(
+    client: &reqwest::Client,
+    channel: &Channel,
+    version: u16,
+    files: &[(PathBuf, String)],
+) -> Result<(), String> {
+    let provider = channel.provider();
+    let patch_dir = provider.patch_dir();
+    let partial_dir = patch_dir.join(format!(".{}.partial", version));
+    let version_dir = patch_dir.join(version.to_string());
+    if fs::try_exists(&version_dir).await.unwrap_or(false) {
+        return Err(format!(
+            "directory of version {} already exists but is not served",
+            version
+        ));
+    }
+
+    // Leftovers of an earlier attempt that failed halfway.
+    if fs::try_exists(&partial_dir).await.unwrap_or(false) {
+        fs::remove_dir_all(&partial_dir)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    for (file, url) in files {
+        if !safe_path::is_relative(file) {
+            return Err(format!("refusing to write file '{}'", file.display()));
+        }
+        download_file(client, url, &partial_dir.join(file)).await?;
+    }
+    fs::rename(&partial_dir, &version_dir)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let patch = read_patch(version, &version_dir).map_err(|err| err.to_string())?;
+    info!(
+        "Fetched version {} of channel '{}' from upstream with {} files",
+        version,
+        channel.name(),
+        patch.files.len()
+    );
+    provider.add_patch(patch);
+    provider.quarantine_unsigned(version)
+}
+
+pub async fn download_file(client: &reqwest::Client, url: &str, path: &Path) -> Result<(), String> {
+    let content = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| format!("could not download '{}': {}", url, err))?
+        .bytes()
+        .await
+        .map_err(|err| format!("could not download '{}': {}", url, err))?;
+    fs::create_dir_all(path.parent().unwrap())
+        .await
+        .map_err(|err| err.to_string())?;
+    fs::write(path, content)
+        .await
+        .map_err(|err| err.to_string())
+}
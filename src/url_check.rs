@@ -0,0 +1,62 @@
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Checks that advertised URLs exist on the fileserver before sending them to
+/// clients, so one misconfigured fileserver doesn't break every update.
+#[derive(Deserialize, Clone, Copy)]
+pub struct UrlCheckConfig {
+    /// How long the result of a check is reused for the same URL.
+    #[serde(default = "default_cache", with = "humantime_serde")]
+    pub cache: Duration,
+}
+
+fn default_cache() -> Duration {
+    Duration::from_secs(60)
+}
+
+pub struct UrlCheck {
+    client: reqwest::Client,
+    config: UrlCheckConfig,
+    results: Mutex<HashMap<String, (Instant, bool)>>,
+}
+
+impl UrlCheck {
+    pub fn new(config: UrlCheckConfig) -> UrlCheck {
+        UrlCheck {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(5))
+                .build()
+                .unwrap(),
+            config,
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends a HEAD request for the URL, unless it was checked recently.
+    pub async fn is_available(&self, url: &str) -> bool {
+        if let Some((checked_at, available)) = self.results.lock().unwrap().get(url) {
+            if checked_at.elapsed() < self.config.cache {
+                return *available;
+            }
+        }
+
+        let available = match self.client.head(url).send().await {
+            Ok(response) if response.status().is_success() => true,
+            Ok(response) => {
+                warn!("'{}' is unavailable: {}", url, response.status());
+                false
+            }
+            Err(err) => {
+                warn!("'{}' is unavailable: {}", url, err);
+                false
+            }
+        };
+        let mut results = self.results.lock().unwrap();
+        results.retain(|_, (checked_at, _)| checked_at.elapsed() < self.config.cache);
+        results.insert(url.to_string(), (Instant::now(), available));
+        available
+    }
+}